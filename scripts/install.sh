@@ -40,7 +40,10 @@ Description=Ghostwire Server
 After=network.target
 
 [Service]
+Type=notify
 ExecStart=/opt/ghostwire/ghostwire
+ExecReload=kill -HUP $MAINPID
+WatchdogSec=30
 Restart=always
 User=nobody
 WorkingDirectory=/opt/ghostwire
@@ -1,5 +1,6 @@
 use clap::{
     Arg,
+    ArgAction,
     Command,
 };
 use utils::{
@@ -7,6 +8,8 @@ use utils::{
     handler::handle_arguments,
 };
 
+use std::process::exit;
+
 mod utils;
 
 /// Core CLI handler
@@ -21,12 +24,198 @@ fn main() {
             Command::new("disable").about("Disable the firewall"),
             Command::new("load")
                 .about("Load the firewall rules from a configuration file")
-                .args([Arg::new("file").required(true)]),
+                .args([
+                    file_arg(),
+                    dir_arg(),
+                    Arg::new("confirm-within")
+                        .long("confirm-within")
+                        .value_name("DURATION")
+                        .help("Roll back unless `gw confirm` is run within this time, e.g. 60s"),
+                    force_arg(),
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Show how the rules differ from the applied ones, without applying them"),
+                ]),
+            Command::new("lint")
+                .about("Check a configuration file for rules that never match or repeat others")
+                .args([file_arg(), dir_arg()]),
+            Command::new("config")
+                .about("Check configuration files without a server")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("validate")
+                        .about("Check a configuration file against the schema and convert its rules")
+                        .args([file_arg(), dir_arg()]),
+                    Command::new("schema")
+                        .about("Print the JSON Schema of configuration files, as in config.schema.json"),
+                ]),
+            Command::new("export")
+                .about("Render a configuration file as the ruleset of another firewall")
+                .args([
+                    Arg::new("format")
+                        .long("format")
+                        .required(true)
+                        .value_parser(["nft"])
+                        .help("The format to render, nft for an nftables ruleset"),
+                    file_arg(),
+                    dir_arg(),
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .help("Write the ruleset to a file instead of printing it"),
+                ]),
+            Command::new("import")
+                .about("Translate the rules of another firewall into a Ghostwire configuration file")
+                .args([
+                    Arg::new("from")
+                        .long("from")
+                        .required(true)
+                        .value_parser(["iptables-save", "nft"])
+                        .help("The format of the file, iptables-save output or `nft -j list ruleset`"),
+                    Arg::new("file").required(true),
+                    Arg::new("interface")
+                        .long("interface")
+                        .required(true)
+                        .help("The interface to apply the rules to"),
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .help("Write the configuration to a file instead of printing it"),
+                ]),
+            Command::new("confirm").about("Confirm the rules loaded with --confirm-within"),
+            Command::new("rule")
+                .about("Change single rules without reloading the others")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("add")
+                        .about("Add a rule after the applied ones")
+                        .args(rule_args())
+                        .arg(
+                            Arg::new("new-id")
+                                .long("id")
+                                .value_parser(clap::value_parser!(u32).range(1..))
+                                .help("The ID to give the rule, instead of the next free one"),
+                        ),
+                    Command::new("update")
+                        .about("Replace an applied rule, keeping its ID and position")
+                        .arg(rule_id_arg())
+                        .args(rule_args())
+                        .arg(force_arg()),
+                    Command::new("delete")
+                        .about("Remove an applied rule")
+                        .args([rule_id_arg(), force_arg(), temporary_arg()]),
+                    Command::new("list").about("List the applied rules"),
+                ]),
+            Command::new("audit")
+                .about("Page through the audit log of changes to the firewall, newest first")
+                .args([
+                    Arg::new("page")
+                        .long("page")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("1")
+                        .help("The page of records to show"),
+                    Arg::new("limit")
+                        .long("limit")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("20")
+                        .help("The number of records per page"),
+                ]),
         ])
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .global(true)
+                .env("GHOSTWIRE_SOCKET")
+                .default_value("/run/ghostwire/ghostwire.sock")
+                .help("The path of the socket the Ghostwire server listens on"),
+        )
         .arg_required_else_help(true)
         .get_matches();
 
     if let Err(e) = handle_arguments(matches) {
-        print_error(&e.to_string());
+        print_error(&format!("{:#}", e));
+        exit(1);
     }
 }
+
+/// The flags describing a rule, as in the rules of the configuration file
+fn rule_args() -> [Arg; 9] {
+    [
+        Arg::new("source")
+            .long("source")
+            .default_value("0.0.0.0/0")
+            .help("The source IP range, e.g. 10.0.0.0/8"),
+        Arg::new("destination")
+            .long("destination")
+            .default_value("0.0.0.0/0")
+            .help("The destination IP range"),
+        Arg::new("protocol")
+            .long("protocol")
+            .required(true)
+            .help("The protocol to allow, like TCP, UDP, SCTP, GRE or ALL, or a protocol number"),
+        Arg::new("port")
+            .long("port")
+            .value_parser(clap::value_parser!(u16))
+            .default_value("0")
+            .help("The port to allow, or 0 for any"),
+        Arg::new("ratelimit")
+            .long("ratelimit")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("0")
+            .help("The packets allowed per source IP, or 0 to disable ratelimiting"),
+        Arg::new("name")
+            .long("name")
+            .help("A name for the rule, shown in its metrics"),
+        Arg::new("match-on")
+            .long("match-on")
+            .value_parser(["outer", "inner"])
+            .default_value("outer")
+            .help("Match on the headers inside GRE, IPIP and VXLAN tunnels with inner"),
+        Arg::new("log")
+            .long("log")
+            .action(ArgAction::SetTrue)
+            .help("Write the packets matching the rule to the packet log of the server"),
+        temporary_arg(),
+    ]
+}
+
+/// The configuration file to read
+fn file_arg() -> Arg {
+    Arg::new("file")
+        .required_unless_present("dir")
+        .conflicts_with("dir")
+        .help("The configuration file, which can include others")
+}
+
+/// A directory of configuration files to read instead of a single file
+fn dir_arg() -> Arg {
+    Arg::new("dir")
+        .long("dir")
+        .value_name("DIRECTORY")
+        .help("Merge the .yml and .yaml files of a directory in lexical order, e.g. /etc/ghostwire/conf.d")
+}
+
+/// The ID of the rule to change
+fn rule_id_arg() -> Arg {
+    Arg::new("id")
+        .required(true)
+        .value_parser(clap::value_parser!(u32))
+        .help("The ID of the rule, as shown by `gw rule list`")
+}
+
+/// Whether to make a change that would drop the established SSH sessions
+fn force_arg() -> Arg {
+    Arg::new("force")
+        .long("force")
+        .action(ArgAction::SetTrue)
+        .help("Make the change even if it'd drop the established SSH sessions")
+}
+
+/// Whether to leave a change out of the rules applied on startup
+fn temporary_arg() -> Arg {
+    Arg::new("temporary")
+        .long("temporary")
+        .action(ArgAction::SetTrue)
+        .help("Undo the change when Ghostwire restarts")
+}
@@ -1,4 +1,18 @@
 use colored::*;
+use ghostwire_types::{
+    protocol_name,
+    yaml::{
+        format_ip_range,
+        ConfigFiles,
+    },
+    AuditRecord,
+    MatchOn,
+    Rule,
+};
+use std::time::{
+    Duration,
+    UNIX_EPOCH,
+};
 
 /// Print a success message to the console.
 pub fn print_success(message: &str) {
@@ -19,3 +33,66 @@ pub fn print_error(message: &str) {
         "<<<".bold().blue()
     );
 }
+
+/// Print a record of the audit log to the console.
+pub fn print_audit_record(record: &AuditRecord) {
+    let time = UNIX_EPOCH + Duration::from_secs(record.timestamp);
+    let who = match (record.uid, record.pid, &record.address) {
+        (Some(uid), Some(pid), _) => format!("uid {} (pid {})", uid, pid),
+        (Some(uid), None, _) => format!("uid {}", uid),
+        (None, _, Some(address)) => format!("api {}", address),
+        (None, _, None) => "ghostwire".to_string(),
+    };
+    let outcome = match record.success {
+        true => "ok".green(),
+        false => "failed".red(),
+    };
+
+    println!(
+        "{} {} {} {}",
+        humantime::format_rfc3339_seconds(time).to_string().dimmed(),
+        record.action.bold(),
+        who,
+        outcome
+    );
+    println!("    {}", record.changes);
+    println!("    {}", record.message.dimmed());
+}
+
+/// Print the files a configuration was read from, with the rules each one added.
+pub fn print_config_files(files: &ConfigFiles) {
+    for file in &files.files {
+        let count = files.rules.iter().filter(|rule| rule.file == *file).count();
+
+        println!("{} {} rules from {}", "Read".bold(), count, file);
+    }
+}
+
+/// Print rules to the console as a table.
+pub fn print_rules(rules: &[Rule]) {
+    println!(
+        "{}",
+        format!(
+            "{:>6}  {:<20} {:<20} {:<8} {:>5}  {:>9}  {:<5}  {}",
+            "ID", "SOURCE", "DESTINATION", "PROTOCOL", "PORT", "RATELIMIT", "MATCH", "NAME"
+        )
+        .bold()
+    );
+
+    for rule in rules {
+        println!(
+            "{:>6}  {:<20} {:<20} {:<8} {:>5}  {:>9}  {:<5}  {}",
+            rule.id,
+            format_ip_range(rule.source_start_ip, rule.source_end_ip),
+            format_ip_range(rule.destination_start_ip, rule.destination_end_ip),
+            protocol_name(rule.protocol_number),
+            u16::from_be(rule.port_number),
+            rule.ratelimiting,
+            match rule.match_on {
+                MatchOn::Outer => "outer",
+                MatchOn::Inner => "inner",
+            },
+            rule.name.as_deref().unwrap_or("")
+        );
+    }
+}
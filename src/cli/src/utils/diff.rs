@@ -0,0 +1,432 @@
+use colored::*;
+use ghostwire_types::{
+    protocol_name,
+    yaml::format_ip_range,
+    MatchOn,
+    Rule,
+};
+
+/// How a ruleset would change if it replaced the applied one
+pub struct RulesetDiff {
+    /// The applied interface and the new one, if they differ
+    pub interface: Option<(Option<String>, String)>,
+    /// Rules only in the new ruleset
+    pub added: Vec<Rule>,
+    /// Rules only in the applied ruleset
+    pub removed: Vec<Rule>,
+    /// Rules matching the same traffic in both, but with a different ratelimit or logging, as
+    /// (applied, new)
+    pub changed: Vec<(Rule, Rule)>,
+    /// Rules in both that moved relative to the others, as (applied position, new position)
+    pub reordered: Vec<(Rule, usize, usize)>,
+    /// Added rules allowing traffic no applied rule allows on its own
+    pub newly_allowed: Vec<Rule>,
+    /// Removed rules whose traffic no new rule allows on its own
+    pub newly_blocked: Vec<Rule>,
+}
+
+impl RulesetDiff {
+    /// Compare the applied ruleset to a new one. Rules are paired by the traffic they match, as
+    /// rules without an ID in the file only get one when they're applied.
+    pub fn new(
+        applied_interface: Option<String>,
+        applied: &[Rule],
+        interface: &str,
+        rules: &[Rule],
+    ) -> Self {
+        // Pair each new rule with the first unpaired applied rule matching the same traffic.
+        let mut paired = vec![false; applied.len()];
+        let mut pairs = vec![];
+        let mut added = vec![];
+
+        for (new_index, rule) in rules.iter().enumerate() {
+            let old_index = applied
+                .iter()
+                .enumerate()
+                .position(|(index, old)| !paired[index] && old.same_traffic(rule));
+
+            match old_index {
+                Some(old_index) => {
+                    paired[old_index] = true;
+                    pairs.push((old_index, new_index));
+                }
+                None => added.push(rule.clone()),
+            }
+        }
+
+        let removed = applied
+            .iter()
+            .zip(&paired)
+            .filter(|(_, paired)| !**paired)
+            .map(|(rule, _)| rule.clone())
+            .collect::<Vec<_>>();
+
+        let changed = pairs
+            .iter()
+            .filter(|(old, new)| {
+                applied[*old].ratelimiting != rules[*new].ratelimiting
+                    || applied[*old].log != rules[*new].log
+            })
+            .map(|(old, new)| (applied[*old].clone(), rules[*new].clone()))
+            .collect();
+
+        // The rules that kept their relative order are the longest run of pairs whose applied
+        // positions increase, every other pair moved.
+        let kept = longest_increasing(&pairs.iter().map(|(old, _)| *old).collect::<Vec<_>>());
+        let reordered = pairs
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !kept.contains(index))
+            .map(|(_, (old, new))| (rules[*new].clone(), *old, *new))
+            .collect();
+
+        let newly_allowed = added
+            .iter()
+            .filter(|rule| !applied.iter().any(|old| covers(old, rule)))
+            .cloned()
+            .collect();
+        let newly_blocked = removed
+            .iter()
+            .filter(|rule| !rules.iter().any(|new| covers(new, rule)))
+            .cloned()
+            .collect();
+
+        Self {
+            interface: match applied_interface.as_deref() == Some(interface) {
+                true => None,
+                false => Some((applied_interface, interface.to_string())),
+            },
+            added,
+            removed,
+            changed,
+            reordered,
+            newly_allowed,
+            newly_blocked,
+        }
+    }
+
+    /// Whether applying the new ruleset would change nothing.
+    pub fn is_empty(&self) -> bool {
+        self.interface.is_none()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.reordered.is_empty()
+    }
+
+    /// Print the diff to the console.
+    pub fn print(&self) {
+        if let Some((applied, new)) = &self.interface {
+            println!(
+                "{} {} -> {}",
+                "interface".bold(),
+                applied.as_deref().unwrap_or("(disabled)"),
+                new
+            );
+        }
+
+        for rule in &self.added {
+            println!("{} {}", "+".bold().green(), describe_rule(rule).green());
+        }
+
+        for rule in &self.removed {
+            println!("{} {}", "-".bold().red(), describe_rule(rule).red());
+        }
+
+        for (old, new) in &self.changed {
+            let mut changes = vec![];
+
+            if old.ratelimiting != new.ratelimiting {
+                changes.push(format!(
+                    "ratelimit {} -> {}",
+                    old.ratelimiting, new.ratelimiting
+                ));
+            }
+
+            if old.log != new.log {
+                changes.push(format!("log {} -> {}", old.log, new.log));
+            }
+
+            println!(
+                "{} {}: {}",
+                "~".bold().yellow(),
+                describe_traffic(new).yellow(),
+                changes.join(", ")
+            );
+        }
+
+        for (rule, old, new) in &self.reordered {
+            println!(
+                "{} {}: moved from position {} to {}",
+                "^".bold().blue(),
+                describe_traffic(rule).blue(),
+                old + 1,
+                new + 1
+            );
+        }
+
+        if !self.newly_allowed.is_empty() {
+            println!("\n{}", "Newly allowed traffic:".bold());
+
+            for rule in &self.newly_allowed {
+                println!("  {}", describe_traffic(rule));
+            }
+        }
+
+        if !self.newly_blocked.is_empty() {
+            println!("\n{}", "Newly blocked traffic:".bold());
+
+            for rule in &self.newly_blocked {
+                println!("  {}", describe_traffic(rule));
+            }
+        }
+    }
+}
+
+/// Whether a rule allows all the traffic another rule does, at least as often.
+fn covers(rule: &Rule, other: &Rule) -> bool {
+    covers_traffic(rule, other)
+        && (rule.ratelimiting == 0 || rule.ratelimiting >= other.ratelimiting)
+        && (rule.ratelimiting == 0 || other.ratelimiting != 0)
+}
+
+/// Whether a rule matches all the traffic another rule does. A protocol or port of 0 matches any,
+/// and the port of a rule for every protocol is ignored, as by the XDP program. The destination
+/// range is ignored too, as the XDP program doesn't check it. Rules on the inner headers of tunnels
+/// only cover each other.
+pub fn covers_traffic(rule: &Rule, other: &Rule) -> bool {
+    if rule.match_on != other.match_on {
+        return false;
+    }
+
+    u32::from_be(rule.source_start_ip) <= u32::from_be(other.source_start_ip)
+        && u32::from_be(other.source_end_ip) <= u32::from_be(rule.source_end_ip)
+        && (rule.protocol_number == 0
+            || (rule.protocol_number == other.protocol_number
+                && (rule.port_number == 0 || rule.port_number == other.port_number)))
+}
+
+/// Find the indices of the longest increasing subsequence.
+fn longest_increasing(values: &[usize]) -> Vec<usize> {
+    // The length of the longest run ending at each value, and the index before it in that run.
+    let mut lengths = vec![1; values.len()];
+    let mut previous = vec![None; values.len()];
+
+    for i in 0..values.len() {
+        for j in 0..i {
+            if values[j] < values[i] && lengths[j] + 1 > lengths[i] {
+                lengths[i] = lengths[j] + 1;
+                previous[i] = Some(j);
+            }
+        }
+    }
+
+    let mut run = vec![];
+    let mut index = (0..values.len()).max_by_key(|i| lengths[*i]);
+
+    while let Some(i) = index {
+        run.push(i);
+        index = previous[i];
+    }
+
+    run
+}
+
+/// Describe a rule for the diff.
+fn describe_rule(rule: &Rule) -> String {
+    let mut description = describe_traffic(rule);
+
+    if rule.ratelimiting != 0 {
+        description.push_str(&format!(" (ratelimit {})", rule.ratelimiting));
+    }
+
+    if rule.log {
+        description.push_str(" (logged)");
+    }
+
+    if let Some(name) = &rule.name {
+        description.push_str(&format!(" [{}]", name));
+    }
+
+    description
+}
+
+/// Describe the traffic a rule matches.
+fn describe_traffic(rule: &Rule) -> String {
+    let port = match u16::from_be(rule.port_number) {
+        0 => String::new(),
+        port => format!(" port {}", port),
+    };
+
+    let tunneled = match rule.match_on {
+        MatchOn::Outer => "",
+        MatchOn::Inner => " inside tunnels",
+    };
+
+    format!(
+        "{}{} from {} to {}{}",
+        protocol_name(rule.protocol_number),
+        port,
+        format_ip_range(rule.source_start_ip, rule.source_end_ip),
+        format_ip_range(rule.destination_start_ip, rule.destination_end_ip),
+        tunneled
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// A rule from a /24 subnet of 10.0.0.0/8 to every destination.
+    fn rule(id: u32, subnet: u8, protocol_number: u8, port: u16) -> Rule {
+        let start = u32::from(Ipv4Addr::new(10, subnet, 0, 0));
+
+        Rule {
+            id,
+            name: None,
+            source_start_ip: start.to_be(),
+            source_end_ip: (start | 0xFF).to_be(),
+            destination_start_ip: 0,
+            destination_end_ip: u32::MAX,
+            protocol_number,
+            match_on: MatchOn::Outer,
+            port_number: port.to_be(),
+            ratelimiting: 0,
+            log: false,
+        }
+    }
+
+    /// Compare rulesets on the same interface.
+    fn diff(applied: &[Rule], rules: &[Rule]) -> RulesetDiff {
+        RulesetDiff::new(Some("eth0".to_string()), applied, "eth0", rules)
+    }
+
+    #[test]
+    fn same_rules_are_empty() {
+        let rules = [rule(1, 1, 6, 22), rule(2, 2, 17, 53)];
+
+        assert!(diff(&rules, &rules).is_empty());
+    }
+
+    #[test]
+    fn interface_change_is_reported() {
+        let rules = [rule(1, 1, 6, 22)];
+        let diff = RulesetDiff::new(None, &rules, "eth1", &rules);
+
+        assert!(!diff.is_empty());
+        assert_eq!(diff.interface, Some((None, "eth1".to_string())));
+    }
+
+    #[test]
+    fn rules_pair_by_traffic_not_id() {
+        // The file's rules have no IDs yet, and an applied ID can come back on other traffic.
+        let applied = [rule(1, 1, 6, 22), rule(2, 2, 6, 22)];
+        let rules = [rule(0, 1, 6, 22), rule(2, 3, 6, 22)];
+        let diff = diff(&applied, &rules);
+
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].id, 2);
+        assert!(diff.added[0].same_traffic(&rule(0, 3, 6, 22)));
+        assert_eq!(diff.removed.len(), 1);
+        assert!(diff.removed[0].same_traffic(&rule(0, 2, 6, 22)));
+        assert!(diff.changed.is_empty());
+        assert!(diff.reordered.is_empty());
+    }
+
+    #[test]
+    fn ratelimit_and_log_changes_are_changes() {
+        let applied = [rule(1, 1, 6, 22), rule(2, 2, 17, 53)];
+        let mut rules = applied.clone();
+        rules[0].ratelimiting = 100;
+        rules[1].log = true;
+        let diff = diff(&applied, &rules);
+
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(
+            (
+                diff.changed[0].0.ratelimiting,
+                diff.changed[0].1.ratelimiting
+            ),
+            (0, 100)
+        );
+        assert!(diff.changed[1].1.log);
+    }
+
+    #[test]
+    fn moved_rule_is_reordered() {
+        let applied = [rule(1, 1, 6, 22), rule(2, 2, 6, 22), rule(3, 3, 6, 22)];
+        let rules = [applied[2].clone(), applied[0].clone(), applied[1].clone()];
+        let diff = diff(&applied, &rules);
+
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        // Rules 1 and 2 kept their order, rule 3 moved to the front.
+        assert_eq!(diff.reordered.len(), 1);
+        assert_eq!(diff.reordered[0].0.id, 3);
+        assert_eq!((diff.reordered[0].1, diff.reordered[0].2), (2, 0));
+    }
+
+    #[test]
+    fn newly_allowed_and_blocked() {
+        let applied = [rule(1, 1, 6, 0)];
+        let rules = [rule(0, 1, 6, 22), rule(0, 2, 17, 53)];
+        let diff = diff(&applied, &rules);
+
+        // SSH was allowed by the rule for all of TCP, DNS wasn't allowed at all.
+        assert_eq!(diff.newly_allowed.len(), 1);
+        assert!(diff.newly_allowed[0].same_traffic(&rule(0, 2, 17, 53)));
+        assert_eq!(diff.newly_blocked.len(), 1);
+        assert!(diff.newly_blocked[0].same_traffic(&applied[0]));
+    }
+
+    #[test]
+    fn ratelimited_rule_doesnt_cover_unlimited_one() {
+        let mut limited = rule(1, 1, 6, 22);
+        limited.ratelimiting = 10;
+        let diff = diff(&[limited], &[rule(0, 1, 6, 22)]);
+
+        // The same traffic is allowed more often, which counts as a change, not as new traffic.
+        assert_eq!(diff.changed.len(), 1);
+        assert!(diff.newly_allowed.is_empty());
+
+        // A rule allowing more often covers a stricter one, but not the other way around.
+        let mut stricter = rule(0, 1, 6, 22);
+        stricter.ratelimiting = 5;
+        let mut looser = stricter.clone();
+        looser.ratelimiting = 20;
+
+        assert!(covers(&looser, &stricter));
+        assert!(!covers(&stricter, &looser));
+        assert!(!covers(&stricter, &rule(0, 1, 6, 22)));
+        assert!(covers(&rule(0, 1, 6, 22), &stricter));
+    }
+
+    #[test]
+    fn destination_changes_no_traffic() {
+        // The XDP program doesn't check destinations, so moving a rule to another one allows and
+        // blocks nothing.
+        let mut applied = rule(1, 1, 6, 22);
+        applied.destination_start_ip = u32::from(Ipv4Addr::new(192, 0, 2, 1)).to_be();
+        applied.destination_end_ip = applied.destination_start_ip;
+        let mut new = applied.clone();
+        new.id = 0;
+        new.destination_start_ip = u32::from(Ipv4Addr::new(192, 0, 2, 2)).to_be();
+        new.destination_end_ip = new.destination_start_ip;
+        let diff = diff(&[applied], &[new]);
+
+        assert_eq!((diff.added.len(), diff.removed.len()), (1, 1));
+        assert!(diff.newly_allowed.is_empty());
+        assert!(diff.newly_blocked.is_empty());
+    }
+
+    #[test]
+    fn longest_increasing_finds_kept_order() {
+        let mut kept = longest_increasing(&[2, 0, 1, 3]);
+        kept.sort();
+
+        assert_eq!(kept, vec![1, 2, 3]);
+        assert!(longest_increasing(&[]).is_empty());
+    }
+}
@@ -0,0 +1,305 @@
+use ghostwire_types::{
+    has_ports,
+    protocol_name,
+    yaml::{
+        format_ip_range,
+        Config,
+    },
+    MatchOn,
+    Rule,
+};
+use std::fmt::Display;
+
+/// Render a configuration as an nftables ruleset, for hosts where the XDP program can't attach. The
+/// ruleset replaces the `ghostwire` table when loaded with `nft -f`, leaving other tables alone.
+/// Connection tracking lets in replies to connections the host made, in place of holepunching.
+/// Rules are named by their ID, like in Ghostwire's logs and metrics.
+pub fn to_nft(config: &Config, source: &str) -> String {
+    let mut sets = String::new();
+    let mut rules = String::new();
+
+    for (index, rule) in config.rules.iter().enumerate() {
+        let label = RuleLabel::new(index, rule);
+
+        if rule.match_on == MatchOn::Inner {
+            rules.push_str(&format!(
+                "\t\t# {} is left out, it matches on the inner headers of tunnels\n",
+                label
+            ));
+            continue;
+        }
+
+        let Some(matches) = nft_matches(rule) else {
+            rules.push_str(&format!(
+                "\t\t# {} is left out, {} has no ports so it never matches\n",
+                label,
+                protocol_name(rule.protocol_number)
+            ));
+            continue;
+        };
+
+        // Ghostwire doesn't enforce destinations, so neither does the ruleset.
+        if (rule.destination_start_ip, rule.destination_end_ip) != (0, u32::MAX) {
+            rules.push_str(&format!(
+                "\t\t# {} matches every destination, not just {}, like in Ghostwire\n",
+                label,
+                format_ip_range(rule.destination_start_ip, rule.destination_end_ip)
+            ));
+        }
+
+        let comment = match &rule.name {
+            Some(name) => format!(" comment \"{}\"", name.replace('"', "'")),
+            None => String::new(),
+        };
+
+        let log = match rule.log {
+            true => format!(" log prefix \"ghostwire rule {}: \"", label.key()),
+            false => String::new(),
+        };
+
+        // Ghostwire drops a source over its ratelimit rather than trying the next rules.
+        if rule.ratelimiting != 0 {
+            let set = format!("ratelimit_{}", label.key());
+
+            sets.push_str(&format!(
+                "\tset {} {{\n\t\ttype ipv4_addr\n\t\tflags dynamic\n\t\ttimeout 1m\n\t}}\n\n",
+                set
+            ));
+            rules.push_str(&format!(
+                "\t\t{} update @{} {{ ip saddr limit rate over {}/minute burst {} packets }}{} drop\n",
+                matches, set, rule.ratelimiting, rule.ratelimiting, log
+            ));
+        }
+
+        rules.push_str(&format!(
+            "\t\t{}{} counter accept{}\n",
+            matches, log, comment
+        ));
+    }
+
+    format!(
+        "#!/usr/sbin/nft -f
+# Generated by gw export from {source}
+# Load it with `nft -f`. It replaces the ghostwire table, leaving other tables alone.
+
+table inet ghostwire
+delete table inet ghostwire
+
+table inet ghostwire {{
+{sets}\tchain input {{
+\t\ttype filter hook input priority filter; policy drop;
+
+\t\t# Ghostwire only filters the interface it's attached to
+\t\tiifname != \"{interface}\" accept
+\t\t# Ghostwire only filters IPv4
+\t\tmeta nfproto != ipv4 accept
+\t\t# Replies to connections the host made, which Ghostwire holepunches
+\t\tct state established,related accept
+
+{rules}\t}}
+}}
+",
+        source = source,
+        sets = sets,
+        interface = config.interface.replace('"', ""),
+        rules = rules
+    )
+}
+
+/// What a rule is called in the ruleset: its ID, or its position if it has none, as rules without
+/// an ID in the file are only given one when they're applied.
+enum RuleLabel {
+    Id(u32),
+    Position(usize),
+}
+
+impl RuleLabel {
+    fn new(index: usize, rule: &Rule) -> Self {
+        match rule.id {
+            0 => Self::Position(index + 1),
+            id => Self::Id(id),
+        }
+    }
+
+    /// The label as part of a set name or log prefix.
+    fn key(&self) -> String {
+        match self {
+            Self::Id(id) => id.to_string(),
+            Self::Position(position) => format!("position_{}", position),
+        }
+    }
+}
+
+impl Display for RuleLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "Rule {}", id),
+            Self::Position(position) => {
+                write!(f, "The rule without an ID at position {}", position)
+            }
+        }
+    }
+}
+
+/// The nftables matches for the traffic of a rule, or None if the rule never matches. The
+/// destination isn't matched, as Ghostwire doesn't enforce it.
+fn nft_matches(rule: &Rule) -> Option<String> {
+    let mut matches = vec![];
+    let source = format_ip_range(rule.source_start_ip, rule.source_end_ip);
+
+    if source != "0.0.0.0/0" {
+        matches.push(format!("ip saddr {}", source));
+    }
+
+    let port = u16::from_be(rule.port_number);
+
+    // nftables knows the protocols Ghostwire names, and takes the others as numbers.
+    let protocol = protocol_name(rule.protocol_number).to_lowercase();
+
+    match (rule.protocol_number, port) {
+        (0, _) => matches.push("meta nfproto ipv4".to_string()),
+        (_, 0) => matches.push(format!("meta l4proto {}", protocol)),
+        (number, _) if !has_ports(number) => return None,
+        (_, port) => matches.push(format!("{} dport {}", protocol, port)),
+    }
+
+    Some(matches.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// A rule from every source to every destination.
+    fn rule(id: u32, protocol_number: u8, port: u16) -> Rule {
+        Rule {
+            id,
+            name: None,
+            source_start_ip: 0,
+            source_end_ip: u32::MAX,
+            destination_start_ip: 0,
+            destination_end_ip: u32::MAX,
+            protocol_number,
+            match_on: MatchOn::Outer,
+            port_number: port.to_be(),
+            ratelimiting: 0,
+            log: false,
+        }
+    }
+
+    /// The lines of the input chain after the ones every ruleset starts with.
+    fn rule_lines(rules: Vec<Rule>) -> Vec<String> {
+        let ruleset = to_nft(
+            &Config {
+                rules,
+                interface: "eth0".to_string(),
+                persistent: true,
+            },
+            "config.yml",
+        );
+        let (_, rules) = ruleset
+            .split_once("ct state established,related accept\n\n")
+            .unwrap();
+
+        rules
+            .lines()
+            .take_while(|line| *line != "\t}")
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ruleset_replaces_its_table() {
+        let ruleset = to_nft(
+            &Config {
+                rules: vec![],
+                interface: "eth\"0".to_string(),
+                persistent: true,
+            },
+            "config.yml",
+        );
+
+        assert!(
+            ruleset.starts_with("#!/usr/sbin/nft -f\n# Generated by gw export from config.yml\n")
+        );
+        assert!(ruleset.contains("table inet ghostwire\ndelete table inet ghostwire\n"));
+        assert!(ruleset.contains("policy drop;"));
+        assert!(ruleset.contains("iifname != \"eth0\" accept"));
+    }
+
+    #[test]
+    fn matches_source_protocol_and_port() {
+        let mut ssh = rule(1, 6, 22);
+        ssh.source_start_ip = u32::from(Ipv4Addr::new(10, 0, 0, 0)).to_be();
+        ssh.source_end_ip = u32::from(Ipv4Addr::new(10, 255, 255, 255)).to_be();
+        ssh.name = Some("ssh from \"ops\"".to_string());
+
+        assert_eq!(
+            rule_lines(vec![ssh, rule(2, 1, 0), rule(3, 0, 0), rule(4, 89, 0)]),
+            vec![
+                "ip saddr 10.0.0.0/8 tcp dport 22 counter accept comment \"ssh from 'ops'\"",
+                "meta l4proto icmp counter accept",
+                "meta nfproto ipv4 counter accept",
+                "meta l4proto 89 counter accept",
+            ]
+        );
+    }
+
+    #[test]
+    fn destination_isnt_matched() {
+        let mut web = rule(7, 6, 443);
+        web.destination_start_ip = u32::from(Ipv4Addr::new(192, 0, 2, 1)).to_be();
+        web.destination_end_ip = web.destination_start_ip;
+
+        assert_eq!(
+            rule_lines(vec![web]),
+            vec![
+                "# Rule 7 matches every destination, not just 192.0.2.1/32, like in Ghostwire",
+                "tcp dport 443 counter accept",
+            ]
+        );
+    }
+
+    #[test]
+    fn ratelimit_and_log_are_named_by_id() {
+        let mut dns = rule(12, 17, 53);
+        dns.ratelimiting = 100;
+        dns.log = true;
+        let mut unnumbered = rule(0, 6, 80);
+        unnumbered.log = true;
+
+        let ruleset = to_nft(
+            &Config {
+                rules: vec![dns.clone(), unnumbered.clone()],
+                interface: "eth0".to_string(),
+                persistent: true,
+            },
+            "config.yml",
+        );
+
+        assert!(ruleset.contains("\tset ratelimit_12 {\n\t\ttype ipv4_addr\n"));
+        assert_eq!(
+            rule_lines(vec![dns, unnumbered]),
+            vec![
+                "udp dport 53 update @ratelimit_12 { ip saddr limit rate over 100/minute burst 100 packets } log prefix \"ghostwire rule 12: \" drop",
+                "udp dport 53 log prefix \"ghostwire rule 12: \" counter accept",
+                "tcp dport 80 log prefix \"ghostwire rule position_2: \" counter accept",
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_that_cant_be_exported_are_left_out() {
+        let mut inner = rule(3, 6, 22);
+        inner.match_on = MatchOn::Inner;
+
+        assert_eq!(
+            rule_lines(vec![inner, rule(0, 47, 22)]),
+            vec![
+                "# Rule 3 is left out, it matches on the inner headers of tunnels",
+                "# The rule without an ID at position 2 is left out, GRE has no ports so it never matches",
+            ]
+        );
+    }
+}
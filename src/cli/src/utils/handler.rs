@@ -1,54 +1,135 @@
 use super::{
-    console::print_success,
-    yaml::parse_yaml,
+    console::{
+        print_audit_record,
+        print_config_files,
+        print_rules,
+        print_success,
+    },
+    diff::RulesetDiff,
+    export::to_nft,
+    import::{
+        from_iptables_save,
+        from_nft_json,
+    },
+    lint::{
+        lint,
+        print_findings,
+    },
+};
+use crate::utils::socket::{
+    send_message,
+    send_request,
 };
-use crate::utils::socket::send_message;
 use anyhow::{
     Context,
     Result,
 };
 use clap::ArgMatches;
+use colored::*;
 use ghostwire_types::{
+    schema::SCHEMA,
+    yaml::{
+        convert_to_rule,
+        ConfigFiles,
+        YamlRule,
+    },
+    AuditQuery,
     ClientMessage,
     ClientReqType,
+    Rule,
 };
 use std::fs;
 
 /// Handle the CLI commands.
 pub fn handle_arguments(matches: ArgMatches) -> Result<()> {
+    let socket = matches
+        .get_one::<String>("socket")
+        .context("No socket provided")?;
+
     let resp = match matches.subcommand() {
-        Some(("status", _)) => send_message(ClientMessage {
-            req_type: ClientReqType::STATUS,
-            interface: None,
-            rules: None,
-        }),
+        Some(("status", _)) => send_message(socket, ClientMessage::new(ClientReqType::STATUS)),
         /* Currently disabled due to lack of implementation for persistence.
         Some(("enable", enable_matches)) => {
             let interface = enable_matches
                 .get_one::<String>("interface")
                 .context("No interface provided")?;
-            send_message(ClientMessage {
-                req_type: ClientReqType::ENABLE,
+            send_message(socket, ClientMessage {
                 interface: Some(interface.to_string()),
-                rules: None,
+                ..ClientMessage::new(ClientReqType::ENABLE)
             })
         }*/
-        Some(("disable", _)) => send_message(ClientMessage {
-            req_type: ClientReqType::DISABLE,
-            interface: None,
-            rules: None,
-        }),
+        Some(("disable", _)) => send_message(socket, ClientMessage::new(ClientReqType::DISABLE)),
         Some(("load", file_matches)) => {
-            let file = file_matches
-                .get_one::<String>("file")
-                .context("No file provided")?;
-            let (rules, interface) = parse_yaml(fs::read_to_string(file)?)?;
-
-            send_message(ClientMessage {
-                req_type: ClientReqType::RULES,
-                interface: Some(interface),
-                rules: Some(rules),
-            })
+            let confirm_within = file_matches
+                .get_one::<String>("confirm-within")
+                .map(|duration| parse_confirm_within(duration))
+                .transpose()?;
+            let files = read_config_files(file_matches)?;
+            let config = files.config()?;
+
+            // Warn about rules that never match, but load them anyway.
+            print_findings(&lint(&files.rules));
+            print_config_files(&files);
+
+            if file_matches.get_flag("dry-run") {
+                return dry_run(socket, &config.interface, &config.rules);
+            }
+
+            send_message(
+                socket,
+                ClientMessage {
+                    interface: Some(config.interface),
+                    rules: Some(config.rules),
+                    confirm_within,
+                    force: file_matches.get_flag("force"),
+                    persistent: Some(config.persistent),
+                    ..ClientMessage::new(ClientReqType::RULES)
+                },
+            )
+        }
+        Some(("lint", lint_matches)) => {
+            let files = read_config_files(lint_matches)?;
+
+            let findings = lint(&files.rules);
+            print_findings(&findings);
+
+            // Catch the problems outside the rules, like a missing interface.
+            files.config()?;
+
+            match findings.len() {
+                0 => Ok(format!("No problems found in {} files", files.files.len())),
+                len => anyhow::bail!("{} problems found in {} files", len, files.files.len()),
+            }
+        }
+        Some(("config", config_matches)) => return handle_config(config_matches),
+        Some(("export", export_matches)) => return export(export_matches),
+        Some(("import", import_matches)) => return import(import_matches),
+        Some(("confirm", _)) => send_message(socket, ClientMessage::new(ClientReqType::CONFIRM)),
+        Some(("rule", rule_matches)) => handle_rule(socket, rule_matches),
+        Some(("audit", audit_matches)) => {
+            let limit = *audit_matches
+                .get_one::<u64>("limit")
+                .context("No limit provided")? as usize;
+            let page = *audit_matches
+                .get_one::<u64>("page")
+                .context("No page provided")? as usize;
+
+            let response = send_request(
+                socket,
+                ClientMessage {
+                    audit_query: Some(AuditQuery {
+                        skip: (page - 1) * limit,
+                        limit,
+                    }),
+                    ..ClientMessage::new(ClientReqType::AUDIT)
+                },
+            )?;
+
+            for record in response.audit.unwrap_or_default() {
+                print_audit_record(&record);
+            }
+
+            Ok(response.message)
         }
         _ => {
             anyhow::bail!("No subcommand provided");
@@ -59,3 +140,218 @@ pub fn handle_arguments(matches: ArgMatches) -> Result<()> {
 
     Ok(())
 }
+
+/// Parse the --confirm-within duration into the whole seconds the daemon takes.
+fn parse_confirm_within(duration: &str) -> Result<u64> {
+    let duration =
+        humantime::parse_duration(duration).context("Invalid duration for --confirm-within")?;
+
+    if duration.as_secs() == 0 || duration.subsec_nanos() != 0 {
+        anyhow::bail!("--confirm-within must be a whole number of seconds, at least 1s");
+    }
+
+    Ok(duration.as_secs())
+}
+
+/// Handle the commands changing single rules.
+fn handle_rule(socket: &str, matches: &ArgMatches) -> Result<String> {
+    match matches.subcommand() {
+        Some(("add", add_matches)) => send_message(
+            socket,
+            ClientMessage {
+                rule: Some(rule_from_args(add_matches)?),
+                persistent: Some(!add_matches.get_flag("temporary")),
+                ..ClientMessage::new(ClientReqType::ADD)
+            },
+        ),
+        Some(("update", update_matches)) => send_message(
+            socket,
+            ClientMessage {
+                rule: Some(rule_from_args(update_matches)?),
+                rule_id: update_matches.get_one::<u32>("id").copied(),
+                force: update_matches.get_flag("force"),
+                persistent: Some(!update_matches.get_flag("temporary")),
+                ..ClientMessage::new(ClientReqType::UPDATE)
+            },
+        ),
+        Some(("delete", delete_matches)) => send_message(
+            socket,
+            ClientMessage {
+                rule_id: delete_matches.get_one::<u32>("id").copied(),
+                force: delete_matches.get_flag("force"),
+                persistent: Some(!delete_matches.get_flag("temporary")),
+                ..ClientMessage::new(ClientReqType::DELETE)
+            },
+        ),
+        Some(("list", _)) => {
+            let response = send_request(socket, ClientMessage::new(ClientReqType::LIST))?;
+
+            let rules = response.rules.unwrap_or_default();
+
+            if !rules.is_empty() {
+                print_rules(&rules);
+            }
+
+            Ok(response.message)
+        }
+        _ => {
+            anyhow::bail!("No rule subcommand provided");
+        }
+    }
+}
+
+/// Build a rule from the flags of `gw rule add` or `gw rule update`. Unless `gw rule add` is given
+/// an ID, the server assigns it.
+fn rule_from_args(matches: &ArgMatches) -> Result<Rule> {
+    let arg = |name: &str| {
+        matches
+            .get_one::<String>(name)
+            .cloned()
+            .context(format!("No {} provided", name))
+    };
+
+    convert_to_rule(YamlRule {
+        id: matches.try_get_one::<u32>("new-id").ok().flatten().copied(),
+        name: matches.get_one::<String>("name").cloned(),
+        source_ip_range: arg("source")?,
+        destination_ip_range: arg("destination")?,
+        protocol: arg("protocol")?,
+        port: *matches.get_one::<u16>("port").context("No port provided")?,
+        ratelimit: *matches
+            .get_one::<u32>("ratelimit")
+            .context("No ratelimit provided")?,
+        match_on: matches.get_one::<String>("match-on").cloned(),
+        log: matches.get_flag("log"),
+    })
+}
+
+/// Read the configuration file given to a command like `gw load`, or the files of the directory
+/// given with `--dir`.
+fn read_config_files(matches: &ArgMatches) -> Result<ConfigFiles> {
+    match (
+        matches.get_one::<String>("file"),
+        matches.get_one::<String>("dir"),
+    ) {
+        (Some(file), _) => ConfigFiles::read(file),
+        (None, Some(dir)) => ConfigFiles::read_dir(dir),
+        (None, None) => anyhow::bail!("No file provided"),
+    }
+}
+
+/// Handle the commands checking configuration files without a server.
+fn handle_config(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("validate", validate_matches)) => {
+            let files = read_config_files(validate_matches)?;
+            let config = files.config()?;
+
+            print_config_files(&files);
+            print_success(&format!(
+                "The configuration is valid, with {} rules from {} files",
+                config.rules.len(),
+                files.files.len()
+            ));
+        }
+        // The schema goes to stdout as is, for editors and other tools to use.
+        Some(("schema", _)) => print!("{}", SCHEMA),
+        _ => anyhow::bail!("No config subcommand provided"),
+    }
+
+    Ok(())
+}
+
+/// Render a configuration as another firewall's ruleset. The ruleset goes to stdout unless
+/// `--output` is given, so everything else is printed to stderr.
+fn export(matches: &ArgMatches) -> Result<()> {
+    let files = read_config_files(matches)?;
+    let config = files.config()?;
+
+    print_findings(&lint(&files.rules));
+
+    let ruleset = to_nft(&config, &files.files.join(", "));
+
+    match matches.get_one::<String>("output") {
+        Some(output) => {
+            fs::write(output, ruleset).context(format!("Couldn't write {}", output))?;
+            print_success(&format!("Wrote {} rules to {}", config.rules.len(), output));
+        }
+        None => print!("{}", ruleset),
+    }
+
+    Ok(())
+}
+
+/// Translate another firewall's rules into a configuration file. The configuration goes to stdout
+/// unless `--output` is given, so everything else is printed to stderr.
+fn import(matches: &ArgMatches) -> Result<()> {
+    let arg = |name: &str| {
+        matches
+            .get_one::<String>(name)
+            .context(format!("No {} provided", name))
+    };
+    let file = arg("file")?;
+    let contents = fs::read_to_string(file).context(format!("Couldn't read {}", file))?;
+
+    let import = match arg("from")?.as_str() {
+        "iptables-save" => from_iptables_save(file, &contents),
+        _ => from_nft_json(file, &contents)?,
+    };
+    let yaml = import.to_yaml(file, arg("interface")?);
+
+    import.print_skipped();
+
+    let summary = format!(
+        "Translated {} rules, {} weren't translated",
+        import.rules.len(),
+        import.skipped.len()
+    );
+
+    match matches.get_one::<String>("output") {
+        Some(output) => {
+            fs::write(output, yaml).context(format!("Couldn't write {}", output))?;
+            print_success(&format!("{}. Wrote {}", summary, output));
+        }
+        None => {
+            print!("{}", yaml);
+            eprintln!("{}", summary.bold());
+        }
+    }
+
+    Ok(())
+}
+
+/// Show how a ruleset differs from the applied one, without applying it.
+fn dry_run(socket: &str, interface: &str, rules: &[Rule]) -> Result<()> {
+    let applied = send_request(socket, ClientMessage::new(ClientReqType::LIST))?;
+
+    let diff = RulesetDiff::new(
+        applied.interface,
+        &applied.rules.unwrap_or_default(),
+        interface,
+        rules,
+    );
+
+    if diff.is_empty() {
+        print_success("Dry run: the rules match the applied ones");
+    } else {
+        diff.print();
+        print_success("Dry run: nothing was applied");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirm_within_is_whole_seconds() {
+        assert_eq!(parse_confirm_within("60s").unwrap(), 60);
+        assert_eq!(parse_confirm_within("2m").unwrap(), 120);
+
+        for duration in ["500ms", "0s", "1500ms", "soon"] {
+            assert!(parse_confirm_within(duration).is_err(), "{}", duration);
+        }
+    }
+}
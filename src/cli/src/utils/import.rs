@@ -0,0 +1,824 @@
+use colored::*;
+use ghostwire_types::{
+    parse_protocol,
+    protocol_name,
+};
+use std::net::Ipv4Addr;
+
+/// Rules translated from another firewall's ruleset, and the ones that couldn't be
+#[derive(Default)]
+pub struct Import {
+    /// The translated rules, in order
+    pub rules: Vec<ImportedRule>,
+    /// The rules that weren't translated
+    pub skipped: Vec<Skipped>,
+    /// Caveats of rules that were translated, but don't behave quite the same
+    pub notes: Vec<Skipped>,
+}
+
+/// A rule translated from another firewall
+pub struct ImportedRule {
+    /// Where the original rule is, like `rules.v4:12`
+    pub location: String,
+    /// The original rule
+    pub original: String,
+    /// The comment of the original rule, if it had one
+    pub name: Option<String>,
+    /// The source IP range in CIDR notation
+    pub source: String,
+    /// The destination IP range in CIDR notation
+    pub destination: String,
+    /// The protocol, like TCP
+    pub protocol: String,
+    /// The ports allowed, or 0 for any
+    pub ports: Vec<u16>,
+    /// The packets allowed per source IP each minute, or 0 for no ratelimit
+    pub ratelimit: u32,
+}
+
+/// A rule that wasn't translated, or a caveat of one that was
+pub struct Skipped {
+    /// Where the original rule is, like `rules.v4:12`
+    pub location: String,
+    /// The original rule
+    pub original: String,
+    /// Why it wasn't translated
+    pub reason: String,
+}
+
+/// The parts of a rule gathered while translating it
+struct Translation {
+    name: Option<String>,
+    source: String,
+    destination: String,
+    protocol: Option<String>,
+    ports: Vec<u16>,
+    ratelimit: u32,
+    /// Whether the ratelimit is shared by every source, not counted per source IP
+    shared_limit: bool,
+    accept: bool,
+}
+
+impl Default for Translation {
+    fn default() -> Self {
+        Self {
+            name: None,
+            source: "0.0.0.0/0".to_string(),
+            destination: "0.0.0.0/0".to_string(),
+            protocol: None,
+            ports: vec![],
+            ratelimit: 0,
+            shared_limit: false,
+            accept: false,
+        }
+    }
+}
+
+impl Import {
+    /// Record the translation of a rule, or why it couldn't be translated.
+    fn push(&mut self, location: String, original: String, result: Result<Translation, String>) {
+        let translation = match result {
+            Ok(translation) => translation,
+            Err(reason) => {
+                self.skipped.push(Skipped {
+                    location,
+                    original,
+                    reason,
+                });
+                return;
+            }
+        };
+
+        if !translation.accept {
+            self.skipped.push(Skipped {
+                location,
+                original,
+                reason: "only rules accepting traffic are translated, as Ghostwire drops everything else"
+                    .to_string(),
+            });
+            return;
+        }
+
+        // A rule without a protocol matches every protocol.
+        let protocol = translation.protocol.unwrap_or_else(|| "ALL".to_string());
+
+        if translation.shared_limit {
+            self.notes.push(Skipped {
+                location: location.clone(),
+                original: original.clone(),
+                reason: "the limit was shared by every source, it's now per source IP".to_string(),
+            });
+        }
+
+        self.rules.push(ImportedRule {
+            location,
+            original,
+            name: translation.name,
+            source: translation.source,
+            destination: translation.destination,
+            protocol,
+            ports: match translation.ports.is_empty() {
+                true => vec![0],
+                false => translation.ports,
+            },
+            ratelimit: translation.ratelimit,
+        });
+    }
+
+    /// Write the translated rules as a Ghostwire configuration file, listing the rules that
+    /// weren't translated at the end.
+    pub fn to_yaml(&self, source: &str, interface: &str) -> String {
+        let mut yaml = format!(
+            "# Imported from {} by gw import, check the rules before loading them\ninterface: {}\nrules:\n",
+            source,
+            quote(interface)
+        );
+
+        if self.rules.is_empty() {
+            yaml.push_str("  []\n");
+        }
+
+        for rule in &self.rules {
+            yaml.push_str(&format!("  # {}: {}\n", rule.location, rule.original));
+            yaml.push_str("  - ");
+
+            if let Some(name) = &rule.name {
+                yaml.push_str(&format!("name: {}\n    ", quote(name)));
+            }
+
+            let ports = match rule.ports.as_slice() {
+                [port] => port.to_string(),
+                ports => format!(
+                    "[{}]",
+                    ports
+                        .iter()
+                        .map(|port| port.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            };
+
+            yaml.push_str(&format!(
+                "source_ip_range: {}\n    destination_ip_range: {}\n    protocol: {}\n    port: {}\n    ratelimit: {}\n",
+                rule.source, rule.destination, rule.protocol, ports, rule.ratelimit
+            ));
+        }
+
+        if !self.skipped.is_empty() {
+            yaml.push_str("\n# Not translated:\n");
+
+            for skipped in &self.skipped {
+                yaml.push_str(&format!(
+                    "#   {}: {}\n#     {}\n",
+                    skipped.location, skipped.original, skipped.reason
+                ));
+            }
+        }
+
+        yaml
+    }
+
+    /// Print the rules that weren't translated and the caveats of the ones that were.
+    pub fn print_skipped(&self) {
+        for (kind, skipped) in self
+            .skipped
+            .iter()
+            .map(|skipped| ("not translated".bold().yellow(), skipped))
+            .chain(self.notes.iter().map(|note| ("note".bold().blue(), note)))
+        {
+            eprintln!(
+                "{}: {}: {}\n    {}",
+                skipped.location.bold(),
+                kind,
+                skipped.reason,
+                skipped.original.dimmed()
+            );
+        }
+    }
+}
+
+/// Translate the filter table of the output of iptables-save. Only ACCEPT rules of the INPUT chain
+/// are translated, matching on the source, destination, protocol, destination port, limit and
+/// hashlimit.
+pub fn from_iptables_save(file: &str, contents: &str) -> Import {
+    let mut import = Import::default();
+    let mut table = "";
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        let location = format!("{}:{}", file, index + 1);
+
+        if let Some(name) = line.strip_prefix('*') {
+            table = name;
+            continue;
+        }
+
+        if table != "filter" {
+            continue;
+        }
+
+        if line.starts_with(":INPUT ACCEPT") {
+            import.skipped.push(Skipped {
+                location,
+                original: line.to_string(),
+                reason: "the INPUT policy accepts the traffic no rule matches, Ghostwire drops it"
+                    .to_string(),
+            });
+        } else if line.starts_with("-A ") {
+            import.push(location, line.to_string(), translate_iptables(line));
+        }
+    }
+
+    import
+}
+
+/// Translate a rule of iptables-save.
+fn translate_iptables(line: &str) -> Result<Translation, String> {
+    let tokens = split_iptables(line)?;
+    let mut tokens = tokens.iter().map(String::as_str);
+    let mut translation = Translation::default();
+    let mut chain = "";
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "-A" | "--append" => chain = tokens.next().unwrap_or_default(),
+            "-s" | "--source" => translation.source = ip_range(&value(&mut tokens, token)?)?,
+            "-d" | "--destination" => {
+                translation.destination = ip_range(&value(&mut tokens, token)?)?
+            }
+            "-p" | "--protocol" => translation.protocol = protocol(&value(&mut tokens, token)?)?,
+            "-m" | "--match" => match value(&mut tokens, token)?.as_str() {
+                "tcp" | "udp" | "sctp" | "dccp" | "icmp" | "multiport" | "limit" | "hashlimit"
+                | "comment" => {}
+                "conntrack" | "state" => return Err(connection_state()),
+                module => return Err(format!("the {} match isn't supported", module)),
+            },
+            "--dport" | "--destination-port" => {
+                translation.ports = vec![port(&value(&mut tokens, token)?)?]
+            }
+            "--dports" | "--destination-ports" => {
+                translation.ports = value(&mut tokens, token)?
+                    .split(',')
+                    .map(port)
+                    .collect::<Result<_, _>>()?
+            }
+            "--limit" => {
+                translation.ratelimit = per_minute(&value(&mut tokens, token)?)?;
+                translation.shared_limit = true;
+            }
+            "--hashlimit" | "--hashlimit-upto" => {
+                translation.ratelimit = per_minute(&value(&mut tokens, token)?)?
+            }
+            "--hashlimit-mode" => {
+                if !value(&mut tokens, token)?
+                    .split(',')
+                    .any(|mode| mode == "srcip")
+                {
+                    translation.shared_limit = true;
+                }
+            }
+            "--hashlimit-above" => {
+                return Err(
+                    "a hashlimit matching traffic above the limit isn't supported".to_string(),
+                )
+            }
+            "--comment" => translation.name = Some(value(&mut tokens, token)?),
+            "-j" | "--jump" => translation.accept = value(&mut tokens, token)? == "ACCEPT",
+            // The burst and the settings of the hash table don't change what's allowed.
+            "--limit-burst" => {
+                value(&mut tokens, token)?;
+            }
+            option if option.starts_with("--hashlimit-") => {
+                value(&mut tokens, token)?;
+            }
+            "!" => return Err("negated matches aren't supported".to_string()),
+            "-i" | "--in-interface" => return Err(interface()),
+            "--icmp-type" => return Err("matching ICMP types isn't supported".to_string()),
+            option => return Err(format!("{} isn't supported", option)),
+        }
+    }
+
+    if chain != "INPUT" {
+        return Err(format!("only the INPUT chain is translated, not {}", chain));
+    }
+
+    Ok(translation)
+}
+
+/// Take the value of an option of an iptables rule.
+fn value<'a>(tokens: &mut impl Iterator<Item = &'a str>, option: &str) -> Result<String, String> {
+    tokens
+        .next()
+        .map(str::to_string)
+        .ok_or(format!("{} has no value", option))
+}
+
+/// Split a line of iptables-save into its arguments, unquoting the quoted ones like comments.
+fn split_iptables(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut in_token = false;
+    let mut quoted = false;
+    let mut chars = line.chars();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '"' => {
+                quoted = !quoted;
+                in_token = true;
+            }
+            '\\' if quoted => token.extend(chars.next()),
+            char if char.is_whitespace() && !quoted => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            char => {
+                token.push(char);
+                in_token = true;
+            }
+        }
+    }
+
+    if quoted {
+        return Err("a quote isn't closed".to_string());
+    }
+
+    if in_token {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// Translate the input chains of an nftables ruleset, as listed by `nft -j list ruleset`. Only rules
+/// accepting traffic are translated, matching on the IPv4 source and destination, protocol,
+/// destination port and limit.
+pub fn from_nft_json(file: &str, contents: &str) -> anyhow::Result<Import> {
+    let parsed: serde_json::Value = serde_json::from_str(contents)?;
+    let objects = parsed["nftables"]
+        .as_array()
+        .ok_or(anyhow::anyhow!("{} isn't the output of nft -j", file))?;
+    let mut import = Import::default();
+
+    // The chains attached to the input hook, by family, table and name.
+    let mut input_chains = vec![];
+
+    for chain in objects.iter().filter_map(|object| object.get("chain")) {
+        if chain["hook"] != "input" || !matches!(chain["family"].as_str(), Some("ip" | "inet")) {
+            continue;
+        }
+
+        let location = format!(
+            "{}: table {} chain {}",
+            file,
+            chain["table"].as_str().unwrap_or_default(),
+            chain["name"].as_str().unwrap_or_default()
+        );
+
+        if chain["policy"] == "accept" {
+            import.skipped.push(Skipped {
+                location,
+                original: "policy accept".to_string(),
+                reason: "the chain accepts the traffic no rule matches, Ghostwire drops it"
+                    .to_string(),
+            });
+        }
+
+        input_chains.push((&chain["family"], &chain["table"], &chain["name"]));
+    }
+
+    for rule in objects.iter().filter_map(|object| object.get("rule")) {
+        let location = format!(
+            "{}: table {} chain {} handle {}",
+            file,
+            rule["table"].as_str().unwrap_or_default(),
+            rule["chain"].as_str().unwrap_or_default(),
+            rule["handle"]
+        );
+        let original = serde_json::to_string(&rule["expr"])?;
+
+        let result = match input_chains.contains(&(&rule["family"], &rule["table"], &rule["chain"]))
+        {
+            true => translate_nft(rule),
+            false => Err("only chains attached to the IPv4 input hook are translated".to_string()),
+        };
+
+        import.push(location, original, result);
+    }
+
+    Ok(import)
+}
+
+/// Translate a rule of an nftables ruleset.
+fn translate_nft(rule: &serde_json::Value) -> Result<Translation, String> {
+    let mut translation = Translation {
+        name: rule["comment"].as_str().map(str::to_string),
+        ..Default::default()
+    };
+
+    for statement in rule["expr"].as_array().into_iter().flatten() {
+        let Some((kind, body)) = statement
+            .as_object()
+            .and_then(|object| object.iter().next())
+        else {
+            return Err(format!("{} isn't a statement", statement));
+        };
+
+        match kind.as_str() {
+            "match" => translate_nft_match(body, &mut translation)?,
+            "limit" => {
+                if body["inv"] == true {
+                    return Err("a limit matching traffic above it isn't supported".to_string());
+                }
+
+                let rate = body["rate"].as_u64().ok_or("the limit has no rate")?;
+                let unit = body["per"].as_str().unwrap_or("second");
+
+                translation.ratelimit = per_minute(&format!("{}/{}", rate, unit))?;
+                translation.shared_limit = true;
+            }
+            "accept" => translation.accept = true,
+            // Other verdicts leave the rule unaccepted, so it's reported as not translated.
+            "counter" | "log" | "drop" | "reject" | "jump" | "goto" | "return" | "queue" => {}
+            kind => return Err(format!("{} statements aren't supported", kind)),
+        }
+    }
+
+    Ok(translation)
+}
+
+/// Translate a match of an nftables rule.
+fn translate_nft_match(
+    body: &serde_json::Value,
+    translation: &mut Translation,
+) -> Result<(), String> {
+    if !matches!(body["op"].as_str(), Some("==" | "in")) {
+        return Err(format!("{} matches aren't supported", body["op"]));
+    }
+
+    let left = &body["left"];
+    let right = &body["right"];
+    let payload = (
+        left["payload"]["protocol"].as_str(),
+        left["payload"]["field"].as_str(),
+    );
+
+    match payload {
+        (Some("ip"), Some("saddr")) => translation.source = nft_address(right)?,
+        (Some("ip"), Some("daddr")) => translation.destination = nft_address(right)?,
+        (Some("ip"), Some("protocol")) => translation.protocol = protocol(&nft_protocol(right)?)?,
+        (Some(transport @ ("tcp" | "udp" | "sctp" | "dccp")), Some("dport")) => {
+            translation.protocol = protocol(transport)?;
+            translation.ports = match right {
+                serde_json::Value::Number(port) => vec![nft_port(port)?],
+                serde_json::Value::Object(set) if set.contains_key("set") => set["set"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|port| match port {
+                        serde_json::Value::Number(port) => nft_port(port),
+                        _ => Err("port ranges aren't supported".to_string()),
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => return Err("port ranges aren't supported".to_string()),
+            };
+        }
+        (Some("ip6"), _) => return Err("IPv6 isn't supported".to_string()),
+        (Some("icmp"), Some("type")) => {
+            return Err("matching ICMP types isn't supported".to_string())
+        }
+        _ => match (left["meta"]["key"].as_str(), left["ct"]["key"].as_str()) {
+            (Some("l4proto"), _) => translation.protocol = protocol(&nft_protocol(right)?)?,
+            (Some("nfproto"), _) if right == "ipv4" => {}
+            (Some("nfproto"), _) => return Err("IPv6 isn't supported".to_string()),
+            (Some("iif" | "iifname"), _) => return Err(interface()),
+            (_, Some("state")) => return Err(connection_state()),
+            _ => return Err(format!("matching on {} isn't supported", left)),
+        },
+    }
+
+    Ok(())
+}
+
+/// Read the protocol of an nftables match, a name or a number.
+fn nft_protocol(right: &serde_json::Value) -> Result<String, String> {
+    match right {
+        serde_json::Value::String(protocol) => Ok(protocol.clone()),
+        serde_json::Value::Number(protocol) => Ok(protocol.to_string()),
+        _ => Err("protocol sets aren't supported".to_string()),
+    }
+}
+
+/// Read an address of an nftables match as an IPv4 range in CIDR notation.
+fn nft_address(right: &serde_json::Value) -> Result<String, String> {
+    match (right.as_str(), right["prefix"]["addr"].as_str()) {
+        (Some(address), _) => ip_range(address),
+        (None, Some(address)) => ip_range(&format!("{}/{}", address, right["prefix"]["len"])),
+        _ => Err("address sets and ranges aren't supported".to_string()),
+    }
+}
+
+/// Read a port of an nftables match.
+fn nft_port(port: &serde_json::Number) -> Result<u16, String> {
+    port.as_u64()
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or(format!("{} isn't a port", port))
+}
+
+/// Check an IPv4 address or range in CIDR notation, giving single addresses a /32.
+fn ip_range(range: &str) -> Result<String, String> {
+    let (address, prefix_length) = range.split_once('/').unwrap_or((range, "32"));
+
+    if address.contains(':') {
+        return Err("IPv6 isn't supported".to_string());
+    }
+
+    match (address.parse::<Ipv4Addr>(), prefix_length.parse::<u8>()) {
+        (Ok(_), Ok(prefix_length)) if prefix_length <= 32 => {
+            Ok(format!("{}/{}", address, prefix_length))
+        }
+        _ => Err(format!("{} isn't an IPv4 range", range)),
+    }
+}
+
+/// Translate a protocol name or number to the name a Ghostwire rule uses, or None for every
+/// protocol.
+fn protocol(protocol: &str) -> Result<Option<String>, String> {
+    match parse_protocol(protocol) {
+        Some(0) => Ok(None),
+        Some(number) => Ok(Some(protocol_name(number))),
+        None => Err(format!("the {} protocol isn't supported", protocol)),
+    }
+}
+
+/// Read a destination port. Ranges aren't supported.
+fn port(port: &str) -> Result<u16, String> {
+    if port.contains(':') || port.contains('-') {
+        return Err("port ranges aren't supported".to_string());
+    }
+
+    port.parse().map_err(|_| format!("{} isn't a port", port))
+}
+
+/// Convert a rate like `10/second` to the packets per minute of a Ghostwire ratelimit, rounding up
+/// so it's never stricter than the original.
+fn per_minute(rate: &str) -> Result<u32, String> {
+    let (count, unit) = rate.split_once('/').unwrap_or((rate, "second"));
+    let count: u32 = count
+        .parse()
+        .map_err(|_| format!("{} isn't a rate", rate))?;
+
+    let per_minute = match unit {
+        "s" | "sec" | "second" => count.saturating_mul(60),
+        "m" | "min" | "minute" => count,
+        "h" | "hour" => count.div_ceil(60),
+        "d" | "day" => count.div_ceil(60 * 24),
+        _ => return Err(format!("{} isn't a rate", rate)),
+    };
+
+    Ok(per_minute.max(1))
+}
+
+/// Why rules matching the connection state aren't translated
+fn connection_state() -> String {
+    "it matches the connection state, Ghostwire already lets in replies to outgoing connections"
+        .to_string()
+}
+
+/// Why rules matching the interface aren't translated
+fn interface() -> String {
+    "it matches the interface, Ghostwire applies its rules to a single one".to_string()
+}
+
+/// Quote a string for YAML if it needs it.
+fn quote(value: &str) -> String {
+    serde_yaml::to_string(value)
+        .map(|quoted| quoted.trim_end().to_string())
+        .unwrap_or_else(|_| format!("{:?}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Translate a single rule of the INPUT chain of the filter table.
+    fn iptables(rule: &str) -> Import {
+        from_iptables_save("rules.v4", &format!("*filter\n{}\nCOMMIT\n", rule))
+    }
+
+    /// The reason the only rule of an import wasn't translated.
+    fn skipped_reason(import: &Import) -> &str {
+        assert!(import.rules.is_empty(), "the rule was translated");
+        assert_eq!(import.skipped.len(), 1);
+
+        &import.skipped[0].reason
+    }
+
+    #[test]
+    fn split_unquotes_comments() {
+        assert_eq!(
+            split_iptables(r#"-A INPUT -m comment --comment "ssh from \"ops\"" -j ACCEPT"#)
+                .unwrap(),
+            vec![
+                "-A",
+                "INPUT",
+                "-m",
+                "comment",
+                "--comment",
+                "ssh from \"ops\"",
+                "-j",
+                "ACCEPT"
+            ]
+        );
+        assert!(split_iptables(r#"-A INPUT --comment "open"#).is_err());
+    }
+
+    #[test]
+    fn quoted_comment_names_the_rule() {
+        let import = iptables(
+            r#"-A INPUT -s 10.0.0.0/8 -p tcp -m tcp --dport 22 -m comment --comment "ssh from the office" -j ACCEPT"#,
+        );
+        let rule = &import.rules[0];
+
+        assert_eq!(rule.name.as_deref(), Some("ssh from the office"));
+        assert_eq!(rule.source, "10.0.0.0/8");
+        assert_eq!(rule.destination, "0.0.0.0/0");
+        assert_eq!(rule.protocol, "TCP");
+        assert_eq!(rule.ports, vec![22]);
+        assert_eq!(rule.ratelimit, 0);
+    }
+
+    #[test]
+    fn multiport_keeps_every_port() {
+        let import = iptables("-A INPUT -p tcp -m multiport --dports 80,443,8080 -j ACCEPT");
+
+        assert_eq!(import.rules[0].ports, vec![80, 443, 8080]);
+    }
+
+    #[test]
+    fn port_ranges_are_skipped() {
+        assert_eq!(
+            skipped_reason(&iptables(
+                "-A INPUT -p tcp -m tcp --dport 1000:2000 -j ACCEPT"
+            )),
+            "port ranges aren't supported"
+        );
+        assert_eq!(
+            skipped_reason(&iptables(
+                "-A INPUT -p udp -m multiport --dports 53,1000:2000 -j ACCEPT"
+            )),
+            "port ranges aren't supported"
+        );
+    }
+
+    #[test]
+    fn negation_is_skipped() {
+        assert_eq!(
+            skipped_reason(&iptables("-A INPUT ! -s 10.0.0.0/8 -j ACCEPT")),
+            "negated matches aren't supported"
+        );
+        assert_eq!(
+            skipped_reason(&iptables("-A INPUT -p tcp -m tcp ! --dport 22 -j ACCEPT")),
+            "negated matches aren't supported"
+        );
+    }
+
+    #[test]
+    fn limit_is_shared_by_every_source() {
+        let import = iptables(
+            "-A INPUT -p tcp -m tcp --dport 80 -m limit --limit 10/sec --limit-burst 20 -j ACCEPT",
+        );
+
+        assert_eq!(import.rules[0].ratelimit, 600);
+        assert_eq!(import.notes.len(), 1);
+    }
+
+    #[test]
+    fn hashlimit_by_source_is_per_source() {
+        let import = iptables("-A INPUT -p tcp -m tcp --dport 80 -m hashlimit --hashlimit-upto 30/min --hashlimit-burst 5 --hashlimit-mode srcip --hashlimit-name http -j ACCEPT");
+
+        assert_eq!(import.rules[0].ratelimit, 30);
+        assert!(import.notes.is_empty());
+    }
+
+    #[test]
+    fn hashlimit_by_destination_is_shared() {
+        let import = iptables("-A INPUT -p tcp -m tcp --dport 80 -m hashlimit --hashlimit-upto 30/min --hashlimit-mode dstip --hashlimit-name http -j ACCEPT");
+
+        assert_eq!(import.rules[0].ratelimit, 30);
+        assert_eq!(import.notes.len(), 1);
+    }
+
+    #[test]
+    fn hashlimit_above_is_skipped() {
+        assert_eq!(
+            skipped_reason(&iptables("-A INPUT -p tcp -m hashlimit --hashlimit-above 30/min --hashlimit-name http -j ACCEPT")),
+            "a hashlimit matching traffic above the limit isn't supported"
+        );
+    }
+
+    #[test]
+    fn rates_round_up_to_per_minute() {
+        assert_eq!(per_minute("5/second"), Ok(300));
+        assert_eq!(per_minute("5"), Ok(300));
+        assert_eq!(per_minute("5/min"), Ok(5));
+        assert_eq!(per_minute("90/hour"), Ok(2));
+        assert_eq!(per_minute("1/day"), Ok(1));
+        assert!(per_minute("5/fortnight").is_err());
+        assert!(per_minute("fast").is_err());
+    }
+
+    #[test]
+    fn only_accepting_input_rules_are_translated() {
+        let import = from_iptables_save(
+            "rules.v4",
+            "*filter\n-A INPUT -p tcp -m tcp --dport 22 -j DROP\n-A FORWARD -p tcp -j ACCEPT\n-A INPUT -m conntrack --ctstate ESTABLISHED -j ACCEPT\nCOMMIT\n",
+        );
+
+        assert!(import.rules.is_empty());
+        assert_eq!(import.skipped.len(), 3);
+    }
+
+    #[test]
+    fn accepting_iptables_policy_is_reported() {
+        let import = from_iptables_save(
+            "rules.v4",
+            "*nat\n:INPUT ACCEPT [0:0]\nCOMMIT\n*filter\n:INPUT ACCEPT [0:0]\n:FORWARD DROP [0:0]\nCOMMIT\n",
+        );
+
+        assert_eq!(import.skipped.len(), 1);
+        assert_eq!(import.skipped[0].location, "rules.v4:5");
+    }
+
+    /// An nftables ruleset with an input chain holding a rule of the expressions.
+    fn nft(policy: &str, expr: serde_json::Value) -> Import {
+        let ruleset = serde_json::json!({"nftables": [
+            {"chain": {"family": "inet", "table": "filter", "name": "input", "hook": "input", "policy": policy}},
+            {"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 4, "expr": expr}},
+        ]});
+
+        from_nft_json("ruleset.json", &ruleset.to_string()).unwrap()
+    }
+
+    #[test]
+    fn accepting_nft_policy_is_reported() {
+        let import = nft("accept", serde_json::json!([{"accept": null}]));
+
+        assert_eq!(import.rules.len(), 1);
+        assert_eq!(import.skipped[0].original, "policy accept");
+    }
+
+    #[test]
+    fn nft_port_sets_keep_every_port() {
+        let import = nft(
+            "drop",
+            serde_json::json!([
+                {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": {"prefix": {"addr": "192.168.0.0", "len": 16}}}},
+                {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": {"set": [80, 443]}}},
+                {"accept": null},
+            ]),
+        );
+        let rule = &import.rules[0];
+
+        assert_eq!(rule.source, "192.168.0.0/16");
+        assert_eq!(rule.protocol, "TCP");
+        assert_eq!(rule.ports, vec![80, 443]);
+    }
+
+    #[test]
+    fn nft_negation_and_ranges_are_skipped() {
+        let negated = nft(
+            "drop",
+            serde_json::json!([
+                {"match": {"op": "!=", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "10.0.0.1"}},
+                {"accept": null},
+            ]),
+        );
+        let range = nft(
+            "drop",
+            serde_json::json!([
+                {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": {"range": [1000, 2000]}}},
+                {"accept": null},
+            ]),
+        );
+
+        assert_eq!(skipped_reason(&negated), "\"!=\" matches aren't supported");
+        assert_eq!(skipped_reason(&range), "port ranges aren't supported");
+    }
+
+    #[test]
+    fn nft_limit_is_shared() {
+        let import = nft(
+            "drop",
+            serde_json::json!([
+                {"limit": {"rate": 2, "per": "hour"}},
+                {"accept": null},
+            ]),
+        );
+
+        assert_eq!(import.rules[0].ratelimit, 1);
+        assert_eq!(import.notes.len(), 1);
+    }
+}
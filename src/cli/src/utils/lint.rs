@@ -0,0 +1,336 @@
+use super::diff::covers_traffic;
+use colored::*;
+use ghostwire_types::{
+    has_ports,
+    protocol_name,
+    yaml::{
+        convert_to_rule,
+        SourcedRule,
+    },
+    Rule,
+};
+use std::net::Ipv4Addr;
+
+/// A problem found in a configuration
+pub struct Finding {
+    /// Where the rule with the problem was written, as `file:line`
+    pub location: String,
+    /// What's wrong
+    pub message: String,
+}
+
+/// Check the rules of a configuration for mistakes that still parse: rules that can never match,
+/// duplicates, subnets written with host bits set and destinations the XDP program won't enforce. Rules are checked against the earlier rules
+/// of every file, as they're applied as one ruleset.
+pub fn lint(sourced_rules: &[SourcedRule]) -> Vec<Finding> {
+    let location = |index: usize| sourced_rules[index].location();
+    let mut findings = vec![];
+    let mut report = |index: usize, message: String| {
+        findings.push(Finding {
+            location: location(index),
+            message,
+        })
+    };
+
+    // The rules that parsed, with their index in the configuration.
+    let mut rules: Vec<(usize, Rule)> = vec![];
+
+    for (index, sourced) in sourced_rules.iter().enumerate() {
+        let yaml_rule = &sourced.rule;
+
+        for range in [&yaml_rule.source_ip_range, &yaml_rule.destination_ip_range] {
+            if let Some(subnet) = host_bits_set(range) {
+                report(
+                    index,
+                    format!("{} has host bits set, it's the same as {}", range, subnet),
+                );
+            }
+        }
+
+        let rule = match convert_to_rule(yaml_rule.clone()) {
+            Ok(rule) => rule,
+            Err(e) => {
+                report(index, format!("invalid rule: {}", e));
+                continue;
+            }
+        };
+
+        let mut never_matches = false;
+
+        if rule.protocol_number != 0 && !has_ports(rule.protocol_number) && rule.port_number != 0 {
+            report(
+                index,
+                format!(
+                    "{} has no ports, so this rule never matches. Remove the port",
+                    protocol_name(rule.protocol_number)
+                ),
+            );
+            never_matches = true;
+        }
+
+        if rule.protocol_number == 0 && rule.port_number != 0 {
+            report(
+                index,
+                "ALL matches every protocol, so the port is ignored. Remove the port".to_string(),
+            );
+        }
+
+        if u32::from_be(rule.source_start_ip) > u32::from_be(rule.source_end_ip) {
+            report(
+                index,
+                "the source range is empty, so this rule never matches".to_string(),
+            );
+            never_matches = true;
+        }
+
+        if (rule.destination_start_ip, rule.destination_end_ip) != (0, u32::MAX) {
+            report(
+                index,
+                format!(
+                    "the XDP program doesn't check destinations, so this rule matches traffic to every destination, not just {}",
+                    yaml_rule.destination_ip_range
+                ),
+            );
+        }
+
+        let earlier = rules
+            .iter()
+            .find(|(_, earlier)| covers_traffic(earlier, &rule));
+
+        match earlier {
+            Some((earlier_index, earlier)) if same_rule(earlier, &rule) => {
+                report(
+                    index,
+                    format!("duplicate of the rule at {}", location(*earlier_index)),
+                );
+                never_matches = true;
+            }
+            Some((earlier_index, _)) => {
+                report(
+                    index,
+                    format!(
+                        "shadowed by the rule at {}, which matches all of its traffic first, so this rule never matches",
+                        location(*earlier_index)
+                    ),
+                );
+                never_matches = true;
+            }
+            None => {}
+        }
+
+        if never_matches && rule.ratelimiting != 0 {
+            report(
+                index,
+                "the ratelimit never applies, as the rule never matches".to_string(),
+            );
+        }
+
+        rules.push((index, rule));
+    }
+
+    findings
+}
+
+/// Print the findings of the linter to the console.
+pub fn print_findings(findings: &[Finding]) {
+    for finding in findings {
+        eprintln!(
+            "{}: {}: {}",
+            finding.location.bold(),
+            "warning".bold().yellow(),
+            finding.message
+        );
+    }
+}
+
+/// Whether two rules match the same traffic with the same ratelimit.
+fn same_rule(a: &Rule, b: &Rule) -> bool {
+    covers_traffic(a, b) && covers_traffic(b, a) && a.ratelimiting == b.ratelimiting
+}
+
+/// If an IP range in CIDR notation has bits set past its prefix, return the subnet it stands for.
+fn host_bits_set(range: &str) -> Option<String> {
+    let (ip, prefix_length) = range.split_once('/')?;
+    let ip: Ipv4Addr = ip.parse().ok()?;
+    let prefix_length: u32 = prefix_length.parse().ok()?;
+    let mask = u32::MAX
+        .checked_shl(32 - prefix_length.min(32))
+        .unwrap_or(0);
+
+    match u32::from(ip) & !mask {
+        0 => None,
+        _ => Some(format!(
+            "{}/{}",
+            Ipv4Addr::from(u32::from(ip) & mask),
+            prefix_length
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lint rules written as YAML, one per line of a file named rules.yml.
+    fn lint_rules(rules: &[&str]) -> Vec<Finding> {
+        let sourced_rules = rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| SourcedRule {
+                rule: serde_yaml::from_str(rule).unwrap(),
+                file: "rules.yml".to_string(),
+                line: Some(index + 1),
+                index,
+                fields: Default::default(),
+            })
+            .collect::<Vec<_>>();
+
+        lint(&sourced_rules)
+    }
+
+    /// The messages of the findings at a line.
+    fn messages_at(findings: &[Finding], line: usize) -> Vec<&str> {
+        findings
+            .iter()
+            .filter(|finding| finding.location == format!("rules.yml:{}", line))
+            .map(|finding| finding.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn clean_rules_have_no_findings() {
+        let findings = lint_rules(&[
+            "{source_ip_range: 10.0.0.0/8, protocol: TCP, port: 22}",
+            "{protocol: TCP, port: 443}",
+            "{protocol: ICMP}",
+        ]);
+
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn broader_earlier_rule_shadows() {
+        let findings = lint_rules(&[
+            "{source_ip_range: 10.0.0.0/8, protocol: TCP}",
+            "{source_ip_range: 10.1.0.0/16, protocol: TCP, port: 22}",
+        ]);
+
+        assert_eq!(messages_at(&findings, 1), Vec::<&str>::new());
+        assert!(messages_at(&findings, 2)[0].starts_with("shadowed by the rule at rules.yml:1"));
+    }
+
+    #[test]
+    fn narrower_earlier_rule_doesnt_shadow() {
+        let findings = lint_rules(&[
+            "{source_ip_range: 10.1.0.0/16, protocol: TCP, port: 22}",
+            "{source_ip_range: 10.0.0.0/8, protocol: TCP}",
+        ]);
+
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn destination_doesnt_stop_shadowing() {
+        let findings = lint_rules(&[
+            "{destination_ip_range: 10.0.0.1/32, protocol: TCP, port: 22}",
+            "{destination_ip_range: 10.0.0.2/32, protocol: TCP, port: 22}",
+        ]);
+        let messages = messages_at(&findings, 2);
+
+        assert!(messages
+            .iter()
+            .any(|message| message.starts_with("duplicate of the rule at rules.yml:1")));
+    }
+
+    #[test]
+    fn destination_is_reported() {
+        let findings = lint_rules(&["{destination_ip_range: 192.168.1.0/24, protocol: UDP}"]);
+
+        assert_eq!(
+            messages_at(&findings, 1),
+            vec!["the XDP program doesn't check destinations, so this rule matches traffic to every destination, not just 192.168.1.0/24"]
+        );
+    }
+
+    #[test]
+    fn duplicate_is_reported_with_ratelimit() {
+        let findings = lint_rules(&[
+            "{protocol: UDP, port: 53, ratelimit: 100}",
+            "{protocol: udp, port: 53, ratelimit: 100}",
+        ]);
+
+        assert_eq!(
+            messages_at(&findings, 2),
+            vec![
+                "duplicate of the rule at rules.yml:1",
+                "the ratelimit never applies, as the rule never matches"
+            ]
+        );
+    }
+
+    #[test]
+    fn different_ratelimit_is_shadowed_not_duplicate() {
+        let findings = lint_rules(&[
+            "{protocol: UDP, port: 53}",
+            "{protocol: UDP, port: 53, ratelimit: 100}",
+        ]);
+
+        assert!(messages_at(&findings, 2)[0].starts_with("shadowed by the rule at rules.yml:1"));
+    }
+
+    #[test]
+    fn inner_rules_dont_shadow_outer_ones() {
+        let findings = lint_rules(&[
+            "{protocol: TCP, match_on: inner}",
+            "{protocol: TCP, port: 22}",
+        ]);
+
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn port_on_protocol_without_ports_never_matches() {
+        let findings = lint_rules(&["{protocol: ICMP, port: 8, ratelimit: 10}"]);
+
+        assert_eq!(
+            messages_at(&findings, 1),
+            vec![
+                "ICMP has no ports, so this rule never matches. Remove the port",
+                "the ratelimit never applies, as the rule never matches"
+            ]
+        );
+    }
+
+    #[test]
+    fn port_on_all_is_ignored() {
+        let findings = lint_rules(&["{protocol: ALL, port: 22}", "{protocol: TCP, port: 80}"]);
+
+        assert_eq!(
+            messages_at(&findings, 1),
+            vec!["ALL matches every protocol, so the port is ignored. Remove the port"]
+        );
+        // The port being ignored, the rule for every protocol shadows the later ones.
+        assert!(messages_at(&findings, 2)[0].starts_with("shadowed by the rule at rules.yml:1"));
+    }
+
+    #[test]
+    fn host_bits_are_reported() {
+        let findings = lint_rules(&["{source_ip_range: 10.1.2.3/16, protocol: TCP}"]);
+
+        assert_eq!(
+            messages_at(&findings, 1),
+            vec!["10.1.2.3/16 has host bits set, it's the same as 10.1.0.0/16"]
+        );
+        assert_eq!(host_bits_set("10.1.0.0/16"), None);
+        assert_eq!(host_bits_set("10.1.2.3"), None);
+        assert_eq!(host_bits_set("0.0.0.0/0"), None);
+    }
+
+    #[test]
+    fn invalid_rule_is_reported() {
+        let findings = lint_rules(&["{protocol: BOGUS}"]);
+
+        assert!(messages_at(&findings, 1)[0]
+            .starts_with("invalid rule: protocol: BOGUS isn't a protocol"));
+    }
+}
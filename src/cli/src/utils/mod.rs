@@ -1,4 +1,7 @@
 pub mod console;
+pub mod diff;
+pub mod export;
 pub mod handler;
+pub mod import;
+pub mod lint;
 pub mod socket;
-pub mod yaml;
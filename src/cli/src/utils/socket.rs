@@ -1,39 +1,76 @@
 use ghostwire_types::{
     ClientMessage,
     ServerMessage,
+    MAX_MESSAGE_SIZE,
+    PROTOCOL_VERSION,
 };
 use std::{
     io::{
+        BufRead,
+        BufReader,
         Read,
         Write,
     },
     os::unix::net::UnixStream,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
 };
 
-/// Send a message to the firewall, erroring if unsuccessful
-pub fn send_message(client_message: ClientMessage) -> anyhow::Result<String> {
+/// Send a message to the firewall over the socket at the path, erroring if unsuccessful
+pub fn send_message(socket: &str, client_message: ClientMessage) -> anyhow::Result<String> {
+    send_request(socket, client_message).map(|response| response.message)
+}
+
+/// Send a message to the firewall over the socket at the path, returning the whole response if
+/// successful
+pub fn send_request(
+    socket: &str,
+    mut client_message: ClientMessage,
+) -> anyhow::Result<ServerMessage> {
     // Connect to the socket.
-    let mut stream = UnixStream::connect("/tmp/ghostwire.sock")
+    let mut stream = UnixStream::connect(socket)
         .map_err(|_| anyhow::anyhow!("couldn't connect to the ghostwire server, is it online?"))?;
 
-    // Serialize the client message.
-    let serialized = serde_json::to_string(&client_message)?;
+    // Identify the request, so it can be matched up with the server's logs.
+    client_message.version = PROTOCOL_VERSION;
+    client_message.request_id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default();
 
-    // Send the message over the wire.
-    stream.write_all(serialized.as_bytes())?;
+    // Serialize the client message. Messages are delimited by newlines, which serde_json never
+    // emits in compact output.
+    let mut serialized = serde_json::to_vec(&client_message)?;
+    serialized.push(b'\n');
 
-    let mut buffer = [0; 1024];
+    // Send the message over the wire.
+    stream.write_all(&serialized)?;
 
     // Read the response.
-    let bytes_read = stream.read(&mut buffer)?;
-
-    let response = std::str::from_utf8(&buffer[..bytes_read])?;
+    let mut response = String::new();
+    BufReader::new(stream)
+        .take(MAX_MESSAGE_SIZE)
+        .read_line(&mut response)?;
 
     // Deserialize the response.
-    let server_response: ServerMessage = serde_json::from_str(response)?;
+    let server_response: ServerMessage = serde_json::from_str(&response)?;
+
+    if server_response.version != PROTOCOL_VERSION {
+        anyhow::bail!(
+            "the server speaks protocol version {}, but this CLI speaks version {}. Use a CLI that matches the server",
+            server_response.version,
+            PROTOCOL_VERSION
+        );
+    }
+
+    if server_response.request_id != client_message.request_id {
+        anyhow::bail!("the server responded to a different request");
+    }
 
     if server_response.request_success {
-        Ok(server_response.message)
+        Ok(server_response)
     } else {
         anyhow::bail!(
             "The server responded with an error: {}",
@@ -17,14 +17,94 @@ pub struct Rule {
     /// The end destination IP address in big endian. If this rule applies everywhere, all bytes
     /// will show 0
     pub destination_end_ip: u32,
-    /// Protocol number (currently limited to either 1, 6, 17 for ICMP, TCP, and UDP respectively.
-    /// if this rule applies to all protocols, this will be zero)
+    /// The IP protocol number, like 6 for TCP or 47 for GRE. If this rule applies to all protocols,
+    /// this will be zero
     pub protocol_number: u8,
-    /// The port if TCP or UDP (if not, 0)
+    /// Which headers of tunneled traffic the rule matches on, MATCH_ON_OUTER or MATCH_ON_INNER.
+    /// It takes the padding after the protocol
+    pub match_on: u8,
+    /// The destination port if the protocol has ports, like TCP, UDP, SCTP and DCCP (if not, 0)
     pub port_number: u16,
     /// If the rule is a ratelimiting one, represent the amount of traffic allowed per IP over 10
     /// seconds. If there's no ratelimiting rule, this is 0.
     pub ratelimiting: u32,
+    /// Whether the packets matching this rule are sent to the daemon's packet log, 0 or 1
+    pub log: u8,
+}
+
+// The daemon and the eBPF program share the rule maps, so their rules must have the same layout.
+// `log` added a trailing byte, padded up to 32 bytes.
+const _: () = assert!(core::mem::size_of::<Rule>() == 32);
+
+/// The rule matches on the outermost IPv4 header, as for traffic that isn't tunneled
+pub const MATCH_ON_OUTER: u8 = 0;
+/// The rule matches on the IPv4 header inside a GRE, IPIP or VXLAN tunnel, and never matches
+/// traffic that isn't tunneled
+pub const MATCH_ON_INNER: u8 = 1;
+
+/// The packet was let in by the rule
+pub const LOG_REASON_PASSED: u8 = 0;
+/// The packet matched the rule, but was dropped as its source went over the ratelimit
+pub const LOG_REASON_RATELIMITED: u8 = 1;
+/// No rule or holepunched connection let the packet in, so it was dropped
+pub const LOG_REASON_DEFAULT_DROP: u8 = 2;
+/// The packet was the first of its source dropped over the rule's ratelimit this minute. It's sent
+/// on its own ring buffer whether or not the rule logs, for the daemon to report the source as
+/// blocked
+pub const LOG_REASON_BLOCKED: u8 = 3;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+/// A packet the XDP program logged, sent to the daemon over the PACKET_LOG ring buffer
+pub struct LogEvent {
+    /// The ID of the rule that matched the packet, or 0 if it was dropped by default
+    pub rule_id: u32,
+    /// The source IP address in big endian
+    pub src_ip: u32,
+    /// The destination IP address in big endian
+    pub dst_ip: u32,
+    /// The source port in big endian, or 0 if the protocol has no ports
+    pub src_port: u16,
+    /// The destination port in big endian, or 0 if the protocol has no ports
+    pub dst_port: u16,
+    /// The IP protocol number
+    pub protocol: u8,
+    /// Why the packet was logged, one of the LOG_REASON constants
+    pub reason: u8,
+    /// Unused, so every byte of the event is initialized
+    pub _padding: [u8; 2],
+    /// The number of events this CPU left out over the cap since the last one it sent
+    pub suppressed: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A connection the host made, the key of the HOLEPUNCHED map. Addresses and ports are in big
+/// endian, as laid out in the packet.
+pub struct Connection {
+    /// The address of the host
+    pub local_ip: u32,
+    /// The address of the peer
+    pub remote_ip: u32,
+    /// The port of the host, or 0 if the protocol has no ports
+    pub local_port: u16,
+    /// The port of the peer, or 0 if the protocol has no ports
+    pub remote_port: u16,
+    /// The IP protocol number
+    pub protocol: u8,
+    /// Unused, so every byte of the key is initialized
+    pub _padding: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+/// The packet log settings, written by the daemon to the LOG_SETTINGS map
+pub struct LogSettings {
+    /// Whether packets dropped by default are logged, 0 or 1
+    pub default_drop: u32,
+    /// The maximum number of events each CPU sends per second, the others are counted as
+    /// suppressed
+    pub max_per_second: u32,
 }
 
 #[repr(C)]
@@ -43,3 +123,9 @@ pub struct RuleAnalytics {
 unsafe impl aya::Pod for Rule {}
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for RuleAnalytics {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LogEvent {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LogSettings {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Connection {}
@@ -1,5 +1,8 @@
 use crate::{
-    utils::ptr_at::tc_ptr_at_fallible,
+    utils::{
+        headers::PortsHdr,
+        ptr_at::tc_ptr_at_fallible,
+    },
     HOLEPUNCHED,
 };
 use aya_ebpf::{
@@ -7,11 +10,17 @@ use aya_ebpf::{
     helpers::bpf_ktime_get_ns,
     programs::TcContext,
 };
+use ghostwire_common::Connection;
 use network_types::{
-    eth::EthHdr,
+    eth::{
+        EthHdr,
+        EtherType,
+    },
     ip::{
         IpProto::{
+            Dccp,
             Icmp,
+            Sctp,
             Tcp,
             Udp,
         },
@@ -24,14 +33,24 @@ use network_types::{
 /// The function is called whenever a packet is leaving the server through the traffic control
 /// hook. It should:
 /// 1. Parse the packet;
-///     - Get the source & destination IP addresses and the port (if the protocol is portless, like ICMP, both ports will be 0)
+///     - Get the source & destination IP addresses and the port (for ICMP, both ports will be 0)
+///     - Only connections of TCP, UDP, SCTP, DCCP and ICMP are holepunched. Other protocols,
+///       like GRE and ESP, are only let in by rules, so a packet the host sends doesn't open up
+///       every protocol without ports from its peer
 /// 2. Update the holepunched LRU map;
-///     - Key is the source IP + source port + destination IP + destination port
+///     - Key is the connection: both addresses, both ports and the protocol
 ///     - If the connection is already in the map, update the timestamp
 ///     - If the connection is not in the map, add it
 ///     - If the connection is a TCP connection and the FIN / RST flags are set, remove from the map
 pub unsafe fn ghostwire_egress_fallible(tc: TcContext) -> Result<i32, ()> {
-    // Skip the ethernet header, that's not providing us with any value right now.
+    // Only IPv4 is filtered, so only IPv4 connections are holepunched.
+    let eth_header: *const EthHdr = tc_ptr_at_fallible(&tc, 0).map_err(|_| ())?;
+
+    match unsafe { (*eth_header).ether_type } {
+        EtherType::Ipv4 => {}
+        _ => return Ok(TC_ACT_PIPE),
+    }
+
     // Attempt to parse the IP header.
     let ip_header: *const Ipv4Hdr = tc_ptr_at_fallible(&tc, EthHdr::LEN).map_err(|_| ())?;
 
@@ -39,6 +58,11 @@ pub unsafe fn ghostwire_egress_fallible(tc: TcContext) -> Result<i32, ()> {
     let src_ip = unsafe { (*ip_header).src_addr };
     let dst_ip = unsafe { (*ip_header).dst_addr };
     let protocol = unsafe { (*ip_header).proto };
+    // The IHL counts 32-bit words, so options put the ports past the fixed 20 bytes.
+    let header_len = unsafe { (*ip_header).ihl() } as usize * 4;
+    if header_len < Ipv4Hdr::LEN {
+        return Ok(TC_ACT_PIPE);
+    }
     // Store whether the connection should be removed from the holepunched map, instead of
     // appended.
     let mut remove = false;
@@ -46,7 +70,7 @@ pub unsafe fn ghostwire_egress_fallible(tc: TcContext) -> Result<i32, ()> {
         Tcp => {
             // Parse the TCP header.
             let tcp_header: *const TcpHdr =
-                tc_ptr_at_fallible::<TcpHdr>(&tc, EthHdr::LEN + Ipv4Hdr::LEN).map_err(|_| ())?;
+                tc_ptr_at_fallible::<TcpHdr>(&tc, EthHdr::LEN + header_len).map_err(|_| ())?;
 
             // Check if the connection is being closed.
             // Currently, this is limited to the RST flag. The problem with FIN is the server will
@@ -63,17 +87,32 @@ pub unsafe fn ghostwire_egress_fallible(tc: TcContext) -> Result<i32, ()> {
         Udp => {
             // Parse the UDP header.
             let udp_header: *const UdpHdr =
-                tc_ptr_at_fallible::<UdpHdr>(&tc, EthHdr::LEN + Ipv4Hdr::LEN).map_err(|_| ())?;
+                tc_ptr_at_fallible::<UdpHdr>(&tc, EthHdr::LEN + header_len).map_err(|_| ())?;
 
             // Get the source and destination ports.
             ((*udp_header).source, (*udp_header).dest)
         }
+        Sctp | Dccp => {
+            // SCTP and DCCP headers start with the ports, like UDP.
+            let ports_header: *const PortsHdr =
+                tc_ptr_at_fallible::<PortsHdr>(&tc, EthHdr::LEN + header_len).map_err(|_| ())?;
+
+            // Get the source and destination ports.
+            ((*ports_header).source, (*ports_header).dest)
+        }
+        // ICMP has no ports, its replies are matched on the addresses and protocol.
         Icmp => (0, 0),
         _ => return Ok(TC_ACT_PIPE),
     };
 
-    // Get the key for the holepunched map, upgrading each type to u64 to avoid overflow.
-    let key = src_ip as u64 + src_port as u64 + dst_ip as u64 + dst_port as u64;
+    let key = Connection {
+        local_ip: src_ip,
+        remote_ip: dst_ip,
+        local_port: src_port,
+        remote_port: dst_port,
+        protocol: protocol as u8,
+        _padding: [0; 3],
+    };
 
     match remove {
         true => {
@@ -8,10 +8,14 @@ use aya_ebpf::{
     programs::XdpContext,
 };
 use network_types::{
-    eth::EthHdr,
+    eth::{
+        EthHdr,
+        EtherType,
+    },
     ip::{
         IpProto::{
-            Icmp,
+            Dccp,
+            Sctp,
             Tcp,
             Udp,
         },
@@ -19,21 +23,61 @@ use network_types::{
     },
     tcp::TcpHdr,
     udp::UdpHdr,
+    vxlan::VxlanHdr,
 };
 
 use crate::{
-    utils::ptr_at::xdp_ptr_at_fallible,
+    utils::{
+        headers::{
+            GreHdr,
+            PortsHdr,
+        },
+        packet_log::{
+            log_packet,
+            logs_default_drops,
+            report_blocked,
+        },
+        ptr_at::xdp_ptr_at_fallible,
+    },
+    ACTIVE_RULES,
     HOLEPUNCHED,
     RATELIMITING,
-    RULES,
+    RULES_A,
+    RULES_B,
     RULE_ANALYTICS,
 };
-use ghostwire_common::RuleAnalytics;
+use ghostwire_common::{
+    Connection,
+    LogEvent,
+    Rule,
+    RuleAnalytics,
+    LOG_REASON_BLOCKED,
+    LOG_REASON_DEFAULT_DROP,
+    LOG_REASON_PASSED,
+    LOG_REASON_RATELIMITED,
+    MATCH_ON_INNER,
+};
+
+/// The GRE flag saying a checksum follows the header
+const GRE_CHECKSUM: u16 = 0x8000;
+/// The GRE flag saying a key follows the header
+const GRE_KEY: u16 = 0x2000;
+/// The GRE flag saying a sequence number follows the header
+const GRE_SEQUENCE: u16 = 0x1000;
+/// The bits of the GRE version
+const GRE_VERSION: u16 = 0x0007;
+/// The EtherType of IPv4, carried in GRE
+const ETH_P_IP: u16 = 0x0800;
+/// The EtherType of Ethernet frames carried in GRE, as in GRETAP
+const ETH_P_TEB: u16 = 0x6558;
+/// The UDP port of VXLAN
+const VXLAN_PORT: u16 = 4789;
 
 /// The function called whenever a packet enters through the wire. This should:
 /// 1. Parse the packet;
 ///     - Letting the packet through if it's an internal protocol (like ARP)
 ///     - Dropping or rejecting clearly malformed traffic
+///     - Parsing the packet inside GRE, IPIP and VXLAN tunnels, for the rules matching on it
 /// 2. Look for rules;
 ///     - Evaluating rules to see if they're applicable to this rule
 ///     - Performing ratelimiting if the rule has it enabled
@@ -42,55 +86,47 @@ use ghostwire_common::RuleAnalytics;
 ///     - When connections are terminated (like if the client sends a FIN or RST to the port), remove from the holepunched map
 /// 4. Drop traffic
 ///     - When traffic has made it to this point, it's not whitelisted or holepunched. Since we're (at least currently) a default-drop firewall, drop it.
+///
+/// Packets matching rules with logging on, and packets dropped by default if the daemon asked for
+/// it, are sent to the packet log along the way.
 pub unsafe fn ghostwire_ingress_fallible(ctx: XdpContext) -> Result<u32, u32> {
-    // skip the ethernet header, that's not providing us with any value right now
-    // attempt to parse the ip header
-    let ip_header: *const Ipv4Hdr =
-        xdp_ptr_at_fallible(&ctx, EthHdr::LEN).map_err(|_| XDP_ABORTED)?;
-
-    // pull the source and destination IP addresses and the protocol
-    let src_ip = unsafe { (*ip_header).src_addr };
-    let dst_ip = unsafe { (*ip_header).dst_addr };
-    let protocol = unsafe { (*ip_header).proto };
-    let (src_port, dst_port) = match protocol {
-        Tcp => {
-            // parse the TCP header
-            let tcp_header: *const TcpHdr =
-                xdp_ptr_at_fallible::<TcpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)
-                    .map_err(|_| XDP_ABORTED)?;
+    // the firewall only filters IPv4, let everything else like ARP through
+    let eth_header: *const EthHdr = xdp_ptr_at_fallible(&ctx, 0).map_err(|_| XDP_ABORTED)?;
 
-            // get the source and destination ports
-            ((*tcp_header).source, (*tcp_header).dest)
-        }
-        Udp => {
-            // parse the UDP header
-            let udp_header: *const UdpHdr =
-                xdp_ptr_at_fallible::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)
-                    .map_err(|_| XDP_ABORTED)?;
-
-            // get the source and destination ports
-            ((*udp_header).source, (*udp_header).dest)
-        }
-        Icmp => (0, 0),
-        // for now, we're only supporting TCP and UDP
-        // let everything else in
+    match unsafe { (*eth_header).ether_type } {
+        EtherType::Ipv4 => {}
         _ => return Ok(XDP_PASS),
-    };
+    }
+
+    // parse the ip header and the ports, aborting on malformed traffic
+    let packet = parse_packet(&ctx, EthHdr::LEN).map_err(|_| XDP_ABORTED)?;
+    // if the packet is tunneled, parse the packet inside for the rules matching on it
+    let inner = parse_inner(&ctx, &packet);
+
+    // The bank is read once, so the whole packet is checked against the same ruleset.
+    let bank = ACTIVE_RULES.get(0).copied().unwrap_or(0);
 
     // the index of where we are in the map
     // we're using maps and not an array because arrays are immutable, meanwhile we can update maps
     // on the fly
     for index in 0..100 {
-        if let Some(rule) = RULES.get(&index) {
-            if src_ip >= rule.source_start_ip && src_ip <= rule.source_end_ip {
+        if let Some(rule) = rule_at(bank, index) {
+            // Pick the headers the rule matches on. Rules on inner headers skip untunneled traffic.
+            let matched = match (rule.match_on, &inner) {
+                (MATCH_ON_INNER, Some(inner)) => inner,
+                (MATCH_ON_INNER, None) => continue,
+                _ => &packet,
+            };
+
+            if matched.src_ip >= rule.source_start_ip && matched.src_ip <= rule.source_end_ip {
                 // Determine if should perform a protocol check.
                 if rule.protocol_number != 0 {
-                    if rule.protocol_number != protocol as u8 {
+                    if rule.protocol_number != matched.protocol {
                         continue;
                     }
 
                     // Compare port if relevant.
-                    if rule.port_number != 0 && rule.port_number != dst_port {
+                    if rule.port_number != 0 && rule.port_number != matched.dst_port {
                         continue;
                     }
                 }
@@ -116,7 +152,7 @@ pub unsafe fn ghostwire_ingress_fallible(ctx: XdpContext) -> Result<u32, u32> {
                 // Determine if we should perform ratelimiting.
                 if rule.ratelimiting != 0 {
                     // Create a ratelimit key. This is a combination of the source IP and the rule ID.
-                    let key = (src_ip + rule.id) as u64;
+                    let key = (matched.src_ip + rule.id) as u64;
                     // Fetch and increment ratelimit value for this key.
                     let current_value = match RATELIMITING.get_ptr_mut(&key) {
                         Some(value) => {
@@ -148,6 +184,14 @@ pub unsafe fn ghostwire_ingress_fallible(ctx: XdpContext) -> Result<u32, u32> {
                             }
                         }
                     } else {
+                        // The first drop of a source this minute is reported outside the packet
+                        // log's cap, as it's blocked until the ratelimits reset.
+                        if current_value == rule.ratelimiting as u64 + 1 {
+                            report_blocked(matched.event(rule.id, LOG_REASON_BLOCKED));
+                        } else if rule.log != 0 {
+                            log_packet(matched.event(rule.id, LOG_REASON_RATELIMITED));
+                        }
+
                         return Ok(XDP_DROP);
                     }
                 }
@@ -169,6 +213,10 @@ pub unsafe fn ghostwire_ingress_fallible(ctx: XdpContext) -> Result<u32, u32> {
                     }
                 }
 
+                if rule.log != 0 {
+                    log_packet(matched.event(rule.id, LOG_REASON_PASSED));
+                }
+
                 // Packet passed protocol conformity checks and ratelimit (if enabled)
                 return Ok(XDP_PASS);
             }
@@ -177,8 +225,15 @@ pub unsafe fn ghostwire_ingress_fallible(ctx: XdpContext) -> Result<u32, u32> {
         }
     }
 
-    // Create a key for the holepunched map, upgrading the type to u64 to avoid overflow
-    let key = src_ip as u64 + src_port as u64 + dst_ip as u64 + dst_port as u64;
+    // The connection the host would have made for this to be a reply, seen from the host.
+    let key = Connection {
+        local_ip: packet.dst_ip,
+        remote_ip: packet.src_ip,
+        local_port: packet.dst_port,
+        remote_port: packet.src_port,
+        protocol: packet.protocol,
+        _padding: [0; 3],
+    };
 
     match HOLEPUNCHED.get_ptr_mut(&key) {
         Some(last_time) => {
@@ -188,8 +243,139 @@ pub unsafe fn ghostwire_ingress_fallible(ctx: XdpContext) -> Result<u32, u32> {
             Ok(XDP_PASS)
         }
         None => {
+            if logs_default_drops() {
+                log_packet(packet.event(0, LOG_REASON_DEFAULT_DROP));
+            }
+
             // Drop the connection if no other case is met.
             Ok(XDP_DROP)
         }
     }
 }
+
+/// The fields of an IPv4 packet the rules match on, in big endian
+struct Packet {
+    src_ip: u32,
+    dst_ip: u32,
+    protocol: u8,
+    src_port: u16,
+    dst_port: u16,
+    /// The length of the IPv4 header, options included
+    header_len: usize,
+}
+
+/// The rule at an index of a bank of rules. Each bank is looked up in its own branch, as the verifier
+/// refuses a lookup that could be on either map.
+#[inline(always)]
+unsafe fn rule_at(bank: u32, index: u32) -> Option<&'static Rule> {
+    match bank {
+        1 => RULES_B.get(&index),
+        _ => RULES_A.get(&index),
+    }
+}
+
+impl Packet {
+    /// Describe the packet for the packet log.
+    fn event(&self, rule_id: u32, reason: u8) -> LogEvent {
+        LogEvent {
+            rule_id,
+            src_ip: self.src_ip,
+            dst_ip: self.dst_ip,
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            protocol: self.protocol,
+            reason,
+            _padding: [0; 2],
+            suppressed: 0,
+        }
+    }
+}
+
+/// Parse the IPv4 header at the offset and the ports after it. Protocols without ports, like ICMP,
+/// GRE and ESP, get ports of 0, so rules only match them on the protocol.
+unsafe fn parse_packet(ctx: &XdpContext, offset: usize) -> Result<Packet, ()> {
+    let ip_header: *const Ipv4Hdr = xdp_ptr_at_fallible(ctx, offset)?;
+    // The IHL counts 32-bit words, so options put the ports past the fixed 20 bytes.
+    let header_len = (*ip_header).ihl() as usize * 4;
+    if header_len < Ipv4Hdr::LEN {
+        return Err(());
+    }
+    let protocol = (*ip_header).proto;
+    let (src_port, dst_port) = match protocol {
+        Tcp => {
+            let tcp_header: *const TcpHdr = xdp_ptr_at_fallible(ctx, offset + header_len)?;
+
+            ((*tcp_header).source, (*tcp_header).dest)
+        }
+        Udp => {
+            let udp_header: *const UdpHdr = xdp_ptr_at_fallible(ctx, offset + header_len)?;
+
+            ((*udp_header).source, (*udp_header).dest)
+        }
+        // SCTP and DCCP headers start with the ports, like UDP
+        Sctp | Dccp => {
+            let ports_header: *const PortsHdr = xdp_ptr_at_fallible(ctx, offset + header_len)?;
+
+            ((*ports_header).source, (*ports_header).dest)
+        }
+        _ => (0, 0),
+    };
+
+    Ok(Packet {
+        src_ip: (*ip_header).src_addr,
+        dst_ip: (*ip_header).dst_addr,
+        protocol: protocol as u8,
+        src_port,
+        dst_port,
+        header_len,
+    })
+}
+
+/// Parse the IPv4 packet inside a GRE, IPIP or VXLAN tunnel, if the packet is tunneled and the
+/// packet inside is IPv4.
+unsafe fn parse_inner(ctx: &XdpContext, outer: &Packet) -> Option<Packet> {
+    let offset = EthHdr::LEN + outer.header_len;
+
+    match outer.protocol {
+        // IPIP, the inner IPv4 header follows the outer one
+        4 => parse_packet(ctx, offset).ok(),
+        // GRE, without the PPTP variant of version 1
+        47 => {
+            let gre_header: *const GreHdr = xdp_ptr_at_fallible(ctx, offset).ok()?;
+            let flags = u16::from_be((*gre_header).flags);
+
+            if flags & GRE_VERSION != 0 {
+                return None;
+            }
+
+            // skip the optional checksum, key and sequence number
+            let mut length = GreHdr::LEN;
+            for flag in [GRE_CHECKSUM, GRE_KEY, GRE_SEQUENCE] {
+                if flags & flag != 0 {
+                    length += 4;
+                }
+            }
+
+            match u16::from_be((*gre_header).protocol_type) {
+                ETH_P_IP => parse_packet(ctx, offset + length).ok(),
+                ETH_P_TEB => parse_ethernet(ctx, offset + length),
+                _ => None,
+            }
+        }
+        // VXLAN, an Ethernet frame in UDP
+        17 if u16::from_be(outer.dst_port) == VXLAN_PORT => {
+            parse_ethernet(ctx, offset + UdpHdr::LEN + VxlanHdr::LEN)
+        }
+        _ => None,
+    }
+}
+
+/// Parse the IPv4 packet of an Ethernet frame carried in a tunnel.
+unsafe fn parse_ethernet(ctx: &XdpContext, offset: usize) -> Option<Packet> {
+    let eth_header: *const EthHdr = xdp_ptr_at_fallible(ctx, offset).ok()?;
+
+    match (*eth_header).ether_type {
+        EtherType::Ipv4 => parse_packet(ctx, offset + EthHdr::LEN).ok(),
+        _ => None,
+    }
+}
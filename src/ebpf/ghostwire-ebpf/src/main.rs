@@ -12,8 +12,11 @@ use aya_ebpf::{
         xdp,
     },
     maps::{
+        Array,
         HashMap,
         LruHashMap,
+        PerCpuArray,
+        RingBuf,
     },
     programs::{
         TcContext,
@@ -21,6 +24,8 @@ use aya_ebpf::{
     },
 };
 use ghostwire_common::{
+    Connection,
+    LogSettings,
     Rule,
     RuleAnalytics,
 };
@@ -28,14 +33,27 @@ use ghostwire_common::{
 mod handlers;
 mod utils;
 
-use crate::handlers::{
-    egress::ghostwire_egress_fallible,
-    ingress::ghostwire_ingress_fallible,
+use crate::{
+    handlers::{
+        egress::ghostwire_egress_fallible,
+        ingress::ghostwire_ingress_fallible,
+    },
+    utils::packet_log::LogBudget,
 };
 
 #[map]
 /// The map which holds the firewall rules. Key is the index. Arrays in eBPF are immutable, so we're using a HashMap as a pseudo array
-pub static RULES: HashMap<u32, Rule> = HashMap::<u32, Rule>::with_max_entries(100, 0);
+/// There are two of them, the daemon writing a new ruleset to the one not in use before switching
+/// to it, so a packet never sees half of a change.
+pub static RULES_A: HashMap<u32, Rule> = HashMap::<u32, Rule>::with_max_entries(100, 0);
+
+#[map]
+/// The other bank of firewall rules, see RULES_A
+pub static RULES_B: HashMap<u32, Rule> = HashMap::<u32, Rule>::with_max_entries(100, 0);
+
+#[map]
+/// The bank of rules in use in key 0, 0 for RULES_A and 1 for RULES_B
+pub static ACTIVE_RULES: Array<u32> = Array::<u32>::with_max_entries(1, 0);
 
 #[map]
 /// The map which holds the ratelimiting metrics for ratelimiting-based rules. Key is a combination
@@ -49,10 +67,10 @@ pub static RULE_ANALYTICS: HashMap<u32, RuleAnalytics> =
     HashMap::<u32, RuleAnalytics>::with_max_entries(1024, 0);
 
 #[map]
-/// The holepunched connections (leaving the server). Key is source IP + source port + destination
-/// IP + destination port. Value is the time the last time there was traffic over this connection.
-pub static HOLEPUNCHED: LruHashMap<u64, u64> =
-    LruHashMap::<u64, u64>::with_max_entries(1_000_000, 0);
+/// The holepunched connections (leaving the server). Key is the connection, with its protocol. Value
+/// is the time the last time there was traffic over this connection.
+pub static HOLEPUNCHED: LruHashMap<Connection, u64> =
+    LruHashMap::<Connection, u64>::with_max_entries(1_000_000, 0);
 
 #[map]
 /// Whenever an action is completed IN XDP, like DROP, PASS, or ABORT, report that in this map. Designed
@@ -65,6 +83,23 @@ pub static XDP_ACTION_ANALYTICS: HashMap<u32, u128> =
 /// to be an overall statistic
 pub static TC_ACTION_ANALYTICS: HashMap<i32, u128> = HashMap::<i32, u128>::with_max_entries(100, 0);
 
+#[map]
+/// The packets logged by rules with logging on, and by default drops if enabled, read by the daemon
+pub static PACKET_LOG: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+#[map]
+/// The sources that went over a ratelimit, one event per source, rule and minute, read by the
+/// daemon for the hooks. Kept apart from the packet log so its cap never holds them back.
+pub static BLOCKED_SOURCES: RingBuf = RingBuf::with_byte_size(64 * 1024, 0);
+
+#[map]
+/// The packet log settings, written by the daemon to key 0. Nothing is logged until it's written.
+pub static LOG_SETTINGS: Array<LogSettings> = Array::<LogSettings>::with_max_entries(1, 0);
+
+#[map]
+/// The number of packet log events each CPU sent this second, in key 0
+pub static LOG_BUDGET: PerCpuArray<LogBudget> = PerCpuArray::<LogBudget>::with_max_entries(1, 0);
+
 #[xdp]
 /// The infallible XDP hook for all incoming traffic.
 pub fn ghostwire_xdp(ctx: XdpContext) -> u32 {
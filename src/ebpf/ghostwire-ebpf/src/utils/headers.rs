@@ -0,0 +1,23 @@
+/// The ports at the start of the SCTP and DCCP headers, which are the only fields of them the
+/// firewall reads. Like the other headers, the ports are in big endian.
+#[repr(C)]
+pub struct PortsHdr {
+    /// The source port
+    pub source: u16,
+    /// The destination port
+    pub dest: u16,
+}
+
+/// The fixed part of a GRE header. The flags say which optional fields follow it.
+#[repr(C)]
+pub struct GreHdr {
+    /// The flags and version, in big endian
+    pub flags: u16,
+    /// The EtherType of the payload, in big endian
+    pub protocol_type: u16,
+}
+
+impl GreHdr {
+    /// The length of the fixed part of the header
+    pub const LEN: usize = core::mem::size_of::<GreHdr>();
+}
@@ -1 +1,3 @@
+pub mod headers;
+pub mod packet_log;
 pub mod ptr_at;
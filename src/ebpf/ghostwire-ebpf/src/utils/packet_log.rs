@@ -0,0 +1,68 @@
+use aya_ebpf::helpers::bpf_ktime_get_ns;
+use ghostwire_common::LogEvent;
+
+use crate::{
+    BLOCKED_SOURCES,
+    LOG_BUDGET,
+    LOG_SETTINGS,
+    PACKET_LOG,
+};
+
+/// The events a CPU sent in the current second, to cap the rate of the packet log
+#[repr(C)]
+pub struct LogBudget {
+    /// The second since boot the count is for
+    pub second: u64,
+    /// The number of events sent in that second
+    pub sent: u32,
+    /// The number of events left out over the cap since the last one sent
+    pub suppressed: u32,
+}
+
+/// Send an event to the daemon's packet log, unless this CPU already sent its cap this second.
+/// Events left out are counted, and the count goes with the next event sent.
+pub unsafe fn log_packet(mut event: LogEvent) {
+    let Some(settings) = LOG_SETTINGS.get(0) else {
+        return;
+    };
+    let Some(budget) = LOG_BUDGET.get_ptr_mut(0) else {
+        return;
+    };
+
+    let second = bpf_ktime_get_ns() / 1_000_000_000;
+
+    if (*budget).second != second {
+        (*budget).second = second;
+        (*budget).sent = 0;
+    }
+
+    if (*budget).sent >= settings.max_per_second {
+        (*budget).suppressed += 1;
+
+        return;
+    }
+
+    event.suppressed = (*budget).suppressed;
+
+    // A full ring buffer means the daemon is behind, so count the event as left out.
+    match PACKET_LOG.output(&event, 0) {
+        Ok(_) => {
+            (*budget).sent += 1;
+            (*budget).suppressed = 0;
+        }
+        Err(_) => (*budget).suppressed += 1,
+    }
+}
+
+/// Report a source that went over a ratelimit. It's sent whatever the cap of the packet log, so the
+/// hooks hear of it during a flood, and only lost if the daemon is behind by thousands of them.
+pub unsafe fn report_blocked(event: LogEvent) {
+    let _ = BLOCKED_SOURCES.output(&event, 0);
+}
+
+/// Whether packets dropped by default are logged.
+pub fn logs_default_drops() -> bool {
+    LOG_SETTINGS
+        .get(0)
+        .is_some_and(|settings| settings.default_drop != 0)
+}
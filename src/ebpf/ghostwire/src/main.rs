@@ -2,45 +2,115 @@ use crate::utils::prometheus::handle_prom_listener;
 use lazy_static::lazy_static;
 use std::process::exit;
 use tokio::{
-    signal,
+    signal::{
+        self,
+        unix::SignalKind,
+    },
     sync::RwLock,
     task,
+    time::interval,
 };
 use tokio_schedule::{
     every,
     Job,
 };
 use utils::{
+    api::api_server,
+    bootloader::restore_state,
+    config::{
+        config,
+        load_config,
+    },
+    ebpf::{
+        adopt_pinned,
+        hand_off,
+    },
+    events::{
+        start_hooks,
+        watch_floods,
+    },
     map_management::manage_maps,
     prometheus::{
         create_prometheus_counters,
         prometheus_metrics,
     },
-    socket::socket_server,
+    reload::{
+        reload,
+        reload_on_hangup,
+        watch_rules_config,
+    },
+    socket::{
+        bind_socket,
+        socket_server,
+    },
     state::OverallState,
+    systemd::{
+        notify,
+        pet_watchdog,
+        watchdog_interval,
+    },
 };
 
 lazy_static! {
     /// State shared with the socket listener.
-    static ref OVERALL_STATE: RwLock<OverallState> = RwLock::new(OverallState { enabled: false, state: None, counters: create_prometheus_counters().expect("infallible prometheus counter generation failed") });
+    static ref OVERALL_STATE: RwLock<OverallState> = RwLock::new(OverallState { enabled: false, state: None, counters: create_prometheus_counters().expect("infallible prometheus counter generation failed"), pending_confirm: None, last_rollback: None });
 }
 
 mod utils;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    load_config()?;
+
     println!("Starting Ghostwire ...");
 
     // Set our own tracing subscriber.
     tracing_subscriber::fmt::Subscriber::builder()
         .pretty()
-        .with_max_level(tracing::Level::TRACE)
+        .with_max_level(config().log_level)
         .init();
 
-    // TODO: read the previous state on startup (@see utils/bootloader.rs)
+    // Start the hooks first, so they see the events of restoring the firewall.
+    start_hooks();
+
+    // Take over the firewall left attached by the last run, or apply the rules it saved, before
+    // accepting new ones.
+    let adopted = adopt_pinned().await.unwrap_or_else(|e| {
+        tracing::error!("Failed to adopt the pinned firewall: {:?}", e);
+        false
+    });
+
+    if !adopted {
+        if let Err(e) = restore_state().await {
+            tracing::error!("Failed to restore the saved rules: {:?}", e);
+        }
+    }
 
-    // Start the UNIX socket server.
-    task::spawn(socket_server());
+    // Apply the rules configuration, if there is one, so changes made while the daemon was stopped
+    // aren't missed.
+    if config().rules_config.is_some() {
+        reload("startup").await;
+    }
+
+    // Start the UNIX socket server. The socket is bound before systemd is told the daemon is
+    // ready, so the CLI can connect as soon as it is.
+    match bind_socket() {
+        Ok(listener) => {
+            task::spawn(async {
+                if let Err(e) = socket_server(listener).await {
+                    tracing::error!("The socket server stopped: {:?}", e);
+                }
+            });
+        }
+        Err(e) => tracing::error!("The socket server couldn't start: {:?}", e),
+    }
+
+    // Start the HTTP API, if it's configured.
+    task::spawn(async {
+        if let Err(e) = api_server().await {
+            tracing::error!("The HTTP API stopped: {:?}", e);
+        }
+    });
 
     // Start the Prometheus metrics task.
     task::spawn(every(10).seconds().perform(|| async {
@@ -52,18 +122,53 @@ async fn main() -> Result<(), anyhow::Error> {
         manage_maps().await;
     }));
 
+    // Watch for floods of dropped packets, for the hooks.
+    task::spawn(watch_floods());
+
+    // Reload the rules configuration on SIGHUP, and when it changes if it's watched.
+    task::spawn(async {
+        if let Err(e) = reload_on_hangup().await {
+            tracing::error!("Stopped reloading on SIGHUP: {:?}", e);
+        }
+    });
+    task::spawn(watch_rules_config());
+
     // Start the Prometheus HTTP listener.
     task::spawn(handle_prom_listener());
 
-    // Allow the user to unload the eBPF program and the socket server with Ctrl-C.
+    // Tell systemd the daemon is up when the rules are applied, and keep its watchdog fed.
+    // The interval can be under a second, so it isn't rounded to the seconds of a schedule.
+    if let Some(period) = watchdog_interval() {
+        task::spawn(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+                pet_watchdog().await;
+            }
+        });
+    }
+
+    notify(&format!(
+        "READY=1\nSTATUS={}",
+        OVERALL_STATE.read().await.fmt().await
+    ));
+
+    // Stop with Ctrl-C, or when systemd stops the daemon with SIGTERM.
     tracing::info!("Waiting for Ctrl-C...");
 
-    signal::ctrl_c().await?;
+    let mut terminate = signal::unix::signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
 
     tracing::info!("Exiting...");
 
+    notify("STOPPING=1");
+    hand_off().await;
+
     // Emperically, the process doesn't close when `main` returns Ok(()), so we exit manually.
     exit(0);
-
-    Ok(())
 }
@@ -0,0 +1,186 @@
+use super::config::config;
+use anyhow::Context;
+use ghostwire_types::ClientReqType;
+use std::{
+    ffi::CString,
+    fmt,
+    fs,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+use tokio::net::unix::UCred;
+
+/// What a client connected to the socket is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// The client can change the firewall
+    Admin,
+    /// The client can only look at the firewall
+    ReadOnly,
+    /// The client can't do anything
+    Denied,
+}
+
+/// The client on the other end of a socket or HTTP API connection
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+    /// The user ID of the process, if it connected to the socket
+    pub uid: Option<u32>,
+    /// The process ID, if the kernel reported it
+    pub pid: Option<i32>,
+    /// The address of the client, if it used the HTTP API
+    pub address: Option<SocketAddr>,
+    /// What the client is allowed to do
+    pub access: Access,
+}
+
+impl Peer {
+    /// Identify the peer from its credentials, applying the access policy: root and the admin group
+    /// are admins, the read-only group can look, and everyone else is denied.
+    pub fn from_cred(cred: &UCred) -> Self {
+        let groups = peer_groups(cred);
+        let in_group = |name: &Option<String>| {
+            name.as_deref()
+                .and_then(group_id)
+                .is_some_and(|gid| groups.contains(&gid))
+        };
+
+        let access = if cred.uid() == 0 || in_group(&config().admin_group) {
+            Access::Admin
+        } else if in_group(&config().read_only_group) {
+            Access::ReadOnly
+        } else {
+            Access::Denied
+        };
+
+        Self {
+            uid: Some(cred.uid()),
+            pid: cred.pid(),
+            address: None,
+            access,
+        }
+    }
+
+    /// Identify a client of the HTTP API. Its token was checked, so it's an admin.
+    pub fn api(address: SocketAddr) -> Self {
+        Self {
+            uid: None,
+            pid: None,
+            address: Some(address),
+            access: Access::Admin,
+        }
+    }
+
+    /// Check that the peer is allowed to make a request.
+    pub fn authorize(&self, req_type: &ClientReqType) -> anyhow::Result<()> {
+        match (self.access, req_type) {
+            (Access::Admin, _)
+            | (Access::ReadOnly, ClientReqType::STATUS)
+            | (Access::ReadOnly, ClientReqType::LIST) => Ok(()),
+            (Access::ReadOnly, _) => anyhow::bail!(
+                "permission denied: {} can only read the firewall status and rules",
+                self
+            ),
+            (Access::Denied, _) => {
+                anyhow::bail!("permission denied: {} can't manage the firewall", self)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.uid, self.address) {
+            (Some(uid), _) => write!(f, "user {}", uid),
+            (None, Some(address)) => write!(f, "API client {}", address),
+            (None, None) => write!(f, "an unknown client"),
+        }
+    }
+}
+
+/// Get every group of the peer: its primary group from the credentials, and its supplementary
+/// groups from procfs.
+fn peer_groups(cred: &UCred) -> Vec<u32> {
+    let mut groups = vec![cred.gid()];
+
+    if let Some(status) = cred
+        .pid()
+        .and_then(|pid| fs::read_to_string(format!("/proc/{}/status", pid)).ok())
+    {
+        groups.extend(
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Groups:"))
+                .unwrap_or_default()
+                .split_whitespace()
+                .filter_map(|gid| gid.parse::<u32>().ok()),
+        );
+    }
+
+    groups
+}
+
+/// Look up the ID of a group by its name.
+fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    (ret == 0 && !result.is_null()).then_some(group.gr_gid)
+}
+
+/// Prepare the directory of the socket, creating it so only root can write to it if it doesn't
+/// exist.
+pub fn prepare_socket_dir(socket: &Path) -> anyhow::Result<()> {
+    let Some(dir) = socket.parent() else {
+        return Ok(());
+    };
+
+    if !dir.exists() {
+        fs::create_dir_all(dir).context(format!("couldn't create {}", dir.display()))?;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o755))?;
+    } else if fs::metadata(dir)?.permissions().mode() & 0o002 != 0 {
+        tracing::warn!(
+            "The socket is in {}, which anyone can write to. Anyone could replace the socket, consider moving it",
+            dir.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Restrict who can connect to the socket. Root can always connect. If only an admin group is
+/// configured, the socket belongs to it. With a read-only group too, file permissions can't
+/// express both groups, so anyone can connect and the peer credentials decide.
+pub fn restrict_socket(socket: &Path) -> anyhow::Result<()> {
+    let admin_group = config().admin_group.as_deref();
+    let read_only_group = config().read_only_group.as_deref();
+
+    let mode = match (admin_group, read_only_group) {
+        (_, Some(_)) => 0o666,
+        (Some(group), None) => {
+            let gid = group_id(group).context(format!("admin group {} doesn't exist", group))?;
+
+            std::os::unix::fs::chown(socket, None, Some(gid))?;
+
+            0o660
+        }
+        (None, None) => 0o600,
+    };
+
+    fs::set_permissions(socket, fs::Permissions::from_mode(mode))?;
+
+    Ok(())
+}
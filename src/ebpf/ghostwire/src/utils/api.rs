@@ -0,0 +1,989 @@
+use super::{
+    access::Peer,
+    audit::{
+        self,
+        snapshot,
+    },
+    config::config,
+    map_management::unblock,
+    prometheus::{
+        tc_action_to_string,
+        xdp_action_to_string,
+    },
+    socket::handle_server_request,
+};
+use crate::OVERALL_STATE;
+use anyhow::Context;
+use bytes::Bytes;
+use ghostwire_types::{
+    api::{
+        ApiBlockedSource,
+        ApiBlocklist,
+        ApiError,
+        ApiMessage,
+        ApiRuleChange,
+        ApiRuleStats,
+        ApiRules,
+        ApiRulesUpdate,
+        ApiStats,
+        ApiStatus,
+    },
+    ClientMessage,
+    ClientReqType,
+    Rule,
+    MAX_MESSAGE_SIZE,
+};
+use http_body_util::{
+    BodyExt,
+    Full,
+    Limited,
+};
+use hyper::{
+    body::{
+        Body,
+        Incoming,
+    },
+    header,
+    server::conn::http1,
+    service::service_fn,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use hyper_util::rt::{
+    TokioIo,
+    TokioTimer,
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fs,
+    net::{
+        Ipv4Addr,
+        SocketAddr,
+    },
+    os::unix::fs::PermissionsExt,
+    sync::Arc,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::{
+    net::TcpListener,
+    time::Instant,
+};
+use utoipa::{
+    openapi::{
+        security::{
+            HttpAuthScheme,
+            HttpBuilder,
+            SecurityScheme,
+        },
+        Components,
+    },
+    Modify,
+    OpenApi,
+};
+
+type HttpResponse = Response<Full<Bytes>>;
+
+/// A request body the endpoints can read, the body of a connection or one built in the tests
+trait RequestBody: Body<Error: Into<Box<dyn std::error::Error + Send + Sync>>> {}
+
+impl<B: Body<Error: Into<Box<dyn std::error::Error + Send + Sync>>>> RequestBody for B {}
+
+/// The OpenAPI description of the HTTP API, served at /v1/openapi.json
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Ghostwire",
+        description = "Manage the Ghostwire firewall. Every endpoint but this description takes the API token as a bearer token."
+    ),
+    paths(
+        get_status,
+        get_rules,
+        get_rule,
+        put_rules,
+        post_rule,
+        put_rule,
+        delete_rule,
+        confirm,
+        disable,
+        get_stats,
+        get_blocklist,
+        delete_blocklist_entry
+    ),
+    modifiers(&TokenAuth),
+    security(("token" = []))
+)]
+struct ApiDoc;
+
+/// Describe the bearer token authentication in the OpenAPI document
+struct TokenAuth;
+
+impl Modify for TokenAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Components::new)
+            .add_security_scheme(
+                "token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+    }
+}
+
+/// Serve the HTTP API, if it's configured. Changes go through the same path as socket requests, so
+/// they're locked, checked for lockouts and audited the same way.
+pub async fn api_server() -> anyhow::Result<()> {
+    let Some(address) = config().api_address else {
+        return Ok(());
+    };
+
+    let token = Arc::new(read_token()?);
+
+    let listener = TcpListener::bind(address)
+        .await
+        .context(format!("could not listen on {}", address))?;
+
+    tracing::info!("The HTTP API is listening on {}", address);
+
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                tracing::error!("Failed to accept API connection: {:?}", err);
+                continue;
+            }
+        };
+
+        let io = TokioIo::new(stream);
+        let token = token.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(Duration::from_secs(config().client_timeout))
+                .serve_connection(
+                    io,
+                    service_fn(|req: Request<Incoming>| route(req, client, token.clone())),
+                )
+                .await
+            {
+                tracing::warn!("error serving API connection: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Read the token API clients authenticate with.
+fn read_token() -> anyhow::Result<String> {
+    let path = &config().api_token_file;
+
+    let token = fs::read_to_string(path)
+        .context(format!(
+            "couldn't read the API token from {}",
+            path.display()
+        ))?
+        .trim()
+        .to_string();
+
+    if token.is_empty() {
+        anyhow::bail!("the API token in {} is empty", path.display());
+    }
+
+    if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+        tracing::warn!(
+            "The API token in {} can be read by other users, consider restricting it to root",
+            path.display()
+        );
+    }
+
+    Ok(token)
+}
+
+/// Authenticate a request and dispatch it to its endpoint.
+async fn route(
+    req: Request<impl RequestBody>,
+    client: SocketAddr,
+    token: Arc<String>,
+) -> Result<HttpResponse, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    if method == Method::GET && path == "/v1/openapi.json" {
+        return Ok(json(StatusCode::OK, &ApiDoc::openapi()));
+    }
+
+    if !authenticated(&req, &token) {
+        let mut response = error(StatusCode::UNAUTHORIZED, "missing or invalid API token");
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+
+        return Ok(response);
+    }
+
+    let peer = Peer::api(client);
+
+    let response = match (method, path.as_str()) {
+        (Method::GET, "/v1/status") => get_status().await,
+        (Method::GET, "/v1/rules") => get_rules().await,
+        (Method::PUT, "/v1/rules") => put_rules(req, &peer).await,
+        (Method::POST, "/v1/rules") => post_rule(req, &peer).await,
+        (method, path) if path.starts_with("/v1/rules/") => {
+            let id = path["/v1/rules/".len()..].to_string();
+
+            match method {
+                Method::GET => get_rule(&id).await,
+                Method::PUT => put_rule(req, &id, &peer).await,
+                Method::DELETE => delete_rule(&req, &id, &peer).await,
+                _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            }
+        }
+        (Method::POST, "/v1/confirm") => confirm(&peer).await,
+        (Method::POST, "/v1/disable") => disable(&peer).await,
+        (Method::GET, "/v1/stats") => get_stats().await,
+        (Method::GET, "/v1/blocklist") => get_blocklist().await,
+        (Method::DELETE, path) if path.starts_with("/v1/blocklist/") => {
+            delete_blocklist_entry(&path["/v1/blocklist/".len()..], &peer).await
+        }
+        _ => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    };
+
+    Ok(response)
+}
+
+/// Check the bearer token of a request, in constant time so the token can't be guessed by timing.
+fn authenticated(req: &Request<impl RequestBody>, token: &str) -> bool {
+    let Some(given) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/status",
+    responses((status = 200, description = "The status of the firewall", body = ApiStatus))
+)]
+/// Get the status of the firewall
+async fn get_status() -> HttpResponse {
+    let overall_state = OVERALL_STATE.read().await;
+
+    let rule_count = match &overall_state.state {
+        Some(state) => state.rules.read().await.len(),
+        None => 0,
+    };
+
+    json(
+        StatusCode::OK,
+        &ApiStatus {
+            enabled: overall_state.enabled,
+            interface: overall_state
+                .state
+                .as_ref()
+                .map(|state| state.interface.clone()),
+            rule_count,
+            rollback_in: overall_state.pending_confirm.as_ref().map(|pending| {
+                pending
+                    .deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs()
+            }),
+            last_rollback: overall_state.last_rollback.clone(),
+        },
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/rules",
+    responses((status = 200, description = "The applied ruleset", body = ApiRules))
+)]
+/// Get the applied rules
+async fn get_rules() -> HttpResponse {
+    let rules = match snapshot().await {
+        Some((rules, interface)) => ApiRules {
+            interface: Some(interface),
+            rules,
+        },
+        None => ApiRules {
+            interface: None,
+            rules: vec![],
+        },
+    };
+
+    json(StatusCode::OK, &rules)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/rules/{id}",
+    params(("id" = u32, Path, description = "The ID of the rule")),
+    responses(
+        (status = 200, description = "The rule", body = Rule),
+        (status = 404, description = "No applied rule has the ID", body = ApiError)
+    )
+)]
+/// Get an applied rule by its ID
+async fn get_rule(id: &str) -> HttpResponse {
+    let id = match parse_id(id) {
+        Ok(id) => id,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("{:#}", e)),
+    };
+
+    let rule = snapshot()
+        .await
+        .and_then(|(rules, _)| rules.into_iter().find(|rule| rule.id == id));
+
+    match rule {
+        Some(rule) => json(StatusCode::OK, &rule),
+        None => error(
+            StatusCode::NOT_FOUND,
+            &format!("no applied rule has the ID {}", id),
+        ),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/rules",
+    request_body = ApiRulesUpdate,
+    responses(
+        (status = 200, description = "The rules were applied", body = ApiMessage),
+        (status = 400, description = "The body isn't a valid ruleset", body = ApiError),
+        (status = 409, description = "The firewall refused the rules", body = ApiError)
+    )
+)]
+/// Replace the applied rules
+async fn put_rules(req: Request<impl RequestBody>, peer: &Peer) -> HttpResponse {
+    let update: ApiRulesUpdate = match read_json(req).await {
+        Ok(update) => update,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("{:#}", e)),
+    };
+
+    change(
+        peer,
+        ClientMessage {
+            rules: Some(update.rules),
+            interface: Some(update.interface),
+            confirm_within: update.confirm_within,
+            force: update.force,
+            persistent: update.persistent,
+            ..ClientMessage::new(ClientReqType::RULES)
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/rules",
+    request_body = ApiRuleChange,
+    responses(
+        (status = 200, description = "The rule was added after the applied ones", body = ApiMessage),
+        (status = 400, description = "The body isn't a valid rule", body = ApiError),
+        (status = 409, description = "The firewall refused the rule", body = ApiError)
+    )
+)]
+/// Add a rule after the applied ones, without touching them
+async fn post_rule(req: Request<impl RequestBody>, peer: &Peer) -> HttpResponse {
+    let body: ApiRuleChange = match read_json(req).await {
+        Ok(body) => body,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("{:#}", e)),
+    };
+
+    change(
+        peer,
+        ClientMessage {
+            rule: Some(body.rule),
+            force: body.force,
+            persistent: body.persistent,
+            ..ClientMessage::new(ClientReqType::ADD)
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/rules/{id}",
+    params(("id" = u32, Path, description = "The ID of the rule")),
+    request_body = ApiRuleChange,
+    responses(
+        (status = 200, description = "The rule was replaced", body = ApiMessage),
+        (status = 400, description = "The body isn't a valid rule", body = ApiError),
+        (status = 409, description = "The firewall refused the change", body = ApiError)
+    )
+)]
+/// Replace an applied rule in place, keeping its ID and position
+async fn put_rule(req: Request<impl RequestBody>, id: &str, peer: &Peer) -> HttpResponse {
+    let id = match parse_id(id) {
+        Ok(id) => id,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("{:#}", e)),
+    };
+
+    let body: ApiRuleChange = match read_json(req).await {
+        Ok(body) => body,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("{:#}", e)),
+    };
+
+    change(
+        peer,
+        ClientMessage {
+            rule: Some(body.rule),
+            rule_id: Some(id),
+            force: body.force,
+            persistent: body.persistent,
+            ..ClientMessage::new(ClientReqType::UPDATE)
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/rules/{id}",
+    params(
+        ("id" = u32, Path, description = "The ID of the rule"),
+        ("force" = Option<bool>, Query, description = "Delete the rule even if it'd drop the established management sessions"),
+        ("persistent" = Option<bool>, Query, description = "Whether the removal is saved for when the daemon restarts. Defaults to true.")
+    ),
+    responses(
+        (status = 200, description = "The rule was removed", body = ApiMessage),
+        (status = 409, description = "The firewall refused the change", body = ApiError)
+    )
+)]
+/// Remove an applied rule, without touching the others
+async fn delete_rule(req: &Request<impl RequestBody>, id: &str, peer: &Peer) -> HttpResponse {
+    let id = match parse_id(id) {
+        Ok(id) => id,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("{:#}", e)),
+    };
+
+    let query = |name: &str| {
+        req.uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+            .map(|value| value == "true")
+    };
+
+    change(
+        peer,
+        ClientMessage {
+            rule_id: Some(id),
+            force: query("force").unwrap_or(false),
+            persistent: query("persistent"),
+            ..ClientMessage::new(ClientReqType::DELETE)
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/confirm",
+    responses(
+        (status = 200, description = "The rules were confirmed", body = ApiMessage),
+        (status = 409, description = "No rules are waiting for confirmation", body = ApiError)
+    )
+)]
+/// Confirm the rules applied with confirm_within, cancelling the rollback
+async fn confirm(peer: &Peer) -> HttpResponse {
+    change(peer, ClientMessage::new(ClientReqType::CONFIRM)).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/disable",
+    responses(
+        (status = 200, description = "The firewall was disabled", body = ApiMessage),
+        (status = 409, description = "The firewall is already disabled", body = ApiError)
+    )
+)]
+/// Disable the firewall
+async fn disable(peer: &Peer) -> HttpResponse {
+    change(peer, ClientMessage::new(ClientReqType::DISABLE)).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/stats",
+    responses((status = 200, description = "The traffic counters of the firewall", body = ApiStats))
+)]
+/// Get the traffic counters of the firewall
+async fn get_stats() -> HttpResponse {
+    let mut stats = ApiStats {
+        rules: BTreeMap::new(),
+        xdp_actions: BTreeMap::new(),
+        tc_actions: BTreeMap::new(),
+    };
+
+    if let Some(state) = &OVERALL_STATE.read().await.state {
+        let names = state.rule_names().await;
+
+        for (id, analytics) in state.rule_analytic_map.read().await.iter().flatten() {
+            stats.rules.insert(
+                id,
+                ApiRuleStats {
+                    name: names.get(&id).cloned(),
+                    evaluated: analytics.evaluated as u64,
+                    passed: analytics.passed as u64,
+                },
+            );
+        }
+
+        for (action, count) in state.xdp_analytic_map.iter().flatten() {
+            stats
+                .xdp_actions
+                .insert(xdp_action_to_string(action).to_string(), count as u64);
+        }
+
+        for (action, count) in state.tc_analytic_map.iter().flatten() {
+            stats
+                .tc_actions
+                .insert(tc_action_to_string(action).to_string(), count as u64);
+        }
+    }
+
+    json(StatusCode::OK, &stats)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/blocklist",
+    responses((status = 200, description = "The sources blocked for going over a ratelimit", body = ApiBlocklist))
+)]
+/// Get the sources blocked for going over the ratelimit of a rule, until the ratelimits reset at the
+/// end of the minute
+async fn get_blocklist() -> HttpResponse {
+    let mut blocklist = ApiBlocklist { sources: vec![] };
+
+    if let Some(state) = &OVERALL_STATE.read().await.state {
+        let names = state.rule_names().await;
+
+        blocklist.sources = state
+            .blocked_sources
+            .read()
+            .await
+            .iter()
+            .map(|blocked| ApiBlockedSource {
+                source_ip: blocked.source_ip.to_string(),
+                rule_id: blocked.rule_id,
+                rule_name: names.get(&blocked.rule_id).cloned(),
+                blocked_at: blocked.blocked_at,
+            })
+            .collect();
+    }
+
+    json(StatusCode::OK, &blocklist)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/blocklist/{ip}",
+    params(("ip" = String, Path, description = "The IPv4 address of the source")),
+    responses(
+        (status = 200, description = "The source was unblocked", body = ApiMessage),
+        (status = 400, description = "The address isn't an IPv4 address", body = ApiError),
+        (status = 404, description = "The source isn't blocked", body = ApiError)
+    )
+)]
+/// Unblock a source before the ratelimits reset, starting its counts over
+async fn delete_blocklist_entry(ip: &str, peer: &Peer) -> HttpResponse {
+    let source_ip: Ipv4Addr = match ip.parse() {
+        Ok(source_ip) => source_ip,
+        Err(_) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                &format!("{} isn't an IPv4 address", ip),
+            )
+        }
+    };
+
+    let state = OVERALL_STATE.read().await.state.clone();
+    let rule_ids = match &state {
+        Some(state) => unblock(state, source_ip).await,
+        None => vec![],
+    };
+
+    if rule_ids.is_empty() {
+        return error(
+            StatusCode::NOT_FOUND,
+            &format!("{} isn't blocked", source_ip),
+        );
+    }
+
+    let message = format!(
+        "Unblocked {} for rules {}",
+        source_ip,
+        rule_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let current = snapshot().await;
+
+    // The ruleset doesn't change, but the unblocking is recorded like the changes that do.
+    audit::record(
+        Some(peer),
+        request_id(),
+        "UNBLOCK",
+        &current,
+        &current,
+        &Ok(message.clone()),
+    );
+
+    json(StatusCode::OK, &ApiMessage { message })
+}
+
+/// Make a change to the firewall the way a socket request would.
+async fn change(peer: &Peer, mut message: ClientMessage) -> HttpResponse {
+    message.request_id = request_id();
+
+    let response = handle_server_request(message, peer).await;
+
+    match response.request_success {
+        true => json(
+            StatusCode::OK,
+            &ApiMessage {
+                message: response.message,
+            },
+        ),
+        false => error(StatusCode::CONFLICT, &response.message),
+    }
+}
+
+/// Identify a request made over the API, as API clients don't send an ID.
+fn request_id() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Parse the ID of a rule from the path.
+fn parse_id(id: &str) -> anyhow::Result<u32> {
+    id.parse().context("the rule ID must be a number")
+}
+
+/// Read and parse the JSON body of a request.
+async fn read_json<T: DeserializeOwned>(req: Request<impl RequestBody>) -> anyhow::Result<T> {
+    let body = Limited::new(req.into_body(), MAX_MESSAGE_SIZE as usize)
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!("couldn't read the body: {}", e))?
+        .to_bytes();
+
+    serde_json::from_slice(&body).context("couldn't parse the body")
+}
+
+/// Build a JSON response.
+fn json<T: Serialize>(status: StatusCode, body: &T) -> HttpResponse {
+    match serde_json::to_vec(body) {
+        Ok(body) => {
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+
+            *response.status_mut() = status;
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+
+            response
+        }
+        Err(err) => {
+            tracing::warn!("Failed to serialize an API response: {err:?}");
+
+            let mut response =
+                Response::new(Full::new(Bytes::from("Failed to serialize the response")));
+
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+
+            response
+        }
+    }
+}
+
+/// Build an error response.
+fn error(status: StatusCode, message: &str) -> HttpResponse {
+    json(
+        status,
+        &ApiError {
+            error: message.to_string(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::config::test_config;
+    use std::net::Ipv4Addr;
+
+    /// The token the tests authenticate with
+    const TOKEN: &str = "test-token";
+
+    /// Send a request to the API with a token, returning the status and the JSON body. The
+    /// firewall is never enabled in the tests, so no request reaches the XDP program.
+    async fn request(
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        test_config();
+
+        let mut req = Request::builder().method(method).uri(path);
+
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, token);
+        }
+
+        let response = route(
+            req.body(Full::new(Bytes::from(body.to_string()))).unwrap(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)),
+            Arc::new(TOKEN.to_string()),
+        )
+        .await
+        .unwrap();
+
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Send an authenticated request.
+    async fn authed(method: Method, path: &str, body: &str) -> (StatusCode, serde_json::Value) {
+        request(method, path, Some(&format!("Bearer {}", TOKEN)), body).await
+    }
+
+    #[tokio::test]
+    async fn openapi_needs_no_token() {
+        let (status, body) = request(Method::GET, "/v1/openapi.json", None, "").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["paths"]["/v1/rules/{id}"]["delete"].is_object());
+        assert!(body["paths"]["/v1/blocklist"]["get"].is_object());
+        assert!(body["components"]["securitySchemes"]["token"].is_object());
+    }
+
+    #[tokio::test]
+    async fn missing_or_wrong_token_is_refused() {
+        for token in [
+            None,
+            Some("Bearer wrong-token"),
+            Some("Bearer test-toke"),
+            Some("Basic test-token"),
+            Some("test-token"),
+        ] {
+            let (status, body) = request(Method::GET, "/v1/status", token, "").await;
+
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", token);
+            assert_eq!(body["error"], "missing or invalid API token");
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_request_asks_for_bearer() {
+        test_config();
+
+        let response = route(
+            Request::get("/v1/status")
+                .body(Full::new(Bytes::new()))
+                .unwrap(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)),
+            Arc::new(TOKEN.to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[tokio::test]
+    async fn token_is_checked_before_routing() {
+        let (status, _) = request(Method::GET, "/v1/nothing", None, "").await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unknown_endpoints_and_methods() {
+        let (status, _) = authed(Method::GET, "/v1/nothing", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = authed(Method::DELETE, "/v1/rules", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = authed(Method::PATCH, "/v1/rules/1", "").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn status_of_disabled_firewall() {
+        let (status, body) = authed(Method::GET, "/v1/status", "").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["enabled"], false);
+        assert_eq!(body["rule_count"], 0);
+    }
+
+    #[tokio::test]
+    async fn rules_of_disabled_firewall() {
+        let (status, body) = authed(Method::GET, "/v1/rules", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["rules"], serde_json::json!([]));
+
+        let (status, _) = authed(Method::GET, "/v1/rules/1", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = authed(Method::GET, "/v1/rules/one", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["error"],
+            "the rule ID must be a number: invalid digit found in string"
+        );
+    }
+
+    #[tokio::test]
+    async fn put_rules_checks_the_body() {
+        let (status, body) = authed(Method::PUT, "/v1/rules", "{\"rules\": []}").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("couldn't parse the body"));
+    }
+
+    #[tokio::test]
+    async fn put_rules_refused_by_the_firewall() {
+        // The reserved ID is refused before anything is loaded.
+        let rule = serde_json::json!({
+            "id": u32::MAX,
+            "source_start_ip": 0,
+            "source_end_ip": u32::MAX,
+            "destination_start_ip": 0,
+            "destination_end_ip": u32::MAX,
+            "protocol_number": 6,
+            "port_number": 22u16.to_be(),
+            "ratelimiting": 0
+        });
+        let (status, body) = authed(
+            Method::PUT,
+            "/v1/rules",
+            &serde_json::json!({"interface": "lo", "rules": [rule]}).to_string(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("is reserved for the always-allow list"));
+
+        // The refused change is audited like a socket request.
+        let audit = fs::read_to_string(&config().audit_log).unwrap();
+        assert!(audit.lines().any(|line| {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+
+            record["action"] == "RULES"
+                && record["success"] == false
+                && record["address"] == "127.0.0.1:40000"
+        }));
+    }
+
+    #[tokio::test]
+    async fn put_rules_refuses_a_zero_confirmation_timeout() {
+        let (status, body) = authed(
+            Method::PUT,
+            "/v1/rules",
+            &serde_json::json!({"interface": "lo", "rules": [], "confirm_within": 0}).to_string(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("must be at least a second"));
+    }
+
+    #[tokio::test]
+    async fn single_rule_changes_need_applied_rules() {
+        let rule = serde_json::json!({"rule": {
+            "id": 0,
+            "source_start_ip": 0,
+            "source_end_ip": u32::MAX,
+            "destination_start_ip": 0,
+            "destination_end_ip": u32::MAX,
+            "protocol_number": 6,
+            "port_number": 22u16.to_be(),
+            "ratelimiting": 0
+        }})
+        .to_string();
+
+        let (status, body) = authed(Method::PUT, "/v1/rules/3", &rule).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["error"].is_string());
+
+        let (status, _) = authed(Method::PUT, "/v1/rules/3", "not json").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = authed(Method::PUT, "/v1/rules/three", &rule).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = authed(Method::DELETE, "/v1/rules/3?force=true", "").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["error"].is_string());
+
+        let (status, _) = authed(Method::DELETE, "/v1/rules/three", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn blocklist_of_disabled_firewall() {
+        let (status, body) = authed(Method::GET, "/v1/blocklist", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["sources"], serde_json::json!([]));
+
+        let (status, body) = authed(Method::DELETE, "/v1/blocklist/192.0.2.1", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "192.0.2.1 isn't blocked");
+
+        let (status, body) = authed(Method::DELETE, "/v1/blocklist/example.com", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "example.com isn't an IPv4 address");
+    }
+
+    #[tokio::test]
+    async fn confirm_without_pending_load() {
+        let (status, body) = authed(Method::POST, "/v1/confirm", "").await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["error"].is_string());
+    }
+}
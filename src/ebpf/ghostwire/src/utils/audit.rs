@@ -0,0 +1,361 @@
+use super::{
+    access::Peer,
+    config::config,
+};
+use crate::OVERALL_STATE;
+use anyhow::Context;
+use ghostwire_types::{
+    AuditQuery,
+    AuditRecord,
+    Rule,
+};
+use std::{
+    fs,
+    io::{
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    os::unix::fs::OpenOptionsExt,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+/// The size of the chunks the audit log is read in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The rules and interface applied at some point, or None if the firewall was disabled
+pub type Snapshot = Option<(Vec<Rule>, String)>;
+
+/// Take a snapshot of the applied ruleset, to compare against after a change.
+pub async fn snapshot() -> Snapshot {
+    let state = OVERALL_STATE.read().await.state.clone()?;
+    let rules = state.rules.read().await.clone();
+
+    Some((rules, state.interface.clone()))
+}
+
+/// Record a change to the firewall in the audit log and the tracing output. The peer is None if
+/// the daemon made the change on its own. Failing to write the record doesn't fail the change,
+/// which has already happened.
+pub fn record(
+    peer: Option<&Peer>,
+    request_id: u64,
+    action: &str,
+    before: &Snapshot,
+    after: &Snapshot,
+    result: &anyhow::Result<String>,
+) {
+    let record = AuditRecord {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+        uid: peer.and_then(|peer| peer.uid),
+        address: peer
+            .and_then(|peer| peer.address)
+            .map(|address| address.to_string()),
+        pid: peer.and_then(|peer| peer.pid),
+        request_id,
+        action: action.to_string(),
+        changes: summarize(before, after),
+        success: result.is_ok(),
+        message: match result {
+            Ok(message) => message.clone(),
+            Err(e) => format!("{}", e),
+        },
+    };
+
+    tracing::info!(
+        "Audit: {} by {} (request {}) {}: {}. {}",
+        record.action,
+        match peer {
+            Some(peer) => peer.to_string(),
+            None => "the daemon".to_string(),
+        },
+        record.request_id,
+        if record.success {
+            "succeeded"
+        } else {
+            "failed"
+        },
+        record.changes,
+        record.message
+    );
+
+    if let Err(e) = append(&record) {
+        tracing::error!("Failed to write to the audit log: {:?}", e);
+    }
+}
+
+/// Append a record to the audit log as a line of JSON.
+fn append(record: &AuditRecord) -> anyhow::Result<()> {
+    let path = &config().audit_log;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    // The whole line is written at once, so records are never interleaved.
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .context(format!("couldn't open {}", path.display()))?
+        .write_all(&line)?;
+
+    Ok(())
+}
+
+/// Read a page of the audit log, newest first, along with the total number of records. The log is
+/// read from the end and only the records of the page are parsed, so the newest pages stay cheap
+/// however long the log grows. Lines that aren't records still take their place in the count.
+pub fn read_page(query: AuditQuery) -> anyhow::Result<(Vec<AuditRecord>, usize)> {
+    let path = &config().audit_log;
+    let context = || format!("couldn't read {}", path.display());
+
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e).with_context(context),
+    };
+
+    let total = count_lines(&mut file).with_context(context)?;
+    let mut page = vec![];
+    let mut position = 0;
+
+    read_lines_backwards(&mut file, CHUNK_SIZE, |line| {
+        if position >= query.skip + query.limit {
+            return false;
+        }
+
+        if position >= query.skip {
+            if let Ok(record) = serde_json::from_slice::<AuditRecord>(line) {
+                page.push(record);
+            }
+        }
+
+        position += 1;
+
+        true
+    })
+    .with_context(context)?;
+
+    Ok((page, total))
+}
+
+/// Count the lines of a file without keeping them.
+fn count_lines(file: &mut fs::File) -> std::io::Result<usize> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut lines = 0;
+    let mut last = b'\n';
+
+    file.seek(SeekFrom::Start(0))?;
+
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+
+        lines += chunk[..read].iter().filter(|byte| **byte == b'\n').count();
+        last = chunk[read - 1];
+    }
+
+    // A last line without a newline still counts.
+    if last != b'\n' {
+        lines += 1;
+    }
+
+    Ok(lines)
+}
+
+/// Call `visit` with each non-empty line of a file, last line first, reading the file from the end
+/// in chunks. Stops early when `visit` returns false.
+fn read_lines_backwards(
+    file: &mut fs::File,
+    chunk_size: usize,
+    mut visit: impl FnMut(&[u8]) -> bool,
+) -> std::io::Result<()> {
+    let mut position = file.seek(SeekFrom::End(0))?;
+    // The start of a line cut off by the start of the last chunk read
+    let mut rest = vec![];
+
+    while position > 0 {
+        let size = (chunk_size as u64).min(position);
+        position -= size;
+
+        let mut chunk = vec![0; size as usize];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&rest);
+
+        // What comes before the first newline may continue in the previous chunk.
+        let (head, tail) = match chunk.iter().position(|byte| *byte == b'\n') {
+            Some(index) => (&chunk[..index], &chunk[index + 1..]),
+            None => (&chunk[..], &[][..]),
+        };
+
+        for line in tail.rsplit(|byte| *byte == b'\n') {
+            if !line.is_empty() && !visit(line) {
+                return Ok(());
+            }
+        }
+
+        rest = head.to_vec();
+    }
+
+    if !rest.is_empty() {
+        visit(&rest);
+    }
+
+    Ok(())
+}
+
+/// Summarize how the ruleset changed between two snapshots.
+fn summarize(before: &Snapshot, after: &Snapshot) -> String {
+    match (before, after) {
+        (None, None) => "no change".to_string(),
+        (None, Some((rules, interface))) => {
+            format!("enabled on {} with {} rules", interface, rules.len())
+        }
+        (Some((rules, interface)), None) => {
+            format!("disabled on {}, removing {} rules", interface, rules.len())
+        }
+        (Some((old_rules, old_interface)), Some((new_rules, new_interface))) => {
+            let diff = diff_rules(old_rules, new_rules);
+
+            match old_interface == new_interface {
+                true => diff,
+                false => format!(
+                    "moved from {} to {}, {}",
+                    old_interface, new_interface, diff
+                ),
+            }
+        }
+    }
+}
+
+/// Count the rules added, removed and kept. Rules are compared by what they match rather than by
+/// ID, so a rule changed in place counts as one removed and one added.
+fn diff_rules(old: &[Rule], new: &[Rule]) -> String {
+    let key = |rule: &Rule| {
+        (
+            rule.source_start_ip,
+            rule.source_end_ip,
+            rule.destination_start_ip,
+            rule.destination_end_ip,
+            rule.protocol_number,
+            rule.port_number,
+            rule.match_on,
+            rule.ratelimiting,
+            rule.log,
+        )
+    };
+
+    let mut remaining = old.iter().map(key).collect::<Vec<_>>();
+    let mut added = 0;
+
+    for rule in new {
+        match remaining.iter().position(|old| *old == key(rule)) {
+            Some(index) => {
+                remaining.swap_remove(index);
+            }
+            None => added += 1,
+        }
+    }
+
+    let removed = remaining.len();
+
+    match (added, removed) {
+        (0, 0) => format!("no rule changes ({} rules)", new.len()),
+        _ => format!(
+            "{} rules added, {} removed, {} unchanged",
+            added,
+            removed,
+            new.len() - added
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a file for a test, returning it opened.
+    fn file(name: &str, contents: &str) -> fs::File {
+        let path =
+            std::env::temp_dir().join(format!("ghostwire-audit-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+
+        let file = fs::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        file
+    }
+
+    fn backwards(file: &mut fs::File, chunk_size: usize) -> Vec<String> {
+        let mut lines = vec![];
+
+        read_lines_backwards(file, chunk_size, |line| {
+            lines.push(String::from_utf8(line.to_vec()).unwrap());
+            true
+        })
+        .unwrap();
+
+        lines
+    }
+
+    #[test]
+    fn reads_lines_backwards_across_chunks() {
+        let contents = "first\nsecond line\n\nthird, the longest line\nx\nlast\n";
+        let expected = [
+            "last",
+            "x",
+            "third, the longest line",
+            "second line",
+            "first",
+        ];
+        let mut file = file("backwards", contents);
+
+        // Chunk boundaries fall inside lines, on newlines, and past the start of the file.
+        for chunk_size in 1..=contents.len() + 1 {
+            assert_eq!(backwards(&mut file, chunk_size), expected, "{}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn reads_a_last_line_without_a_newline() {
+        let mut file = file("unterminated", "first\nlast");
+
+        assert_eq!(backwards(&mut file, 3), ["last", "first"]);
+        assert_eq!(count_lines(&mut file).unwrap(), 2);
+    }
+
+    #[test]
+    fn stops_reading_when_told_to() {
+        let mut file = file("stop", "a\nb\nc\n");
+        let mut seen = vec![];
+
+        read_lines_backwards(&mut file, 2, |line| {
+            seen.push(line.to_vec());
+            seen.len() < 2
+        })
+        .unwrap();
+
+        assert_eq!(seen, [b"c".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn counts_lines() {
+        assert_eq!(count_lines(&mut file("empty", "")).unwrap(), 0);
+        assert_eq!(count_lines(&mut file("three", "a\nb\nc\n")).unwrap(), 3);
+    }
+}
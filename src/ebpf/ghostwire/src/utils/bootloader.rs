@@ -1,34 +1,109 @@
-/*
- * A TODO is to load the previous state of the firewall on startup, probably over a oneshot. This
- * file is not included as a module yet for that reason.
- *
- * (random code proceeds)
- *
- *
-    let (tx, rx) = oneshot::channel::<(Vec<Rule>, String)>();
-
-    {
-        let mut overall_state = OVERALL_STATE.write().await;
-
-        overall_state.oneshot_send = Some(tx)
+use super::ebpf::{
+    highest_rule_id,
+    load_ebpf,
+    use_rule_id,
+};
+use crate::OVERALL_STATE;
+use anyhow::Context;
+use ghostwire_types::Rule;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+};
+
+/// Where the last accepted ruleset is saved, to be applied again when the daemon starts
+pub const STATE_PATH: &str = "/var/lib/ghostwire/state.json";
+
+/// The ruleset saved across restarts
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    /// The interface the rules were applied to
+    interface: String,
+    /// The applied rules
+    rules: Vec<Rule>,
+    /// The highest rule ID used, so IDs aren't given out again after a restart. Missing from the
+    /// state of runs before it was tracked.
+    #[serde(default)]
+    highest_rule_id: u32,
+}
+
+/// Save or forget an accepted ruleset, depending on whether the client asked for it to persist.
+pub fn persist(persistent: bool, rules: &[Rule], interface: &str) -> anyhow::Result<()> {
+    match persistent {
+        true => save_state(rules, interface),
+        false => clear_state(),
+    }
+    .context("the rules were applied, but couldn't be saved for the next startup")
+}
+
+/// Save the ruleset to the state file. The file is written next to the old one and renamed over
+/// it, so a crash mid-write never leaves a partial ruleset behind.
+fn save_state(rules: &[Rule], interface: &str) -> anyhow::Result<()> {
+    let path = Path::new(STATE_PATH);
+    let tmp_path = path.with_extension("json.tmp");
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let serialized = serde_json::to_vec(&SavedState {
+        interface: interface.to_string(),
+        rules: rules.to_vec(),
+        highest_rule_id: highest_rule_id(),
+    })?;
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(&serialized)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Remove the state file, so the firewall starts with nothing loaded.
+pub fn clear_state() -> anyhow::Result<()> {
+    match fs::remove_file(STATE_PATH) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
     }
-     // block until we get the initial rules or a startup message
-    let (initial_rules, interface) = rx.await?;
+}
+
+/// Apply the ruleset saved by the last run, if there is one.
+pub async fn restore_state() -> anyhow::Result<()> {
+    let contents = match fs::read(STATE_PATH) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!("No saved rules to restore, waiting for rules to be loaded");
+
+            return Ok(());
+        }
+        Err(e) => return Err(e).context(format!("couldn't read {}", STATE_PATH)),
+    };
+
+    let saved: SavedState =
+        serde_json::from_slice(&contents).context(format!("couldn't parse {}", STATE_PATH))?;
 
-    // ok, we're ready to start
-    if initial_rules.is_empty() {
+    if saved.rules.is_empty() {
         tracing::warn!(
-            "Starting firewall with no rules - all inbound new connections will be dropped!"
+            "Restoring firewall with no rules - all inbound new connections will be dropped!"
         )
     }
 
-    // load the eBPF into the kernel
-    let state = Arc::new(load_ebpf(counters.clone(), initial_rules, interface)?);*    // update the CLI with the new state
-    {
-        let state = Arc::clone(&state);
-        let mut overall_state = OVERALL_STATE.write().await;
+    let len = saved.rules.len();
 
-        overall_state.enabled = true;
-        overall_state.state = Some(state);
-    }
-*/
+    use_rule_id(saved.highest_rule_id);
+
+    load_ebpf(saved.rules, saved.interface.clone()).await?;
+
+    OVERALL_STATE.write().await.enabled = true;
+
+    tracing::info!("Restored {} rules on interface {}", len, saved.interface);
+
+    Ok(())
+}
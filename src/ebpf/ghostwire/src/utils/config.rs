@@ -0,0 +1,407 @@
+use super::events::EventKind;
+use anyhow::Context;
+use clap::Parser;
+use serde::Deserialize;
+use std::{
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::OnceLock,
+};
+
+/// Where the daemon configuration is read from, unless another path is given with --config
+const DEFAULT_CONFIG_PATH: &str = "/etc/ghostwire/ghostwire.yml";
+
+/// The daemon configuration, set once at startup
+static CONFIG: OnceLock<DaemonConfig> = OnceLock::new();
+
+#[derive(Debug, Parser)]
+#[clap(name = "ghostwire", about = "Ghostwire is a stateful XDP firewall")]
+/// Command line flags of the daemon. Flags take precedence over the configuration file.
+pub struct Options {
+    /// The path of the daemon configuration file
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// The path of the UNIX socket the CLI connects to
+    #[clap(long, env = "GHOSTWIRE_SOCKET")]
+    pub socket: Option<PathBuf>,
+    /// The group whose members can manage the firewall over the socket, besides root
+    #[clap(long)]
+    pub admin_group: Option<String>,
+    /// The group whose members can read the firewall status over the socket
+    #[clap(long)]
+    pub read_only_group: Option<String>,
+    /// The path of the audit log, where every change to the firewall is recorded
+    #[clap(long)]
+    pub audit_log: Option<PathBuf>,
+    /// The maximum number of clients connected to the socket at once
+    #[clap(long)]
+    pub max_clients: Option<usize>,
+    /// How long to wait on a client to send a request or read a response, in seconds
+    #[clap(long)]
+    pub client_timeout: Option<u64>,
+    /// The address the Prometheus exporter listens on
+    #[clap(long)]
+    pub prometheus_address: Option<SocketAddr>,
+    /// The address the HTTP API listens on. The API is off unless this is set.
+    #[clap(long)]
+    pub api_address: Option<SocketAddr>,
+    /// The path of the file holding the token HTTP API clients authenticate with
+    #[clap(long)]
+    pub api_token_file: Option<PathBuf>,
+    /// The log level, one of trace, debug, info, warn or error
+    #[clap(long)]
+    pub log_level: Option<tracing::Level>,
+    /// The maximum number of tracked ratelimiting keys
+    #[clap(long)]
+    pub ratelimit_map_size: Option<u32>,
+    /// The maximum number of holepunched connections
+    #[clap(long)]
+    pub holepunch_map_size: Option<u32>,
+    /// The maximum number of rules tracked in the analytics map
+    #[clap(long)]
+    pub analytics_map_size: Option<u32>,
+    /// Where the packet log is written, to a file, syslog or journald
+    #[clap(long, value_enum)]
+    pub packet_log: Option<PacketLogOutput>,
+    /// The path of the packet log, when it's written to a file
+    #[clap(long)]
+    pub packet_log_path: Option<PathBuf>,
+    /// Log the packets dropped because no rule let them in
+    #[clap(long)]
+    pub log_default_drops: bool,
+    /// The maximum number of packets each CPU logs per second
+    #[clap(long)]
+    pub packet_log_rate: Option<u32>,
+    /// The number of dropped packets per second over which a flood starts, or 0 to not watch
+    #[clap(long)]
+    pub flood_threshold: Option<u64>,
+    /// The rules configuration file, or a directory of them, applied on SIGHUP and at startup
+    #[clap(long)]
+    pub rules_config: Option<PathBuf>,
+    /// Apply the rules configuration whenever it changes on disk
+    #[clap(long)]
+    pub watch_rules_config: bool,
+    /// How long the rules configuration has to go unchanged before it's applied, in milliseconds
+    #[clap(long)]
+    pub watch_debounce: Option<u64>,
+    /// A local port of management sessions, like SSH, which rulesets can't drop without --force.
+    /// Repeat it for several ports
+    #[clap(long = "management-port")]
+    pub management_ports: Vec<u16>,
+    /// The path of the always-allow list, applied ahead of every ruleset
+    #[clap(long)]
+    pub always_allow: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// The daemon configuration file
+pub struct DaemonConfig {
+    /// The path of the UNIX socket the CLI connects to
+    pub socket: PathBuf,
+    /// The group whose members can manage the firewall over the socket, besides root
+    pub admin_group: Option<String>,
+    /// The group whose members can read the firewall status over the socket
+    pub read_only_group: Option<String>,
+    /// The path of the audit log, where every change to the firewall is recorded
+    pub audit_log: PathBuf,
+    /// The maximum number of clients connected to the socket at once
+    pub max_clients: usize,
+    /// How long to wait on a client to send a request or read a response, in seconds
+    pub client_timeout: u64,
+    /// The address the Prometheus exporter listens on
+    pub prometheus_address: SocketAddr,
+    /// The address the HTTP API listens on. The API is off unless this is set.
+    pub api_address: Option<SocketAddr>,
+    /// The path of the file holding the token HTTP API clients authenticate with
+    pub api_token_file: PathBuf,
+    /// The log level, one of trace, debug, info, warn or error
+    #[serde(with = "level")]
+    pub log_level: tracing::Level,
+    /// The sizes of the eBPF maps
+    pub map_sizes: MapSizes,
+    /// The log of the packets matching rules with logging on, and of default drops
+    pub packet_log: PacketLog,
+    /// The commands run and URLs posted to on events, like a source going over a ratelimit
+    pub hooks: Vec<Hook>,
+    /// The number of dropped packets per second over which a flood starts, or 0 to not watch
+    pub flood_threshold: u64,
+    /// The rules configuration file, or a directory of them, applied on SIGHUP and at startup
+    pub rules_config: Option<PathBuf>,
+    /// Whether the rules configuration is applied whenever it changes on disk
+    pub watch_rules_config: bool,
+    /// How long the rules configuration has to go unchanged before it's applied, in milliseconds
+    pub watch_debounce: u64,
+    /// The local ports of management sessions, like SSH, which rulesets can't drop without force
+    pub management_ports: Vec<u16>,
+    /// The path of the always-allow list, applied ahead of every ruleset
+    pub always_allow: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// The maximum number of entries of the eBPF maps that grow with traffic
+pub struct MapSizes {
+    /// The maximum number of tracked ratelimiting keys
+    pub ratelimiting: u32,
+    /// The maximum number of holepunched connections
+    pub holepunched: u32,
+    /// The maximum number of rules tracked in the analytics map
+    pub rule_analytics: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// The log of the packets matching rules with logging on, and of default drops, as JSON lines
+pub struct PacketLog {
+    /// Where the packet log is written
+    pub output: PacketLogOutput,
+    /// The path of the packet log, when it's written to a file
+    pub path: PathBuf,
+    /// Whether the packets dropped because no rule or holepunched connection let them in are
+    /// logged
+    pub default_drops: bool,
+    /// The maximum number of packets each CPU logs per second. The XDP program counts the others,
+    /// and the next packet logged carries the count.
+    pub max_per_second: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+/// Where the packet log is written
+pub enum PacketLogOutput {
+    /// Append to the file at the path of the packet log
+    File,
+    /// Send to syslog over /dev/log
+    Syslog,
+    /// Send to journald over its native socket
+    Journald,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A command to run or a URL to POST to with each event, as JSON
+pub struct Hook {
+    /// The events the hook runs on, or every event if empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// The program and arguments of a command to run with the event on stdin
+    pub command: Option<Vec<String>>,
+    /// An http:// URL to POST the event to
+    pub url: Option<String>,
+    /// How many times to try again after the command fails or the endpoint doesn't answer with a
+    /// success, waiting a second and then twice as long each time
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How long each try may take, in seconds
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// The maximum number of events the hook runs on each minute, the others are skipped
+    #[serde(default = "default_max_per_minute")]
+    pub max_per_minute: u32,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from("/run/ghostwire/ghostwire.sock"),
+            admin_group: None,
+            read_only_group: None,
+            audit_log: PathBuf::from("/var/log/ghostwire/audit.jsonl"),
+            max_clients: 16,
+            client_timeout: 10,
+            prometheus_address: SocketAddr::from(([127, 0, 0, 1], 4343)),
+            api_address: None,
+            api_token_file: PathBuf::from("/etc/ghostwire/api_token"),
+            log_level: tracing::Level::INFO,
+            map_sizes: MapSizes::default(),
+            packet_log: PacketLog::default(),
+            hooks: vec![],
+            flood_threshold: 100_000,
+            rules_config: None,
+            watch_rules_config: false,
+            watch_debounce: 500,
+            management_ports: vec![22],
+            always_allow: PathBuf::from("/etc/ghostwire/always_allow"),
+        }
+    }
+}
+
+impl Default for MapSizes {
+    fn default() -> Self {
+        Self {
+            ratelimiting: 1_000_000,
+            holepunched: 1_000_000,
+            rule_analytics: 1024,
+        }
+    }
+}
+
+impl Default for PacketLog {
+    fn default() -> Self {
+        Self {
+            output: PacketLogOutput::File,
+            path: PathBuf::from("/var/log/ghostwire/packets.jsonl"),
+            default_drops: false,
+            max_per_second: 100,
+        }
+    }
+}
+
+/// The retries of a hook, unless set
+fn default_retries() -> u32 {
+    3
+}
+
+/// The timeout of a hook, unless set
+fn default_timeout() -> u64 {
+    10
+}
+
+/// The maximum events a hook runs on each minute, unless set
+fn default_max_per_minute() -> u32 {
+    60
+}
+
+/// Parse the command line flags and the configuration file into the daemon configuration.
+pub fn load_config() -> anyhow::Result<()> {
+    let options = Options::parse();
+
+    let mut config = match &options.config {
+        // The file was asked for explicitly, so it has to exist.
+        Some(path) => read_config(path)?,
+        None => match fs::metadata(DEFAULT_CONFIG_PATH) {
+            Ok(_) => read_config(&PathBuf::from(DEFAULT_CONFIG_PATH))?,
+            Err(_) => DaemonConfig::default(),
+        },
+    };
+
+    if let Some(socket) = options.socket {
+        config.socket = socket;
+    }
+    if let Some(admin_group) = options.admin_group {
+        config.admin_group = Some(admin_group);
+    }
+    if let Some(read_only_group) = options.read_only_group {
+        config.read_only_group = Some(read_only_group);
+    }
+    if let Some(audit_log) = options.audit_log {
+        config.audit_log = audit_log;
+    }
+    if let Some(max_clients) = options.max_clients {
+        config.max_clients = max_clients;
+    }
+    if let Some(client_timeout) = options.client_timeout {
+        config.client_timeout = client_timeout;
+    }
+    if let Some(prometheus_address) = options.prometheus_address {
+        config.prometheus_address = prometheus_address;
+    }
+    if let Some(api_address) = options.api_address {
+        config.api_address = Some(api_address);
+    }
+    if let Some(api_token_file) = options.api_token_file {
+        config.api_token_file = api_token_file;
+    }
+    if let Some(log_level) = options.log_level {
+        config.log_level = log_level;
+    }
+    if let Some(size) = options.ratelimit_map_size {
+        config.map_sizes.ratelimiting = size;
+    }
+    if let Some(size) = options.holepunch_map_size {
+        config.map_sizes.holepunched = size;
+    }
+    if let Some(size) = options.analytics_map_size {
+        config.map_sizes.rule_analytics = size;
+    }
+    if let Some(output) = options.packet_log {
+        config.packet_log.output = output;
+    }
+    if let Some(path) = options.packet_log_path {
+        config.packet_log.path = path;
+    }
+    if options.log_default_drops {
+        config.packet_log.default_drops = true;
+    }
+    if let Some(rate) = options.packet_log_rate {
+        config.packet_log.max_per_second = rate;
+    }
+    if let Some(threshold) = options.flood_threshold {
+        config.flood_threshold = threshold;
+    }
+    if let Some(rules_config) = options.rules_config {
+        config.rules_config = Some(rules_config);
+    }
+    if options.watch_rules_config {
+        config.watch_rules_config = true;
+    }
+    if let Some(debounce) = options.watch_debounce {
+        config.watch_debounce = debounce;
+    }
+    if !options.management_ports.is_empty() {
+        config.management_ports = options.management_ports;
+    }
+    if let Some(always_allow) = options.always_allow {
+        config.always_allow = always_allow;
+    }
+
+    for (index, hook) in config.hooks.iter().enumerate() {
+        match (&hook.command, &hook.url) {
+            (Some(command), None) if !command.is_empty() => {}
+            (None, Some(_)) => {}
+            _ => anyhow::bail!("hook {} needs either a command or a url", index + 1),
+        }
+    }
+
+    CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("the configuration was already loaded"))
+}
+
+/// Read and parse a daemon configuration file.
+fn read_config(path: &PathBuf) -> anyhow::Result<DaemonConfig> {
+    let contents = fs::read_to_string(path).context(format!("couldn't read {}", path.display()))?;
+
+    serde_yaml::from_str(&contents).context(format!("couldn't parse {}", path.display()))
+}
+
+/// Get the daemon configuration. Falls back to the defaults if it wasn't loaded.
+pub fn config() -> &'static DaemonConfig {
+    CONFIG.get_or_init(DaemonConfig::default)
+}
+
+/// Set the configuration of the tests, before anything else reads it, so their audit and packet
+/// logs go to a directory of their own rather than /var/log.
+#[cfg(test)]
+pub fn test_config() -> &'static DaemonConfig {
+    CONFIG.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("ghostwire-test-{}", std::process::id()));
+
+        DaemonConfig {
+            audit_log: dir.join("audit.jsonl"),
+            packet_log: PacketLog {
+                path: dir.join("packets.jsonl"),
+                ..PacketLog::default()
+            },
+            ..DaemonConfig::default()
+        }
+    })
+}
+
+/// Deserialize a tracing level from its name.
+mod level {
+    use serde::{
+        Deserialize,
+        Deserializer,
+    };
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<tracing::Level, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
@@ -1,27 +1,107 @@
-use super::state::State;
+use super::{
+    config::config,
+    lockout::always_allow_rules,
+    packet_log,
+    state::{
+        RuleMaps,
+        State,
+    },
+};
 use crate::OVERALL_STATE;
 use anyhow::Context;
 use aya::{
     include_bytes_aligned,
-    maps::HashMap,
+    maps::{
+        Array,
+        HashMap,
+        Map,
+        MapData,
+        RingBuf,
+    },
     programs::{
-        tc,
+        links::FdLink,
+        tc::{
+            self,
+            SchedClassifierLink,
+        },
         SchedClassifier,
         TcAttachType,
         Xdp,
         XdpFlags,
     },
-    Bpf,
+    util::KernelVersion,
+    BpfLoader,
 };
 use aya_log::BpfLogger;
 use ghostwire_common::{
     Rule,
     RuleAnalytics,
+    MATCH_ON_INNER,
+    MATCH_ON_OUTER,
+};
+use ghostwire_types::MatchOn;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{
+            AtomicU32,
+            Ordering,
+        },
+        Arc,
+    },
 };
-use std::sync::Arc;
 use tokio::sync::RwLock;
 
-pub async fn load_ebpf(initial_rules: Vec<Rule>, interface: String) -> anyhow::Result<()> {
+/// Where the maps and the XDP link are pinned in bpffs, so they outlive the daemon
+const PIN_PATH: &str = "/sys/fs/bpf/ghostwire";
+
+/// The name of the pinned XDP link in the pin directory
+const XDP_LINK_PIN: &str = "xdp_link";
+
+/// The number of rule map keys the XDP program evaluates, in order
+const RULE_SLOTS: u32 = 100;
+
+/// What the next run of the daemon needs to adopt the pinned programs
+const PINNED_STATE_PATH: &str = "/run/ghostwire/pinned.json";
+
+/// The highest rule ID applied since the daemon started or saved by an earlier run, including the
+/// IDs of rules removed since. New rules take IDs above it, so they never take over the counters of
+/// a removed rule.
+static HIGHEST_RULE_ID: AtomicU32 = AtomicU32::new(0);
+
+/// The parts of the firewall state that can't be recovered from bpffs
+#[derive(Serialize, Deserialize)]
+struct PinnedState {
+    /// The interface the programs are attached to
+    interface: String,
+    /// The priority and handle of the TC filter
+    tc_filter: (u16, u32),
+    /// The applied rules, in the format the client sent them
+    rules: Vec<ghostwire_types::Rule>,
+    /// The highest rule ID used. Missing from the state of runs before it was tracked.
+    #[serde(default)]
+    highest_rule_id: u32,
+}
+
+/// The highest rule ID used so far, by applied rules or removed ones.
+pub fn highest_rule_id() -> u32 {
+    HIGHEST_RULE_ID.load(Ordering::Relaxed)
+}
+
+/// Count an ID as used, so it isn't given to a new rule.
+pub fn use_rule_id(id: u32) {
+    HIGHEST_RULE_ID.fetch_max(id, Ordering::Relaxed);
+}
+
+pub async fn load_ebpf(
+    initial_rules: Vec<ghostwire_types::Rule>,
+    interface: String,
+) -> anyhow::Result<()> {
     match load_ebpf_fallible(initial_rules.clone(), interface.clone(), false).await {
         Ok(_) => Ok(()),
         Err(e) => {
@@ -43,7 +123,7 @@ pub async fn load_ebpf(initial_rules: Vec<Rule>, interface: String) -> anyhow::R
 
 /// Load the eBPF program, fetching the maps and creating state from partial arguments
 async fn load_ebpf_fallible(
-    initial_rules: Vec<Rule>,
+    initial_rules: Vec<ghostwire_types::Rule>,
     interface: String,
     skb: bool,
 ) -> anyhow::Result<()> {
@@ -58,12 +138,19 @@ async fn load_ebpf_fallible(
         tracing::debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
+    let map_sizes = &config().map_sizes;
+    let mut loader = BpfLoader::new();
+    loader
+        .set_max_entries("RATELIMITING", map_sizes.ratelimiting)
+        .set_max_entries("HOLEPUNCHED", map_sizes.holepunched)
+        .set_max_entries("RULE_ANALYTICS", map_sizes.rule_analytics);
+
     #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
+    let mut bpf = loader.load(include_bytes_aligned!(
         "../../../target/bpfel-unknown-none/debug/ghostwire"
     ))?;
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
+    let mut bpf = loader.load(include_bytes_aligned!(
         "../../../target/bpfel-unknown-none/release/ghostwire"
     ))?;
 
@@ -72,9 +159,19 @@ async fn load_ebpf_fallible(
         tracing::info!("didn't initialize eBPF logger: {}", e);
     }
 
+    // Start from a clean pin directory, anything left in it isn't attached anymore.
+    remove_pins();
+    fs::create_dir_all(PIN_PATH).context(format!("couldn't create {}", PIN_PATH))?;
+
+    // Pin the maps, so the next run of the daemon can pick them back up.
+    for (name, map) in bpf.maps() {
+        map.pin(Path::new(PIN_PATH).join(name))
+            .context(format!("couldn't pin map {}", name))?;
+    }
+
     let program: &mut Xdp = bpf.program_mut("ghostwire_xdp").unwrap().try_into()?;
     program.load().unwrap();
-    program
+    let xdp_link_id = program
         .attach(
             &interface,
             match skb {
@@ -83,22 +180,52 @@ async fn load_ebpf_fallible(
             },
         )
         .context("failed to attach XDP. trying with SKB next...")?;
+    let xdp_link = program.take_link(xdp_link_id)?;
+
+    // Pinning the link keeps the program attached after the daemon exits. Kernels older than 5.9
+    // attach XDP over netlink, which can't be pinned, so we hold onto the link instead.
+    let xdp_link = if KernelVersion::current().is_ok_and(|v| v >= KernelVersion::new(5, 9, 0)) {
+        FdLink::try_from(xdp_link)?
+            .pin(Path::new(PIN_PATH).join(XDP_LINK_PIN))
+            .context("couldn't pin the XDP link")?;
+
+        None
+    } else {
+        tracing::warn!(
+            "this kernel can't pin XDP links, the firewall will detach when the daemon exits"
+        );
+
+        Some(xdp_link)
+    };
     let _ = tc::qdisc_add_clsact(&interface);
 
     let program: &mut SchedClassifier = bpf.program_mut("ghostwire_tc").unwrap().try_into()?;
     program.load()?;
-    program.attach(&interface, TcAttachType::Egress)?;
+    let tc_link_id = program.attach(&interface, TcAttachType::Egress)?;
+    let tc_link = program.take_link(tc_link_id)?;
+    let tc_filter = (tc_link.priority(), tc_link.handle());
+    // TC filters stay attached without the daemon. Forget the link so it isn't detached when
+    // dropped, we'll detach it by its priority and handle when the firewall is disabled.
+    std::mem::forget(tc_link);
 
     // Fetch the eBPF maps.
-    let mut rule_map: HashMap<_, u32, Rule> = HashMap::try_from(bpf.take_map("RULES").unwrap())?;
+    let mut rule_maps = RuleMaps {
+        banks: [
+            HashMap::try_from(bpf.take_map("RULES_A").unwrap())?,
+            HashMap::try_from(bpf.take_map("RULES_B").unwrap())?,
+        ],
+        active: Array::try_from(bpf.take_map("ACTIVE_RULES").unwrap())?,
+    };
 
-    for (i, rule) in initial_rules.iter().enumerate() {
-        rule_map.insert(i as u32, rule, 0)?;
-    }
+    write_rules(&mut rule_maps, &initial_rules)?;
+
+    initial_rules.iter().for_each(|rule| use_rule_id(rule.id));
 
     let rule_ratelimit_map: HashMap<_, u64, u64> =
         HashMap::try_from(bpf.take_map("RATELIMITING").unwrap())?;
 
+    let holepunched = HashMap::try_from(bpf.take_map("HOLEPUNCHED").unwrap())?;
+
     let rule_analytic_map: HashMap<_, u32, RuleAnalytics> =
         HashMap::try_from(bpf.take_map("RULE_ANALYTICS").unwrap())?;
 
@@ -108,14 +235,28 @@ async fn load_ebpf_fallible(
     let tc_analytic_map: HashMap<_, i32, u128> =
         HashMap::try_from(bpf.take_map("TC_ACTION_ANALYTICS").unwrap())?;
 
+    let packet_log = packet_log::start(
+        Array::try_from(bpf.take_map("LOG_SETTINGS").unwrap())?,
+        RingBuf::try_from(bpf.take_map("PACKET_LOG").unwrap())?,
+        RingBuf::try_from(bpf.take_map("BLOCKED_SOURCES").unwrap())?,
+        &interface,
+    )?;
+
+    save_pinned_state(&interface, tc_filter, &initial_rules)?;
+
     let state = Arc::new(State {
         interface,
-        _ebpf: RwLock::new(bpf),
-        rule_map: RwLock::new(rule_map),
+        tc_filter,
+        _xdp_link: xdp_link,
+        rules: RwLock::new(initial_rules),
+        rule_maps: RwLock::new(rule_maps),
         rule_ratelimit_map: RwLock::new(rule_ratelimit_map),
-        rule_analytic_map,
+        blocked_sources: RwLock::new(vec![]),
+        holepunched: RwLock::new(holepunched),
+        rule_analytic_map: RwLock::new(rule_analytic_map),
         xdp_analytic_map,
         tc_analytic_map,
+        _packet_log: packet_log,
     });
 
     // Load the state.
@@ -126,13 +267,331 @@ async fn load_ebpf_fallible(
     Ok(())
 }
 
+/// Take over the programs and maps pinned by a previous run of the daemon, instead of loading
+/// them again. This keeps the holepunched connections and leaves traffic untouched across
+/// restarts. Returns whether there was anything to adopt.
+pub async fn adopt_pinned() -> anyhow::Result<bool> {
+    let contents = match fs::read(PINNED_STATE_PATH) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).context(format!("couldn't read {}", PINNED_STATE_PATH)),
+    };
+
+    // Without the XDP link, nothing is filtering traffic and there's nothing worth adopting.
+    if !Path::new(PIN_PATH).join(XDP_LINK_PIN).exists() {
+        tracing::warn!("Found pinned state without a pinned XDP link, loading from scratch");
+
+        return Ok(false);
+    }
+
+    let pinned: PinnedState = serde_json::from_slice(&contents)
+        .context(format!("couldn't parse {}", PINNED_STATE_PATH))?;
+
+    use_rule_id(pinned.highest_rule_id);
+    pinned.rules.iter().for_each(|rule| use_rule_id(rule.id));
+
+    // The settings are written again, in case the daemon configuration changed.
+    let packet_log = packet_log::start(
+        Array::try_from(Map::Array(pinned_map("LOG_SETTINGS")?))?,
+        RingBuf::try_from(Map::RingBuf(pinned_map("PACKET_LOG")?))?,
+        RingBuf::try_from(Map::RingBuf(pinned_map("BLOCKED_SOURCES")?))?,
+        &pinned.interface,
+    )?;
+
+    let state = Arc::new(State {
+        tc_filter: pinned.tc_filter,
+        _xdp_link: None,
+        rule_maps: RwLock::new(RuleMaps {
+            banks: [
+                HashMap::try_from(Map::HashMap(pinned_map("RULES_A")?))?,
+                HashMap::try_from(Map::HashMap(pinned_map("RULES_B")?))?,
+            ],
+            active: Array::try_from(Map::Array(pinned_map("ACTIVE_RULES")?))?,
+        }),
+        rule_ratelimit_map: RwLock::new(HashMap::try_from(Map::LruHashMap(pinned_map(
+            "RATELIMITING",
+        )?))?),
+        blocked_sources: RwLock::new(vec![]),
+        holepunched: RwLock::new(HashMap::try_from(Map::LruHashMap(pinned_map(
+            "HOLEPUNCHED",
+        )?))?),
+        rule_analytic_map: RwLock::new(HashMap::try_from(Map::HashMap(pinned_map(
+            "RULE_ANALYTICS",
+        )?))?),
+        xdp_analytic_map: HashMap::try_from(Map::HashMap(pinned_map("XDP_ACTION_ANALYTICS")?))?,
+        tc_analytic_map: HashMap::try_from(Map::HashMap(pinned_map("TC_ACTION_ANALYTICS")?))?,
+        rules: RwLock::new(pinned.rules),
+        interface: pinned.interface,
+        _packet_log: packet_log,
+    });
+
+    tracing::info!(
+        "Adopted the firewall pinned on interface {} by the previous run",
+        state.interface
+    );
+
+    let mut write = OVERALL_STATE.write().await;
+
+    write.state = Some(state);
+    write.enabled = true;
+
+    Ok(true)
+}
+
 /// Unload the eBPF program
 pub async fn unload_ebpf() {
     let mut write = OVERALL_STATE.write().await;
 
+    // The TC filter isn't tied to the daemon, so it's detached by hand.
+    if let Some(state) = &write.state {
+        let (priority, handle) = state.tc_filter;
+
+        match SchedClassifierLink::attached(
+            &state.interface,
+            TcAttachType::Egress,
+            priority,
+            handle,
+        ) {
+            // Dropping the link detaches it.
+            Ok(link) => drop(link),
+            Err(e) => tracing::error!("Failed to detach the TC filter: {}", e),
+        }
+    }
+
+    // Removing the pins detaches the XDP link, and the maps are freed once the state drops.
+    remove_pins();
+
+    if let Err(e) = fs::remove_file(PINNED_STATE_PATH) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::error!("Failed to remove {}: {}", PINNED_STATE_PATH, e);
+        }
+    }
+
     // writing None to the state necessarily will drop the eBPF program
     // @see https://aya-rs.dev/book/aya/lifecycle/#populating-our-map-from-userspace
     // a critical assumption is that the state is not being used anywhere else in the program (this
     // assumption is currently correct, this is the only reference persistently held to the state)
     write.state = None;
 }
+
+/// Let go of the firewall as the daemon exits. A pinned firewall keeps filtering for the next run
+/// to adopt. One that couldn't be pinned would lose its XDP program with the daemon anyway, so
+/// it's unloaded along with its TC filter, leaving nothing half attached.
+pub async fn hand_off() {
+    let pinned = OVERALL_STATE
+        .read()
+        .await
+        .state
+        .as_ref()
+        .map(|state| state._xdp_link.is_none());
+
+    match pinned {
+        Some(true) => tracing::info!("Leaving the firewall pinned for the next run to adopt"),
+        Some(false) => {
+            tracing::warn!("Detaching the firewall, as this kernel couldn't pin it");
+
+            unload_ebpf().await;
+        }
+        None => {}
+    }
+}
+
+/// Open a map pinned by [`load_ebpf_fallible`].
+fn pinned_map(name: &str) -> anyhow::Result<MapData> {
+    MapData::from_pin(Path::new(PIN_PATH).join(name))
+        .context(format!("couldn't open pinned map {}", name))
+}
+
+/// Remove everything pinned under the pin directory.
+fn remove_pins() {
+    if let Err(e) = fs::remove_dir_all(PIN_PATH) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::error!("Failed to remove the pins in {}: {}", PIN_PATH, e);
+        }
+    }
+}
+
+/// Save what the next run of the daemon needs to adopt the pinned programs. Pins don't survive a
+/// reboot, so this lives in /run, which doesn't either.
+fn save_pinned_state(
+    interface: &str,
+    tc_filter: (u16, u32),
+    rules: &[ghostwire_types::Rule],
+) -> anyhow::Result<()> {
+    let path = Path::new(PINNED_STATE_PATH);
+    let tmp_path = path.with_extension("json.tmp");
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(
+        &tmp_path,
+        serde_json::to_vec(&PinnedState {
+            interface: interface.to_string(),
+            tc_filter,
+            rules: rules.to_vec(),
+            highest_rule_id: highest_rule_id(),
+        })?,
+    )?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Write a ruleset to the bank of the rule map the XDP program isn't reading, then switch the
+/// program over to it with a single write. Each packet is checked against the old rules or the new
+/// ones, never a mix, and a ruleset that fails to write leaves the old one in use.
+fn write_rules(maps: &mut RuleMaps, rules: &[ghostwire_types::Rule]) -> anyhow::Result<()> {
+    let rules = map_rules(rules)?;
+
+    if rules.len() > RULE_SLOTS as usize {
+        anyhow::bail!(
+            "there are {} rules with the always-allow list, but only room for {}",
+            rules.len(),
+            RULE_SLOTS
+        );
+    }
+
+    let standby = match maps.active.get(&0, 0)? {
+        0 => 1,
+        _ => 0,
+    };
+    let bank = &mut maps.banks[standby as usize];
+
+    // eBPF maps are super limited in what they can do in comparison to a HashMap from the standard
+    // library, so instead of being able to clear the map,
+    // we'll have to sauce it up
+    let keys = bank.keys().collect::<Result<Vec<_>, _>>()?;
+
+    for key in keys {
+        bank.remove(&key)?;
+    }
+
+    for (i, rule) in rules.iter().enumerate() {
+        bank.insert(i as u32, rule, 0)?;
+    }
+
+    maps.active.set(0, standby, 0)?;
+
+    Ok(())
+}
+
+/// Replace the rules of a loaded eBPF program with a new list, switching to them at once.
+pub async fn replace_rules(state: &State, rules: Vec<ghostwire_types::Rule>) -> anyhow::Result<()> {
+    let mut applied = state.rules.write().await;
+
+    write_rules(&mut *state.rule_maps.write().await, &rules)?;
+
+    rules.iter().for_each(|rule| use_rule_id(rule.id));
+
+    let removed = applied
+        .iter()
+        .filter(|old| !rules.iter().any(|rule| rule.id == old.id))
+        .map(|old| old.id)
+        .collect::<Vec<_>>();
+
+    remove_analytics(state, &removed).await;
+    save_pinned_state(&state.interface, state.tc_filter, &rules)?;
+
+    *applied = rules;
+
+    Ok(())
+}
+
+/// Add a rule after the applied ones.
+pub async fn add_rule(state: &State, rule: ghostwire_types::Rule) -> anyhow::Result<()> {
+    let mut applied = state.rules.write().await;
+    let mut rules = applied.clone();
+
+    rules.push(rule);
+
+    write_rules(&mut *state.rule_maps.write().await, &rules)?;
+    rules.iter().for_each(|rule| use_rule_id(rule.id));
+    save_pinned_state(&state.interface, state.tc_filter, &rules)?;
+
+    *applied = rules;
+
+    Ok(())
+}
+
+/// Replace an applied rule, keeping its ID and its position.
+pub async fn update_rule(state: &State, rule: ghostwire_types::Rule) -> anyhow::Result<()> {
+    let mut applied = state.rules.write().await;
+    let mut rules = applied.clone();
+
+    let index = rules
+        .iter()
+        .position(|applied| applied.id == rule.id)
+        .context(format!("no applied rule has the ID {}", rule.id))?;
+
+    rules[index] = rule;
+
+    write_rules(&mut *state.rule_maps.write().await, &rules)?;
+    save_pinned_state(&state.interface, state.tc_filter, &rules)?;
+
+    *applied = rules;
+
+    Ok(())
+}
+
+/// Remove an applied rule, the rules after it moving up a place.
+pub async fn delete_rule(state: &State, id: u32) -> anyhow::Result<()> {
+    let mut applied = state.rules.write().await;
+    let mut rules = applied.clone();
+
+    let index = rules
+        .iter()
+        .position(|applied| applied.id == id)
+        .context(format!("no applied rule has the ID {}", id))?;
+
+    rules.remove(index);
+
+    write_rules(&mut *state.rule_maps.write().await, &rules)?;
+    remove_analytics(state, &[id]).await;
+    save_pinned_state(&state.interface, state.tc_filter, &rules)?;
+
+    *applied = rules;
+
+    Ok(())
+}
+
+/// Remove the counters of rules that left the ruleset, so they don't pile up in the metrics. Their
+/// IDs aren't given out again, so no new rule would count on from them.
+async fn remove_analytics(state: &State, ids: &[u32]) {
+    let mut analytics = state.rule_analytic_map.write().await;
+
+    // A rule no packet reached has no counters to remove.
+    for id in ids {
+        let _ = analytics.remove(id);
+    }
+}
+
+/// Build the contents of the rule map from the client's rules, putting the always-allow list
+/// first so it can't be overridden.
+fn map_rules(rules: &[ghostwire_types::Rule]) -> anyhow::Result<Vec<Rule>> {
+    Ok(always_allow_rules()?
+        .iter()
+        .chain(rules)
+        .map(convert_rule)
+        .collect())
+}
+
+/// Convert a rule from the common format to the eBPF format for insertion into the map.
+fn convert_rule(rule: &ghostwire_types::Rule) -> Rule {
+    Rule {
+        id: rule.id,
+        source_start_ip: rule.source_start_ip,
+        source_end_ip: rule.source_end_ip,
+        destination_start_ip: rule.destination_start_ip,
+        destination_end_ip: rule.destination_end_ip,
+        protocol_number: rule.protocol_number,
+        match_on: match rule.match_on {
+            MatchOn::Outer => MATCH_ON_OUTER,
+            MatchOn::Inner => MATCH_ON_INNER,
+        },
+        port_number: rule.port_number,
+        ratelimiting: rule.ratelimiting,
+        log: rule.log as u8,
+    }
+}
@@ -0,0 +1,480 @@
+use super::config::{
+    config,
+    Hook,
+};
+use crate::OVERALL_STATE;
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    client::conn::http1,
+    header,
+    Request,
+    Uri,
+};
+use hyper_util::rt::TokioIo;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    net::Ipv4Addr,
+    process::Stdio,
+    sync::{
+        Arc,
+        OnceLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    process::Command,
+    sync::broadcast::{
+        self,
+        error::RecvError,
+    },
+    time::{
+        interval,
+        sleep,
+        timeout,
+        Instant,
+    },
+};
+
+/// The number of events kept for hooks that fall behind, after which they miss the oldest
+const BUS_CAPACITY: usize = 1024;
+
+/// How often the rate of dropped packets is checked for floods, in seconds
+const FLOOD_INTERVAL: u64 = 10;
+
+/// The key of dropped packets in the XDP action map
+const XDP_DROP: u32 = 1;
+
+/// Something that happened to the firewall, sent to the hooks as JSON
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A source went over the ratelimit of a rule, so its packets matching the rule are dropped
+    /// until the ratelimits reset at the end of the minute
+    SourceBlocked {
+        interface: String,
+        source_ip: Ipv4Addr,
+        rule_id: u32,
+        rule_name: Option<String>,
+        ratelimit: u32,
+    },
+    /// The XDP program started dropping more packets per second than the flood threshold
+    FloodStarted {
+        interface: String,
+        drops_per_second: u64,
+        threshold: u64,
+    },
+    /// The rate of dropped packets fell back under the flood threshold
+    FloodEnded {
+        interface: String,
+        drops_per_second: u64,
+        threshold: u64,
+    },
+    /// A ruleset wasn't confirmed in time, so the one before it was restored
+    Rollback { success: bool, message: String },
+}
+
+/// The kinds of events, to pick the ones a hook runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    SourceBlocked,
+    FloodStarted,
+    FloodEnded,
+    Rollback,
+}
+
+/// An event as the hooks receive it
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    /// The Unix timestamp of the event, in seconds
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl Event {
+    /// The kind of the event.
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::SourceBlocked { .. } => EventKind::SourceBlocked,
+            Event::FloodStarted { .. } => EventKind::FloodStarted,
+            Event::FloodEnded { .. } => EventKind::FloodEnded,
+            Event::Rollback { .. } => EventKind::Rollback,
+        }
+    }
+}
+
+/// The bus the events are published on, each hook reading it on its own
+fn bus() -> &'static broadcast::Sender<Arc<Event>> {
+    static BUS: OnceLock<broadcast::Sender<Arc<Event>>> = OnceLock::new();
+
+    BUS.get_or_init(|| broadcast::channel(BUS_CAPACITY).0)
+}
+
+/// Send an event to the hooks. Events are dropped if no hook is configured.
+pub fn publish(event: Event) {
+    // Sources can be blocked by the thousand in an attack, the other events are rare.
+    match event {
+        Event::SourceBlocked { .. } => tracing::debug!("Event: {:?}", event),
+        _ => tracing::info!("Event: {:?}", event),
+    }
+
+    let _ = bus().send(Arc::new(event));
+}
+
+/// Start a task for each configured hook, each reading the events on its own so a slow hook
+/// doesn't hold up the others.
+pub fn start_hooks() {
+    for (index, hook) in config().hooks.iter().enumerate() {
+        let receiver = bus().subscribe();
+
+        tokio::spawn(run_hook(index + 1, hook, receiver));
+    }
+}
+
+/// Run a hook on the events it's configured for, at most `max_per_minute` times a minute.
+async fn run_hook(
+    number: usize,
+    hook: &'static Hook,
+    mut receiver: broadcast::Receiver<Arc<Event>>,
+) {
+    let mut window = Instant::now();
+    let mut sent = 0;
+    let mut skipped = 0;
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Hook {} fell behind and missed {} events", number, missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if !hook.events.is_empty() && !hook.events.contains(&event.kind()) {
+            continue;
+        }
+
+        if window.elapsed() >= Duration::from_secs(60) {
+            if skipped > 0 {
+                tracing::warn!(
+                    "Hook {} skipped {} events over its limit of {} a minute",
+                    number,
+                    skipped,
+                    hook.max_per_minute
+                );
+            }
+
+            window = Instant::now();
+            sent = 0;
+            skipped = 0;
+        }
+
+        if sent >= hook.max_per_minute {
+            skipped += 1;
+            continue;
+        }
+
+        sent += 1;
+
+        let body = match serde_json::to_string(&EventRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+            event: &event,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("Failed to serialize event for hook {}: {}", number, e);
+                continue;
+            }
+        };
+
+        // Try again after a second, then twice as long after each failure.
+        let mut delay = Duration::from_secs(1);
+
+        for attempt in 0..=hook.retries {
+            let result =
+                match timeout(Duration::from_secs(hook.timeout), deliver(hook, &body)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(anyhow::anyhow!("timed out after {} seconds", hook.timeout)),
+                };
+
+            match result {
+                Ok(_) => break,
+                Err(e) if attempt < hook.retries => {
+                    tracing::warn!("Hook {} failed, trying again: {:#}", number, e);
+
+                    sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "Hook {} failed {} times, giving up on the event: {:#}",
+                        number,
+                        hook.retries + 1,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Run the hook's command or POST to its URL, with the event as the body.
+async fn deliver(hook: &Hook, body: &str) -> anyhow::Result<()> {
+    if let Some(command) = &hook.command {
+        run_command(command, body).await
+    } else if let Some(url) = &hook.url {
+        post(url, body).await
+    } else {
+        Ok(())
+    }
+}
+
+/// Run a command with the event on stdin. The command is killed if the hook times out.
+async fn run_command(command: &[String], body: &str) -> anyhow::Result<()> {
+    let (program, args) = command.split_first().context("the command is empty")?;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context(format!("couldn't run {}", program))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body.as_bytes()).await?;
+    }
+
+    let status = child.wait().await?;
+
+    if !status.success() {
+        anyhow::bail!("{} exited with {}", program, status);
+    }
+
+    Ok(())
+}
+
+/// POST the event to a URL. Only plain HTTP is supported, for endpoints on the host or a trusted
+/// network.
+async fn post(url: &str, body: &str) -> anyhow::Result<()> {
+    let uri: Uri = url.parse().context(format!("{} isn't a URL", url))?;
+
+    if uri.scheme_str() != Some("http") {
+        anyhow::bail!("{} isn't an http:// URL", url);
+    }
+
+    let authority = uri.authority().context(format!("{} has no host", url))?;
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = authority.port_u16().unwrap_or(80);
+
+    let stream = TcpStream::connect((host, port))
+        .await
+        .context(format!("couldn't connect to {}", authority))?;
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+
+    tokio::spawn(connection);
+
+    let request = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(header::HOST, authority.as_str())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::USER_AGENT, "ghostwire")
+        .body(Full::new(Bytes::from(body.to_string())))?;
+
+    let response = sender.send_request(request).await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!("{} answered {}", url, response.status());
+    }
+
+    Ok(())
+}
+
+/// Watch the rate of packets the XDP program drops, publishing an event when it goes over the
+/// flood threshold and when it falls back under. Designed to be run as a task.
+pub async fn watch_floods() {
+    let threshold = config().flood_threshold;
+
+    if threshold == 0 {
+        return;
+    }
+
+    let mut ticker = interval(Duration::from_secs(FLOOD_INTERVAL));
+    // The drop count at the last check, and the interface it was for
+    let mut last: Option<(String, u128)> = None;
+    let mut flooding = false;
+
+    loop {
+        ticker.tick().await;
+
+        let current = OVERALL_STATE.read().await.state.clone().map(|state| {
+            (
+                state.interface.clone(),
+                state.xdp_analytic_map.get(&XDP_DROP, 0).unwrap_or(0),
+            )
+        });
+
+        let rate = match (&last, &current) {
+            // The counters start over when the program is loaded again, so skip the check then.
+            (Some((last_interface, last_drops)), Some((interface, drops)))
+                if last_interface == interface && drops >= last_drops =>
+            {
+                Some((
+                    interface.clone(),
+                    ((drops - last_drops) / FLOOD_INTERVAL as u128) as u64,
+                ))
+            }
+            _ => None,
+        };
+
+        last = current;
+
+        let Some((interface, drops_per_second)) = rate else {
+            continue;
+        };
+
+        if !flooding && drops_per_second > threshold {
+            flooding = true;
+
+            publish(Event::FloodStarted {
+                interface,
+                drops_per_second,
+                threshold,
+            });
+        } else if flooding && drops_per_second <= threshold {
+            flooding = false;
+
+            publish(Event::FloodEnded {
+                interface,
+                drops_per_second,
+                threshold,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::AsyncReadExt,
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    /// Answer the requests on a listener with the statuses in turn, sending each request's body.
+    async fn serve(listener: TcpListener, statuses: Vec<u16>, bodies: mpsc::Sender<String>) {
+        for status in statuses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+
+            // Read the headers, then the body up to its Content-Length.
+            let body = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length: ")?
+                                .parse()
+                                .ok()
+                        })
+                        .unwrap_or(0);
+
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                }
+            };
+
+            stream
+                .write_all(
+                    format!("HTTP/1.1 {} Status\r\ncontent-length: 0\r\n\r\n", status).as_bytes(),
+                )
+                .await
+                .unwrap();
+            bodies.send(body).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn url_hooks_post_the_event_and_retry() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook = Box::leak(Box::new(Hook {
+            events: vec![EventKind::SourceBlocked],
+            command: None,
+            url: Some(format!("http://{}/hook", listener.local_addr().unwrap())),
+            retries: 2,
+            timeout: 5,
+            max_per_minute: 10,
+        }));
+        let (bodies, mut received) = mpsc::channel(4);
+        let (sender, receiver) = broadcast::channel(4);
+
+        // A third request would be answered too, but the hook shouldn't send one.
+        tokio::spawn(serve(listener, vec![500, 200, 200], bodies));
+        tokio::spawn(run_hook(1, hook, receiver));
+
+        // Events the hook isn't configured for are skipped.
+        sender
+            .send(Arc::new(Event::Rollback {
+                success: true,
+                message: "restored".to_string(),
+            }))
+            .unwrap();
+        sender
+            .send(Arc::new(Event::SourceBlocked {
+                interface: "eth0".to_string(),
+                source_ip: Ipv4Addr::new(192, 0, 2, 1),
+                rule_id: 7,
+                rule_name: Some("ssh".to_string()),
+                ratelimit: 100,
+            }))
+            .unwrap();
+
+        let start = Instant::now();
+        let first = received.recv().await.unwrap();
+        let second = received.recv().await.unwrap();
+
+        // The failed POST is tried again after a second, with the same event.
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(first, second);
+
+        let event: serde_json::Value = serde_json::from_str(&first).unwrap();
+        assert_eq!(event["event"], "source_blocked");
+        assert_eq!(event["interface"], "eth0");
+        assert_eq!(event["source_ip"], "192.0.2.1");
+        assert_eq!(event["rule_id"], 7);
+        assert_eq!(event["rule_name"], "ssh");
+        assert_eq!(event["ratelimit"], 100);
+        assert!(event["timestamp"].as_u64().unwrap() > 0);
+
+        // The hook stops trying once the POST succeeds.
+        assert!(timeout(Duration::from_secs(2), received.recv())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,590 @@
+use super::{
+    config::config,
+    reload::monotonic_usec,
+    state::State,
+};
+use anyhow::Context;
+use ghostwire_common::Connection;
+use ghostwire_types::{
+    parse_protocol,
+    MatchOn,
+    Rule,
+    RESERVED_RULE_IDS,
+};
+use std::{
+    fmt,
+    fs,
+    net::Ipv4Addr,
+};
+
+/// How recently a connection in the holepunch table must have sent traffic to be considered a
+/// session, as connections closed with a FIN stay in the table.
+const HOLEPUNCH_IDLE_SECS: u64 = 300;
+
+/// Always-allow rules take IDs counting down from here, out of the way of the client's rule IDs.
+const ALWAYS_ALLOW_ID_START: u32 = u32::MAX;
+
+/// An established inbound management session, like an SSH connection.
+#[derive(Debug, PartialEq)]
+pub struct Session {
+    /// The remote address of the session
+    pub source_ip: Ipv4Addr,
+    /// The local address of the session
+    pub destination_ip: Ipv4Addr,
+    /// The local port of the session
+    pub port: u16,
+    /// Whether this is the session of the client making the request
+    pub client: bool,
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {}:{}",
+            self.source_ip, self.destination_ip, self.port
+        )?;
+
+        if self.client {
+            write!(f, " (your session)")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Refuse a ruleset that would drop the established management sessions, including the session
+/// of the client asking for it. A session only counts as allowed if the first rule it matches
+/// isn't ratelimited, as the XDP program drops the traffic over a ratelimit.
+pub async fn check_lockout(
+    state: Option<&State>,
+    rules: &[Rule],
+    client_pid: Option<i32>,
+) -> anyhow::Result<()> {
+    let rules = always_allow_rules()?
+        .into_iter()
+        .chain(rules.iter().cloned())
+        .collect::<Vec<_>>();
+
+    let holepunched = match state {
+        Some(state) => holepunched_sessions(state).await,
+        None => vec![],
+    };
+
+    let dropped = management_sessions(holepunched, client_pid)
+        .into_iter()
+        .filter_map(|session| drop_reason(&rules, &session))
+        .collect::<Vec<_>>();
+
+    if !dropped.is_empty() {
+        anyhow::bail!(
+            "the new rules would drop these management sessions: {}. Allow them in the rules or {}, or load with --force",
+            dropped.join(", "),
+            config().always_allow.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Describe how a session would be dropped by the rules, if it would be. The XDP program lets the
+/// traffic in on the first rule it matches, within that rule's ratelimit.
+fn drop_reason(rules: &[Rule], session: &Session) -> Option<String> {
+    match rules.iter().find(|rule| matches(rule, session)) {
+        Some(rule) if rule.ratelimiting == 0 => None,
+        Some(rule) => Some(format!("{} (ratelimited by rule {})", session, rule.id)),
+        None => Some(session.to_string()),
+    }
+}
+
+/// Read the always-allow list into rules. Each line is a source IP range in CIDR notation,
+/// optionally followed by a protocol and a port, like `10.0.0.0/8 tcp 22`. Lines starting with `#`
+/// are ignored.
+pub fn always_allow_rules() -> anyhow::Result<Vec<Rule>> {
+    let path = &config().always_allow;
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(format!("couldn't read {}", path.display())),
+    };
+
+    let lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+
+    if lines.len() as u64 > (ALWAYS_ALLOW_ID_START - RESERVED_RULE_IDS) as u64 + 1 {
+        anyhow::bail!(
+            "{} has more entries than there are IDs reserved for it",
+            path.display()
+        );
+    }
+
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| {
+            parse_always_allow(line, ALWAYS_ALLOW_ID_START - i as u32)
+                .with_context(|| format!("invalid entry in {}: {}", path.display(), line))
+        })
+        .collect()
+}
+
+/// Parse a line of the always-allow list into a rule.
+fn parse_always_allow(line: &str, id: u32) -> anyhow::Result<Rule> {
+    let mut parts = line.split_whitespace();
+
+    let ip_range = parts.next().context("missing IP range")?;
+    let (ip, prefix_length) = match ip_range.split_once('/') {
+        Some((ip, prefix_length)) => (ip, prefix_length.parse().context("invalid prefix length")?),
+        None => (ip_range, 32),
+    };
+    let ip: Ipv4Addr = ip.parse().context("invalid IP address")?;
+    if prefix_length > 32 {
+        anyhow::bail!("invalid prefix length");
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix_length).unwrap_or(0);
+    let start_ip = u32::from(ip) & mask;
+    let end_ip = start_ip | !mask;
+
+    let protocol_number = match parts.next() {
+        Some(protocol) => parse_protocol(protocol).context("invalid protocol")?,
+        None => 0,
+    };
+
+    let port_number: u16 = match parts.next() {
+        Some(port) => port.parse().context("invalid port")?,
+        None => 0,
+    };
+
+    Ok(Rule {
+        id,
+        name: None,
+        source_start_ip: start_ip.to_be(),
+        source_end_ip: end_ip.to_be(),
+        destination_start_ip: 0,
+        destination_end_ip: u32::MAX,
+        protocol_number,
+        match_on: MatchOn::Outer,
+        port_number: port_number.to_be(),
+        ratelimiting: 0,
+        log: false,
+    })
+}
+
+/// Whether a rule matches a session's traffic, the way the XDP program evaluates rules.
+fn matches(rule: &Rule, session: &Session) -> bool {
+    // Ranges are compared in host order, the bounds are stored in big endian. Ports are compared
+    // as they're laid out in the packet.
+    let src_ip = u32::from(session.source_ip);
+    let port = session.port.to_be();
+
+    // Management sessions aren't tunneled.
+    if rule.match_on == MatchOn::Inner {
+        return false;
+    }
+
+    if src_ip < u32::from_be(rule.source_start_ip) || src_ip > u32::from_be(rule.source_end_ip) {
+        return false;
+    }
+
+    if rule.protocol_number != 0 {
+        // Management sessions are always TCP.
+        if rule.protocol_number != 6 {
+            return false;
+        }
+
+        if rule.port_number != 0 && rule.port_number != port {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Find the established management sessions from the kernel's TCP tables and the connections in
+/// the holepunch table, along with the session of the client if it's connected over SSH.
+fn management_sessions(holepunched: Vec<Session>, client_pid: Option<i32>) -> Vec<Session> {
+    let ports = &config().management_ports;
+    let mut sessions = vec![];
+
+    for (path, parse) in [
+        (
+            "/proc/net/tcp",
+            parse_tcp_entry as fn(&str) -> Option<Session>,
+        ),
+        ("/proc/net/tcp6", parse_tcp6_entry),
+    ] {
+        match fs::read_to_string(path) {
+            Ok(table) => sessions.extend(table.lines().skip(1).filter_map(parse)),
+            Err(e) => tracing::warn!("couldn't read {} to find management sessions: {}", path, e),
+        }
+    }
+
+    sessions.extend(holepunched);
+    sessions.retain(|session| ports.contains(&session.port));
+
+    if let Some(client) = client_pid.and_then(client_session) {
+        sessions.push(client);
+    }
+
+    // The same session can be in several tables, keep one of each, marked if it's the client's.
+    let mut unique: Vec<Session> = vec![];
+    for session in sessions {
+        match unique.iter_mut().find(|known| {
+            known.source_ip == session.source_ip
+                && known.destination_ip == session.destination_ip
+                && known.port == session.port
+        }) {
+            Some(known) => known.client |= session.client,
+            None => unique.push(session),
+        }
+    }
+
+    // Loopback traffic never passes through the XDP program.
+    unique.retain(|session| !session.source_ip.is_loopback());
+
+    unique
+}
+
+/// Read the TCP connections from the holepunch table that sent traffic recently, as sessions.
+async fn holepunched_sessions(state: &State) -> Vec<Session> {
+    let now = monotonic_usec() * 1_000;
+    let holepunched = state.holepunched.read().await;
+
+    holepunched
+        .iter()
+        .filter_map(|entry| match entry {
+            Ok((connection, last_seen)) => holepunched_session(&connection, last_seen, now),
+            Err(e) => {
+                tracing::warn!("couldn't read the holepunch table: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Turn a connection from the holepunch table into a session, if it's TCP and sent traffic in the
+/// last few minutes. Times are nanoseconds on the monotonic clock.
+fn holepunched_session(connection: &Connection, last_seen: u64, now: u64) -> Option<Session> {
+    if connection.protocol != 6
+        || now.saturating_sub(last_seen) > HOLEPUNCH_IDLE_SECS * 1_000_000_000
+    {
+        return None;
+    }
+
+    Some(Session {
+        source_ip: Ipv4Addr::from(connection.remote_ip.to_ne_bytes()),
+        destination_ip: Ipv4Addr::from(connection.local_ip.to_ne_bytes()),
+        port: u16::from_be(connection.local_port),
+        client: false,
+    })
+}
+
+/// Parse a line of `/proc/net/tcp` into a session, if it's established.
+fn parse_tcp_entry(line: &str) -> Option<Session> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+
+    // The connection state, 01 being ESTABLISHED.
+    if fields.get(3) != Some(&"01") {
+        return None;
+    }
+
+    let (destination_ip, port) = parse_tcp_address(fields.get(1)?)?;
+    let (source_ip, _) = parse_tcp_address(fields.get(2)?)?;
+
+    Some(Session {
+        source_ip,
+        destination_ip,
+        port,
+        client: false,
+    })
+}
+
+/// Parse a line of `/proc/net/tcp6` into a session, if it's established and between IPv4-mapped
+/// addresses, like a session with an SSH server listening on `[::]`.
+fn parse_tcp6_entry(line: &str) -> Option<Session> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+
+    if fields.get(3) != Some(&"01") {
+        return None;
+    }
+
+    let (destination_ip, port) = parse_tcp6_address(fields.get(1)?)?;
+    let (source_ip, _) = parse_tcp6_address(fields.get(2)?)?;
+
+    Some(Session {
+        source_ip,
+        destination_ip,
+        port,
+        client: false,
+    })
+}
+
+/// Parse an address from `/proc/net/tcp6` if it's IPv4-mapped (`::ffff:a.b.c.d`). The IP is
+/// four 32-bit words, each hex in host byte order.
+fn parse_tcp6_address(address: &str) -> Option<(Ipv4Addr, u16)> {
+    let (ip, port) = address.split_once(':')?;
+
+    if ip.len() != 32 || !ip.is_ascii() || &ip[..16] != "0000000000000000" {
+        return None;
+    }
+
+    let mapped = u32::from_str_radix(&ip[16..24], 16).ok()?.to_ne_bytes();
+    if mapped != [0, 0, 0xff, 0xff] {
+        return None;
+    }
+
+    parse_tcp_address(&format!("{}:{}", &ip[24..], port))
+}
+
+/// Parse an address from `/proc/net/tcp`, where the IP is hex in host byte order and the port is
+/// hex.
+fn parse_tcp_address(address: &str) -> Option<(Ipv4Addr, u16)> {
+    let (ip, port) = address.split_once(':')?;
+
+    Some((
+        Ipv4Addr::from(u32::from_str_radix(ip, 16).ok()?.to_ne_bytes()),
+        u16::from_str_radix(port, 16).ok()?,
+    ))
+}
+
+/// Find the SSH session a process belongs to, by walking up its ancestors until one has
+/// `SSH_CONNECTION` set. Walking up the tree means this still works under `sudo`, which drops the
+/// variable.
+fn client_session(pid: i32) -> Option<Session> {
+    let mut pid = pid;
+
+    while pid > 1 {
+        if let Ok(environ) = fs::read(format!("/proc/{}/environ", pid)) {
+            let connection = environ
+                .split(|byte| *byte == 0)
+                .filter_map(|var| std::str::from_utf8(var).ok())
+                .find_map(|var| var.strip_prefix("SSH_CONNECTION="));
+
+            // SSH_CONNECTION is "client_ip client_port server_ip server_port"
+            if let Some(connection) = connection {
+                let parts = connection.split_whitespace().collect::<Vec<_>>();
+
+                return Some(Session {
+                    source_ip: parts.first()?.parse().ok()?,
+                    destination_ip: parts.get(2)?.parse().ok()?,
+                    port: parts.get(3)?.parse().ok()?,
+                    client: true,
+                });
+            }
+        }
+
+        // The parent PID is the field after the parenthesized command name.
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        pid = stat
+            .rsplit_once(')')?
+            .1
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()?;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An address as the kernel writes it in `/proc/net/tcp`
+    fn tcp_address(ip: [u8; 4], port: u16) -> String {
+        format!("{:08X}:{:04X}", u32::from_ne_bytes(ip), port)
+    }
+
+    /// An IPv4-mapped address as the kernel writes it in `/proc/net/tcp6`
+    fn tcp6_address(ip: [u8; 4], port: u16) -> String {
+        format!(
+            "0000000000000000{:08X}{}",
+            u32::from_ne_bytes([0, 0, 0xff, 0xff]),
+            tcp_address(ip, port)
+        )
+    }
+
+    /// A line of a TCP table, with the fields after the state cut short
+    fn tcp_line(local: &str, remote: &str, state: &str) -> String {
+        format!(
+            "   0: {} {} {} 00000000:00000000 00:00000000 00000000     0        0 12345 1",
+            local, remote, state
+        )
+    }
+
+    fn session(source_ip: [u8; 4], port: u16) -> Session {
+        Session {
+            source_ip: Ipv4Addr::from(source_ip),
+            destination_ip: Ipv4Addr::new(10, 0, 0, 1),
+            port,
+            client: false,
+        }
+    }
+
+    fn rule(line: &str) -> Rule {
+        parse_always_allow(line, 1).unwrap()
+    }
+
+    #[test]
+    fn parses_established_tcp_entries() {
+        let line = tcp_line(
+            &tcp_address([10, 0, 0, 1], 22),
+            &tcp_address([192, 168, 1, 5], 51234),
+            "01",
+        );
+
+        assert_eq!(
+            parse_tcp_entry(&line),
+            Some(Session {
+                source_ip: Ipv4Addr::new(192, 168, 1, 5),
+                destination_ip: Ipv4Addr::new(10, 0, 0, 1),
+                port: 22,
+                client: false,
+            })
+        );
+    }
+
+    #[test]
+    fn skips_tcp_entries_that_are_not_established() {
+        // 0A is LISTEN
+        let line = tcp_line(
+            &tcp_address([0, 0, 0, 0], 22),
+            &tcp_address([0, 0, 0, 0], 0),
+            "0A",
+        );
+
+        assert_eq!(parse_tcp_entry(&line), None);
+        assert_eq!(parse_tcp_entry("garbage"), None);
+    }
+
+    #[test]
+    fn parses_ipv4_mapped_tcp6_entries() {
+        let line = tcp_line(
+            &tcp6_address([10, 0, 0, 1], 22),
+            &tcp6_address([192, 168, 1, 5], 51234),
+            "01",
+        );
+
+        assert_eq!(parse_tcp6_entry(&line), Some(session([192, 168, 1, 5], 22)));
+    }
+
+    #[test]
+    fn skips_native_ipv6_tcp6_entries() {
+        let ipv6 = "B80D0120000000000000000001000000:0016";
+        let line = tcp_line(ipv6, ipv6, "01");
+
+        assert_eq!(parse_tcp6_entry(&line), None);
+    }
+
+    #[test]
+    fn parses_always_allow_entries() {
+        let rule = rule("10.0.0.0/8 tcp 22");
+
+        assert_eq!(
+            rule.source_start_ip,
+            u32::from(Ipv4Addr::new(10, 0, 0, 0)).to_be()
+        );
+        assert_eq!(
+            rule.source_end_ip,
+            u32::from(Ipv4Addr::new(10, 255, 255, 255)).to_be()
+        );
+        assert_eq!(rule.protocol_number, 6);
+        assert_eq!(rule.port_number, 22u16.to_be());
+        assert_eq!(rule.ratelimiting, 0);
+    }
+
+    #[test]
+    fn parses_always_allow_entries_without_a_prefix_or_port() {
+        let entry = rule("192.0.2.7");
+
+        assert_eq!(entry.source_start_ip, entry.source_end_ip);
+        assert_eq!(entry.protocol_number, 0);
+        assert_eq!(entry.port_number, 0);
+
+        assert_eq!(rule("0.0.0.0/0").source_end_ip, u32::MAX);
+    }
+
+    #[test]
+    fn rejects_invalid_always_allow_entries() {
+        for line in [
+            "10.0.0.0/33",
+            "10.0.0/8",
+            "10.0.0.0/8 nope",
+            "10.0.0.0/8 tcp port",
+        ] {
+            assert!(parse_always_allow(line, 1).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn matches_sessions_by_source_protocol_and_port() {
+        let ssh = session([10, 1, 2, 3], 22);
+
+        assert!(matches(&rule("10.0.0.0/8"), &ssh));
+        assert!(matches(&rule("10.0.0.0/8 tcp"), &ssh));
+        assert!(matches(&rule("10.0.0.0/8 tcp 22"), &ssh));
+        assert!(!matches(&rule("10.0.0.0/8 tcp 2222"), &ssh));
+        assert!(!matches(&rule("10.0.0.0/8 udp"), &ssh));
+        assert!(!matches(&rule("192.168.0.0/16"), &ssh));
+
+        let mut inner = rule("0.0.0.0/0");
+        inner.match_on = MatchOn::Inner;
+        assert!(!matches(&inner, &ssh));
+    }
+
+    #[test]
+    fn only_the_first_matching_rule_counts() {
+        let ssh = session([10, 1, 2, 3], 22);
+        let mut ratelimited = rule("10.0.0.0/8 tcp 22");
+        ratelimited.id = 5;
+        ratelimited.ratelimiting = 100;
+
+        assert_eq!(drop_reason(&[rule("10.0.0.0/8")], &ssh), None);
+        assert_eq!(
+            drop_reason(&[rule("10.0.0.0/8"), ratelimited.clone()], &ssh),
+            None
+        );
+        assert_eq!(
+            drop_reason(&[ratelimited.clone(), rule("10.0.0.0/8")], &ssh),
+            Some("10.1.2.3 -> 10.0.0.1:22 (ratelimited by rule 5)".to_string())
+        );
+        assert_eq!(
+            drop_reason(&[rule("192.168.0.0/16")], &ssh),
+            Some("10.1.2.3 -> 10.0.0.1:22".to_string())
+        );
+    }
+
+    #[test]
+    fn converts_recent_tcp_holepunched_connections() {
+        let connection = Connection {
+            local_ip: u32::from_ne_bytes([10, 0, 0, 1]),
+            remote_ip: u32::from_ne_bytes([192, 168, 1, 5]),
+            local_port: 22u16.to_be(),
+            remote_port: 51234u16.to_be(),
+            protocol: 6,
+            _padding: [0; 3],
+        };
+        let now = 1_000 * 1_000_000_000;
+
+        assert_eq!(
+            holepunched_session(&connection, now - 1_000_000_000, now),
+            Some(session([192, 168, 1, 5], 22))
+        );
+        assert_eq!(holepunched_session(&connection, 0, now), None);
+        assert_eq!(
+            holepunched_session(
+                &Connection {
+                    protocol: 17,
+                    ..connection
+                },
+                now,
+                now
+            ),
+            None
+        );
+    }
+}
@@ -1,4 +1,6 @@
+use super::state::State;
 use crate::OVERALL_STATE;
+use std::net::Ipv4Addr;
 
 /// Function to manage eBPF maps in the background, such as the ratelimiter. Designed to be run in a task.
 pub async fn manage_maps() {
@@ -23,5 +25,32 @@ pub async fn manage_maps() {
                 }
             }
         }
+
+        // With the ratelimits reset, every source is let through again.
+        state.blocked_sources.write().await.clear();
+    }
+}
+
+/// Unblock a source before the ratelimits reset, starting its count over for every rule it went
+/// over. Returns the IDs of those rules.
+pub async fn unblock(state: &State, source_ip: Ipv4Addr) -> Vec<u32> {
+    let mut rule_map = state.rule_ratelimit_map.write().await;
+    let mut blocked_sources = state.blocked_sources.write().await;
+
+    let rule_ids = blocked_sources
+        .iter()
+        .filter(|blocked| blocked.source_ip == source_ip)
+        .map(|blocked| blocked.rule_id)
+        .collect::<Vec<_>>();
+
+    for rule_id in &rule_ids {
+        // The XDP program keys the count by the sum of the source address, as read from the
+        // packet, and the rule ID. The count may have been evicted already.
+        let key = u32::from_ne_bytes(source_ip.octets()).wrapping_add(*rule_id) as u64;
+        let _ = rule_map.remove(&key);
     }
+
+    blocked_sources.retain(|blocked| blocked.source_ip != source_ip);
+
+    rule_ids
 }
@@ -1,5 +1,15 @@
+pub mod access;
+pub mod api;
+pub mod audit;
+pub mod bootloader;
+pub mod config;
 pub mod ebpf;
+pub mod events;
+pub mod lockout;
 pub mod map_management;
+pub mod packet_log;
 pub mod prometheus;
+pub mod reload;
 pub mod socket;
 pub mod state;
+pub mod systemd;
@@ -0,0 +1,443 @@
+use super::{
+    config::{
+        config,
+        PacketLogOutput,
+    },
+    events::{
+        publish,
+        Event,
+    },
+    state::BlockedSource,
+};
+use crate::OVERALL_STATE;
+use anyhow::Context;
+use aya::maps::{
+    Array,
+    MapData,
+    RingBuf,
+};
+use ghostwire_common::{
+    LogEvent,
+    LogSettings,
+    LOG_REASON_BLOCKED,
+    LOG_REASON_DEFAULT_DROP,
+    LOG_REASON_PASSED,
+    LOG_REASON_RATELIMITED,
+};
+use ghostwire_types::{
+    has_ports,
+    protocol_name,
+    Rule,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    net::Ipv4Addr,
+    os::unix::{
+        fs::OpenOptionsExt,
+        net::UnixDatagram,
+    },
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::{
+    io::unix::AsyncFd,
+    task::JoinHandle,
+};
+
+/// The socket syslog listens on, which journald also reads when it's running
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The socket of journald's native protocol
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// A packet in the packet log, written as a line of JSON
+#[derive(Serialize)]
+struct PacketRecord {
+    /// The Unix timestamp the daemon read the packet at, in seconds
+    timestamp: u64,
+    /// The interface the packet came in on
+    interface: String,
+    /// What happened to the packet, pass or drop
+    action: &'static str,
+    /// Why, rule if a rule let it in, ratelimit if a rule matched but its source went over the
+    /// ratelimit, or default if no rule or holepunched connection let it in
+    reason: &'static str,
+    /// The ID of the rule that matched the packet, if one did
+    rule_id: Option<u32>,
+    /// The name of the rule that matched the packet, if it has one
+    rule_name: Option<String>,
+    /// The protocol name, or its number if it has no name
+    protocol: String,
+    /// The source address
+    source_ip: Ipv4Addr,
+    /// The source port, if the protocol has ports
+    source_port: Option<u16>,
+    /// The destination address
+    destination_ip: Ipv4Addr,
+    /// The destination port, if the protocol has ports
+    destination_port: Option<u16>,
+    /// The number of packets the CPU that saw this one left out of the log since the last one it
+    /// logged, as it went over the cap per second
+    suppressed: u32,
+}
+
+/// The tasks reading the packet log and the blocked sources of the loaded program. They stop when
+/// the state holding them drops, as the firewall is disabled or replaced.
+pub struct PacketLogReader(Vec<JoinHandle<()>>);
+
+impl Drop for PacketLogReader {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Write the packet log settings of the daemon configuration to the eBPF program, and start
+/// reading the packets it logs and the sources it reports blocked.
+pub fn start(
+    mut settings: Array<MapData, LogSettings>,
+    log_ring: RingBuf<MapData>,
+    blocked_ring: RingBuf<MapData>,
+    interface: &str,
+) -> anyhow::Result<PacketLogReader> {
+    let packet_log = &config().packet_log;
+
+    settings
+        .set(
+            0,
+            LogSettings {
+                default_drop: packet_log.default_drops as u32,
+                max_per_second: packet_log.max_per_second,
+            },
+            0,
+        )
+        .context("couldn't write the packet log settings")?;
+
+    Ok(PacketLogReader(vec![
+        read(log_ring, "the packet log", interface)?,
+        read(blocked_ring, "the blocked sources", interface)?,
+    ]))
+}
+
+/// Start reading the events of a ring buffer.
+fn read(
+    ring_buf: RingBuf<MapData>,
+    name: &'static str,
+    interface: &str,
+) -> anyhow::Result<JoinHandle<()>> {
+    let mut ring_buf = AsyncFd::new(ring_buf).context(format!("couldn't watch {}", name))?;
+    let interface = interface.to_string();
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let mut guard = match ring_buf.readable_mut().await {
+                Ok(guard) => guard,
+                Err(e) => {
+                    tracing::error!("Stopped reading {}: {}", name, e);
+
+                    return;
+                }
+            };
+
+            let mut events = vec![];
+            while let Some(item) = guard.get_inner_mut().next() {
+                if item.len() >= std::mem::size_of::<LogEvent>() {
+                    // The ring buffer only aligns entries to 8 bytes.
+                    events.push(unsafe { std::ptr::read_unaligned(item.as_ptr().cast()) });
+                }
+            }
+            guard.clear_ready();
+
+            if let Err(e) = write_events(&interface, &events).await {
+                tracing::error!("Failed to write the packet log: {:#}", e);
+            }
+        }
+    }))
+}
+
+/// Write logged packets to the configured output.
+async fn write_events(interface: &str, events: &[LogEvent]) -> anyhow::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let state = OVERALL_STATE.read().await.state.clone();
+    let rules: HashMap<u32, Rule> = match &state {
+        Some(state) => state
+            .rules
+            .read()
+            .await
+            .iter()
+            .map(|rule| (rule.id, rule.clone()))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+
+    // A source going over a ratelimit is reported whether or not the rule logs, for the hooks and
+    // the blocklist.
+    for event in events {
+        if event.reason == LOG_REASON_BLOCKED {
+            let rule = rules.get(&event.rule_id);
+            let source_ip = Ipv4Addr::from(event.src_ip.to_ne_bytes());
+
+            if let Some(state) = &state {
+                state.blocked_sources.write().await.push(BlockedSource {
+                    source_ip,
+                    rule_id: event.rule_id,
+                    blocked_at: timestamp,
+                });
+            }
+
+            publish(Event::SourceBlocked {
+                interface: interface.to_string(),
+                source_ip,
+                rule_id: event.rule_id,
+                rule_name: rule.and_then(|rule| rule.name.clone()),
+                ratelimit: rule.map_or(0, |rule| rule.ratelimiting),
+            });
+        }
+    }
+
+    let events = events
+        .iter()
+        .filter(|event| logged(event, &rules))
+        .collect::<Vec<_>>();
+
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let lines = events
+        .iter()
+        .map(|event| serde_json::to_string(&record(event, interface, timestamp, &rules)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let packet_log = &config().packet_log;
+
+    match packet_log.output {
+        PacketLogOutput::File => {
+            if let Some(parent) = packet_log.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(&packet_log.path)
+                .context(format!("couldn't open {}", packet_log.path.display()))?;
+
+            file.write_all(format!("{}\n", lines.join("\n")).as_bytes())?;
+        }
+        PacketLogOutput::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(SYSLOG_SOCKET)
+                .context(format!("couldn't connect to {}", SYSLOG_SOCKET))?;
+
+            for line in lines {
+                socket.send(syslog_message(&line, std::process::id()).as_bytes())?;
+            }
+        }
+        PacketLogOutput::Journald => {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(JOURNALD_SOCKET)
+                .context(format!("couldn't connect to {}", JOURNALD_SOCKET))?;
+
+            for line in lines {
+                socket.send(journald_message(&line).as_bytes())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether an event goes to the packet log. The first packet of a source over a ratelimit is sent
+/// whether or not its rule logs, and is only written if it does.
+fn logged(event: &LogEvent, rules: &HashMap<u32, Rule>) -> bool {
+    event.reason != LOG_REASON_BLOCKED || rules.get(&event.rule_id).is_some_and(|rule| rule.log)
+}
+
+/// Format a line of the packet log as a syslog message, in the daemon facility at the info level.
+fn syslog_message(line: &str, pid: u32) -> String {
+    format!("<30>ghostwire[{}]: {}", pid, line)
+}
+
+/// Format a line of the packet log as a message of journald's native protocol. JSON escapes
+/// newlines, so the message fits the simple form of a field.
+fn journald_message(line: &str) -> String {
+    format!(
+        "SYSLOG_IDENTIFIER=ghostwire\nPRIORITY=6\nMESSAGE={}\n",
+        line
+    )
+}
+
+/// Describe a logged packet for the packet log.
+fn record(
+    event: &LogEvent,
+    interface: &str,
+    timestamp: u64,
+    rules: &HashMap<u32, Rule>,
+) -> PacketRecord {
+    let (action, reason) = match event.reason {
+        LOG_REASON_PASSED => ("pass", "rule"),
+        LOG_REASON_RATELIMITED | LOG_REASON_BLOCKED => ("drop", "ratelimit"),
+        LOG_REASON_DEFAULT_DROP => ("drop", "default"),
+        _ => ("drop", "unknown"),
+    };
+    let rule_id = match event.rule_id {
+        0 => None,
+        id => Some(id),
+    };
+    let port = |port: u16| has_ports(event.protocol).then(|| u16::from_be(port));
+
+    PacketRecord {
+        timestamp,
+        interface: interface.to_string(),
+        action,
+        reason,
+        rule_id,
+        rule_name: rule_id.and_then(|id| rules.get(&id)?.name.clone()),
+        protocol: protocol_name(event.protocol),
+        source_ip: Ipv4Addr::from(event.src_ip.to_ne_bytes()),
+        source_port: port(event.src_port),
+        destination_ip: Ipv4Addr::from(event.dst_ip.to_ne_bytes()),
+        destination_port: port(event.dst_port),
+        suppressed: event.suppressed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ghostwire_types::MatchOn;
+
+    fn event(reason: u8, rule_id: u32, protocol: u8) -> LogEvent {
+        LogEvent {
+            rule_id,
+            src_ip: u32::from_ne_bytes([192, 0, 2, 1]),
+            dst_ip: u32::from_ne_bytes([10, 0, 0, 1]),
+            src_port: 51234u16.to_be(),
+            dst_port: 22u16.to_be(),
+            protocol,
+            reason,
+            _padding: [0; 2],
+            suppressed: 0,
+        }
+    }
+
+    fn rules(log: bool) -> HashMap<u32, Rule> {
+        let rule = Rule {
+            id: 7,
+            name: Some("ssh".to_string()),
+            source_start_ip: 0,
+            source_end_ip: u32::MAX,
+            destination_start_ip: 0,
+            destination_end_ip: u32::MAX,
+            protocol_number: 6,
+            match_on: MatchOn::Outer,
+            port_number: 22u16.to_be(),
+            ratelimiting: 100,
+            log,
+        };
+
+        HashMap::from([(7, rule)])
+    }
+
+    /// The packet log line of an event, parsed back
+    fn line(event: &LogEvent, rules: &HashMap<u32, Rule>) -> serde_json::Value {
+        let line = serde_json::to_string(&record(event, "eth0", 1700000000, rules)).unwrap();
+
+        assert!(!line.contains('\n'));
+
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn passed_packets_name_their_rule() {
+        assert_eq!(
+            line(&event(LOG_REASON_PASSED, 7, 6), &rules(true)),
+            serde_json::json!({
+                "timestamp": 1700000000,
+                "interface": "eth0",
+                "action": "pass",
+                "reason": "rule",
+                "rule_id": 7,
+                "rule_name": "ssh",
+                "protocol": "TCP",
+                "source_ip": "192.0.2.1",
+                "source_port": 51234,
+                "destination_ip": "10.0.0.1",
+                "destination_port": 22,
+                "suppressed": 0
+            })
+        );
+    }
+
+    #[test]
+    fn dropped_packets_give_the_reason() {
+        let ratelimited = line(&event(LOG_REASON_RATELIMITED, 7, 6), &rules(true));
+        assert_eq!(ratelimited["action"], "drop");
+        assert_eq!(ratelimited["reason"], "ratelimit");
+
+        let blocked = line(&event(LOG_REASON_BLOCKED, 7, 6), &rules(true));
+        assert_eq!(blocked["reason"], "ratelimit");
+
+        let mut dropped = event(LOG_REASON_DEFAULT_DROP, 0, 6);
+        dropped.suppressed = 12;
+        let dropped = line(&dropped, &rules(true));
+        assert_eq!(dropped["action"], "drop");
+        assert_eq!(dropped["reason"], "default");
+        assert_eq!(dropped["rule_id"], serde_json::Value::Null);
+        assert_eq!(dropped["rule_name"], serde_json::Value::Null);
+        assert_eq!(dropped["suppressed"], 12);
+    }
+
+    #[test]
+    fn protocols_without_ports_have_no_ports() {
+        let icmp = line(&event(LOG_REASON_DEFAULT_DROP, 0, 1), &HashMap::new());
+        assert_eq!(icmp["protocol"], "ICMP");
+        assert_eq!(icmp["source_port"], serde_json::Value::Null);
+        assert_eq!(icmp["destination_port"], serde_json::Value::Null);
+
+        let unnamed = line(&event(LOG_REASON_DEFAULT_DROP, 0, 253), &HashMap::new());
+        assert_eq!(unnamed["protocol"], "253");
+    }
+
+    #[test]
+    fn blocked_sources_are_only_logged_by_logging_rules() {
+        let blocked = event(LOG_REASON_BLOCKED, 7, 6);
+
+        assert!(logged(&blocked, &rules(true)));
+        assert!(!logged(&blocked, &rules(false)));
+        assert!(!logged(&blocked, &HashMap::new()));
+        assert!(logged(&event(LOG_REASON_RATELIMITED, 7, 6), &rules(false)));
+    }
+
+    #[test]
+    fn formats_syslog_and_journald_messages() {
+        let line = r#"{"action":"drop"}"#;
+
+        assert_eq!(
+            syslog_message(line, 42),
+            r#"<30>ghostwire[42]: {"action":"drop"}"#
+        );
+        assert_eq!(
+            journald_message(line),
+            "SYSLOG_IDENTIFIER=ghostwire\nPRIORITY=6\nMESSAGE={\"action\":\"drop\"}\n"
+        );
+    }
+}
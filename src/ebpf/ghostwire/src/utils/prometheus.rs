@@ -18,10 +18,12 @@ use prometheus::{
     Registry,
     TextEncoder,
 };
-use std::net::SocketAddr;
 use tokio::net::TcpListener;
 
-use super::state::PromCounters;
+use super::{
+    config::config,
+    state::PromCounters,
+};
 
 /// Create the Prometheus counters and Registry.
 pub fn create_prometheus_counters() -> anyhow::Result<PromCounters> {
@@ -32,7 +34,7 @@ pub fn create_prometheus_counters() -> anyhow::Result<PromCounters> {
             "gw_rule_evaluated",
             "The number of times a rule was evaluated",
         ),
-        &["rule_id"],
+        &["rule_id", "name"],
     )?;
     registry.register(Box::new(rule_evaluated.clone()))?;
 
@@ -41,7 +43,7 @@ pub fn create_prometheus_counters() -> anyhow::Result<PromCounters> {
             "gw_rule_passed",
             "The number of times a rule allowed traffic",
         ),
-        &["rule_id"],
+        &["rule_id", "name"],
     )?;
     registry.register(Box::new(rule_passed.clone()))?;
 
@@ -74,31 +76,37 @@ pub async fn prometheus_metrics() {
     let overall_state = OVERALL_STATE.read().await;
 
     if let Some(state) = &overall_state.state {
-        for (key, value) in state.rule_analytic_map.iter().flatten() {
+        let names = state.rule_names().await;
+
+        for (key, value) in state.rule_analytic_map.read().await.iter().flatten() {
+            // Rules without a name, like the always-allow list, get an empty name label.
+            let id = key.to_string();
+            let labels = [id.as_str(), names.get(&key).map_or("", String::as_str)];
+
             let evaluated_diff = value.evaluated
                 - overall_state
                     .counters
                     .rule_evaluated
-                    .with_label_values(&[&key.to_string()])
+                    .with_label_values(&labels)
                     .get() as u128;
 
             overall_state
                 .counters
                 .rule_evaluated
-                .with_label_values(&[&key.to_string()])
+                .with_label_values(&labels)
                 .inc_by(evaluated_diff as u64);
 
             let passed_diff = value.passed
                 - overall_state
                     .counters
                     .rule_passed
-                    .with_label_values(&[&key.to_string()])
+                    .with_label_values(&labels)
                     .get() as u128;
 
             overall_state
                 .counters
                 .rule_passed
-                .with_label_values(&[&key.to_string()])
+                .with_label_values(&labels)
                 .inc_by(passed_diff as u64);
         }
 
@@ -122,7 +130,7 @@ pub async fn prometheus_metrics() {
 
 /// Convert an XDP action to a string.
 /// @see https://docs.aya-rs.dev/aya_ebpf/bindings/xdp_action/
-fn xdp_action_to_string(action: u32) -> &'static str {
+pub fn xdp_action_to_string(action: u32) -> &'static str {
     match action {
         0 => "XDP_ABORTED",
         1 => "XDP_DROP",
@@ -134,7 +142,7 @@ fn xdp_action_to_string(action: u32) -> &'static str {
 }
 
 /// Convert a TC action to a string.
-fn tc_action_to_string(action: i32) -> &'static str {
+pub fn tc_action_to_string(action: i32) -> &'static str {
     match action {
         0 => "TC_ACT_OK",
         2 => "TC_ACT_SHOT",
@@ -144,14 +152,13 @@ fn tc_action_to_string(action: i32) -> &'static str {
 }
 
 pub async fn handle_prom_listener() -> anyhow::Result<()> {
-    // TODO: implement different ports
-    let socket_addr: SocketAddr = "127.0.0.1:4343".parse()?;
+    let socket_addr = config().prometheus_address;
 
     let listener = TcpListener::bind(socket_addr)
         .await
-        .context("could not listen on port 4343")?;
+        .context(format!("could not listen on {}", socket_addr))?;
 
-    tracing::info!("Prometheus is starting on port 4343");
+    tracing::info!("Prometheus is starting on {}", socket_addr);
 
     loop {
         let (stream, _ip) = listener.accept().await?;
@@ -0,0 +1,223 @@
+use super::{
+    config::config,
+    socket::handle_reload,
+    systemd::notify,
+};
+use anyhow::Context;
+use ghostwire_types::yaml::ConfigFiles;
+use std::{
+    ffi::{
+        CString,
+        OsStr,
+    },
+    io,
+    mem::size_of,
+    os::{
+        fd::{
+            AsRawFd,
+            FromRawFd,
+            OwnedFd,
+        },
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    time::Duration,
+};
+use tokio::{
+    io::unix::AsyncFd,
+    signal::unix::{
+        signal,
+        SignalKind,
+    },
+    time::timeout,
+};
+
+/// The changes to a directory that can change the rules configuration in it, including editors
+/// and configuration management writing a file elsewhere and moving it over the old one
+const WATCHED_EVENTS: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVED_FROM
+    | libc::IN_CREATE
+    | libc::IN_DELETE;
+
+/// Read the rules configuration, check it like the CLI does and apply it, logging the result. A
+/// configuration that doesn't read or check leaves the rules as they are.
+pub async fn reload(trigger: &str) {
+    let Some(path) = &config().rules_config else {
+        tracing::warn!(
+            "Got {} but no rules_config is set, nothing to reload",
+            trigger
+        );
+        return;
+    };
+
+    match reload_fallible(path).await {
+        Ok(message) => tracing::info!("Reloaded {} on {}: {}", path.display(), trigger, message),
+        Err(e) => tracing::error!(
+            "Didn't reload {} on {}, the rules are unchanged: {:#}",
+            path.display(),
+            trigger,
+            e
+        ),
+    }
+}
+
+/// Read and check every file of the rules configuration before applying any of it.
+async fn reload_fallible(path: &Path) -> anyhow::Result<String> {
+    let path_str = path
+        .to_str()
+        .context(format!("{} isn't valid UTF-8", path.display()))?;
+
+    let files = match path.is_dir() {
+        true => ConfigFiles::read_dir(path_str)?,
+        false => ConfigFiles::read(path_str)?,
+    };
+    let rules_config = files.config()?;
+
+    handle_reload(
+        rules_config.rules,
+        rules_config.interface,
+        rules_config.persistent,
+    )
+    .await
+}
+
+/// Reload the rules configuration on each SIGHUP, telling systemd about it so `systemctl reload`
+/// waits for it. Designed to be run as a task.
+pub async fn reload_on_hangup() -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+
+        reload("SIGHUP").await;
+
+        notify("READY=1");
+    }
+
+    Ok(())
+}
+
+/// The time on the monotonic clock in microseconds. systemd matches reload notifications by it, and
+/// the eBPF programs timestamp connections with the same clock.
+pub fn monotonic_usec() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+
+    time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1_000
+}
+
+/// Reload the rules configuration whenever it changes on disk, once it has gone unchanged for the
+/// debounce time so a half written file or a batch of files is applied once. Does nothing unless
+/// the watch is turned on. Designed to be run as a task.
+pub async fn watch_rules_config() {
+    let Some(path) = &config().rules_config else {
+        return;
+    };
+
+    if !config().watch_rules_config {
+        return;
+    }
+
+    if let Err(e) = watch_fallible(path).await {
+        tracing::error!("Stopped watching {}: {:#}", path.display(), e);
+    }
+}
+
+/// Watch the directory of the rules configuration, or the directory itself if it's a conf.d, with
+/// inotify. Watching the directory rather than the file keeps up with files replaced by moving a
+/// new one over them. Files included from other directories aren't watched.
+async fn watch_fallible(path: &Path) -> anyhow::Result<()> {
+    let (dir, name) = match path.is_dir() {
+        true => (path, None),
+        false => (
+            path.parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+            path.file_name(),
+        ),
+    };
+
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("couldn't start inotify");
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let dir_str = CString::new(dir.as_os_str().as_bytes())?;
+    if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir_str.as_ptr(), WATCHED_EVENTS) } < 0 {
+        return Err(io::Error::last_os_error())
+            .context(format!("couldn't watch {}", dir.display()));
+    }
+
+    let inotify = AsyncFd::new(fd)?;
+    let debounce = Duration::from_millis(config().watch_debounce);
+
+    tracing::info!("Watching {} for changes", path.display());
+
+    loop {
+        wait_for_change(&inotify, name).await?;
+
+        // Wait for the changes to settle.
+        while let Ok(result) = timeout(debounce, wait_for_change(&inotify, name)).await {
+            result?;
+        }
+
+        reload("a change on disk").await;
+    }
+}
+
+/// Wait until inotify reports a change to the rules configuration. With a file, that's a change to
+/// a file of the same name, and with a directory, a change to any of its .yml or .yaml files.
+async fn wait_for_change(inotify: &AsyncFd<OwnedFd>, name: Option<&OsStr>) -> io::Result<()> {
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        let mut guard = inotify.readable().await?;
+
+        let read = match guard.try_io(|inner| {
+            let read =
+                unsafe { libc::read(inner.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+
+            match read {
+                ..0 => Err(io::Error::last_os_error()),
+                read => Ok(read as usize),
+            }
+        }) {
+            Ok(result) => result?,
+            Err(_would_block) => continue,
+        };
+
+        let mut offset = 0;
+
+        while offset + size_of::<libc::inotify_event>() <= read {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+            let start = offset + size_of::<libc::inotify_event>();
+            let end = (start + event.len as usize).min(read);
+
+            // The name is padded with zeros.
+            let event_name = buffer[start..end]
+                .split(|byte| *byte == 0)
+                .next()
+                .unwrap_or(&[]);
+
+            offset = end;
+
+            let changed = match name {
+                Some(name) => event_name == name.as_bytes(),
+                None => event_name.ends_with(b".yml") || event_name.ends_with(b".yaml"),
+            };
+
+            if changed {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -1,95 +1,351 @@
-use super::ebpf::{
-    load_ebpf,
-    unload_ebpf,
+use super::{
+    access::{
+        prepare_socket_dir,
+        restrict_socket,
+        Peer,
+    },
+    audit::{
+        self,
+        read_page,
+        snapshot,
+    },
+    bootloader::{
+        clear_state,
+        persist,
+    },
+    config::config,
+    ebpf::{
+        add_rule,
+        delete_rule,
+        highest_rule_id,
+        load_ebpf,
+        replace_rules,
+        unload_ebpf,
+        update_rule,
+    },
+    events::{
+        self,
+        Event,
+    },
+    lockout::check_lockout,
+    state::{
+        PendingConfirm,
+        State,
+    },
+    systemd::activated_socket,
 };
 use crate::OVERALL_STATE;
+use anyhow::Context;
 use ghostwire_types::{
+    AuditQuery,
+    AuditRecord,
     ClientMessage,
     ClientReqType,
     Rule,
     ServerMessage,
+    MAX_MESSAGE_SIZE,
+    PROTOCOL_VERSION,
+    RESERVED_RULE_IDS,
 };
 use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
     io::{
-        Read,
-        Write,
+        AsyncBufReadExt,
+        AsyncReadExt,
+        AsyncWriteExt,
+        BufReader,
     },
-    os::unix::net::{
+    net::{
         UnixListener,
         UnixStream,
     },
+    sync::{
+        Mutex,
+        OwnedSemaphorePermit,
+        Semaphore,
+    },
+    task,
+    time::{
+        timeout,
+        Instant,
+    },
 };
 
-/// Listen on the socket for client requests from the CLI
-pub async fn socket_server() -> anyhow::Result<()> {
-    // delete a socket that could exist currently
-    let _ = std::fs::remove_file("/tmp/ghostwire.sock");
+/// Identifies each load waiting for confirmation
+static CONFIRM_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Held while a request changes the state of the firewall, so concurrent clients (and the rollback
+/// timer) can't interleave their changes
+static MANAGEMENT_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// The number of audit records sent when the client doesn't ask for a page
+const DEFAULT_AUDIT_PAGE: usize = 20;
+
+/// What a successful request is answered with
+#[derive(Default)]
+struct Reply {
+    /// The message for the client
+    message: String,
+    /// The audit records, on an AUDIT request
+    audit: Option<Vec<AuditRecord>>,
+    /// The applied rules, on a LIST request
+    rules: Option<Vec<Rule>>,
+    /// The interface the rules are applied to, on a LIST request
+    interface: Option<String>,
+}
 
-    let listener = UnixListener::bind("/tmp/ghostwire.sock").expect("Failed to bind socket");
+impl From<String> for Reply {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            ..Default::default()
+        }
+    }
+}
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                if let Err(e) = handle_stream(stream).await {
-                    tracing::error!("Failed to handle stream: {:?}", e);
-                };
+/// Listen on the socket for client requests from the CLI
+pub async fn socket_server(listener: UnixListener) -> anyhow::Result<()> {
+    let clients = Arc::new(Semaphore::new(config().max_clients));
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                // Past the client limit, the connection is still read from so it can be told
+                // why it's refused.
+                let permit = clients.clone().try_acquire_owned().ok();
+
+                task::spawn(async move {
+                    if let Err(e) = handle_stream(stream, permit).await {
+                        tracing::error!("Failed to handle stream: {:?}", e);
+                    };
+                });
             }
             Err(err) => {
                 tracing::error!("Failed to accept connection: {:?}", err);
             }
         }
     }
-
-    Ok(())
 }
 
-/// Run the listener and handle new messages
-async fn handle_stream(mut stream: UnixStream) -> anyhow::Result<()> {
-    let mut buffer = [0; 1024];
+/// Listen on the socket systemd passed if the daemon was socket activated, or bind the configured
+/// socket. systemd sets up the permissions of a socket it passes.
+pub fn bind_socket() -> anyhow::Result<UnixListener> {
+    if let Some(fd) = activated_socket() {
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener.set_nonblocking(true)?;
 
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            let received_data = &buffer[..size];
-            match serde_json::from_slice::<ClientMessage>(received_data) {
-                Ok(message) => {
-                    handle_server_request(message, stream).await?;
-                }
-                Err(err) => {
-                    anyhow::bail!("Failed to parse JSON: {}", err);
-                }
+        tracing::info!("Listening on the socket passed by systemd");
+
+        return UnixListener::from_std(listener)
+            .context("the socket passed by systemd isn't a UNIX socket");
+    }
+
+    let path = &config().socket;
+
+    prepare_socket_dir(path)?;
+
+    // delete a socket that could exist currently
+    let _ = std::fs::remove_file(path);
+
+    let listener =
+        UnixListener::bind(path).context(format!("failed to bind socket {}", path.display()))?;
+
+    restrict_socket(path).context("couldn't restrict access to the socket")?;
+
+    Ok(listener)
+}
+
+/// Handle the requests of a connection until the client hangs up. A connection without a permit
+/// is over the client limit, and its request is refused.
+async fn handle_stream(
+    stream: UnixStream,
+    permit: Option<OwnedSemaphorePermit>,
+) -> anyhow::Result<()> {
+    let client_timeout = Duration::from_secs(config().client_timeout);
+    let peer = Peer::from_cred(
+        &stream
+            .peer_cred()
+            .context("couldn't get the credentials of the client")?,
+    );
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+
+    loop {
+        // Messages are newline-delimited JSON. Reading until a newline keeps what was read when
+        // the timeout cancels it.
+        let mut line = Vec::new();
+
+        let size = match timeout(
+            client_timeout,
+            (&mut reader)
+                .take(MAX_MESSAGE_SIZE)
+                .read_until(b'\n', &mut line),
+        )
+        .await
+        {
+            Ok(result) => {
+                result.map_err(|err| anyhow::anyhow!("Failed to read from socket: {}", err))?
             }
+            // A client from before versioning sends its message without a newline and waits for
+            // the answer, so it's answered with what it sent, which the version check refuses.
+            Err(_) if !line.is_empty() => line.len(),
+            Err(_) => anyhow::bail!("Client didn't send a request in time"),
+        };
+
+        // The client hung up.
+        if size == 0 {
+            return Ok(());
         }
-        Err(err) => {
-            anyhow::bail!("Failed to read from socket: {}", err);
+
+        // A message that didn't end in a newline came from a client that half-closed the socket or
+        // stopped sending, either way it's the last one.
+        let last = !line.ends_with(b"\n");
+
+        let message = serde_json::from_slice::<ClientMessage>(&line)
+            .map_err(|err| anyhow::anyhow!("Failed to parse JSON: {}", err))?;
+
+        let resp = match permit {
+            Some(_) => handle_server_request(message, &peer).await,
+            None => response(
+                message.request_id,
+                Err(anyhow::anyhow!(
+                    "too many clients are connected to the server, try again later"
+                )),
+            ),
+        };
+
+        let mut response_data = serde_json::to_vec(&resp)?;
+        response_data.push(b'\n');
+
+        timeout(client_timeout, write.write_all(&response_data))
+            .await
+            .map_err(|_| anyhow::anyhow!("Client didn't read the response in time"))??;
+
+        if permit.is_none() || last {
+            return Ok(());
         }
     }
+}
 
-    Ok(())
+/// Handle a message from a socket or HTTP API client.
+pub async fn handle_server_request(message: ClientMessage, peer: &Peer) -> ServerMessage {
+    let request_id = message.request_id;
+
+    tracing::debug!("Handling {:?} request {}", message.req_type, request_id);
+
+    let result = match message.version {
+        PROTOCOL_VERSION => match peer.authorize(&message.req_type) {
+            Ok(()) => handle_server_request_fallible(message, peer).await,
+            Err(e) => {
+                tracing::warn!(
+                    "Refused {:?} request {} from {}: {}",
+                    message.req_type,
+                    request_id,
+                    peer,
+                    e
+                );
+
+                let result = Err(e);
+
+                // Attempts to change the firewall are audited even when they're refused.
+                if changes_firewall(message.req_type) {
+                    let current = snapshot().await;
+
+                    audit::record(
+                        Some(peer),
+                        request_id,
+                        &format!("{:?}", message.req_type),
+                        &current,
+                        &current,
+                        &result,
+                    );
+                }
+
+                result.map(Reply::from)
+            }
+        },
+        version => Err(anyhow::anyhow!(
+            "the CLI speaks protocol version {}, but the server speaks version {}. Use a CLI that matches the server",
+            version,
+            PROTOCOL_VERSION
+        )),
+    };
+
+    response(request_id, result)
 }
 
-/// Handle a message from the socket client.
-async fn handle_server_request(
-    message: ClientMessage,
-    mut stream: UnixStream,
-) -> anyhow::Result<()> {
-    let resp = match handle_server_request_fallible(message).await {
-        Ok(t) => t,
+/// Build the response to a request from its result.
+fn response(request_id: u64, result: anyhow::Result<Reply>) -> ServerMessage {
+    match result {
+        Ok(reply) => ServerMessage {
+            version: PROTOCOL_VERSION,
+            request_id,
+            request_success: true,
+            message: reply.message,
+            audit: reply.audit,
+            rules: reply.rules,
+            interface: reply.interface,
+        },
         Err(e) => ServerMessage {
+            version: PROTOCOL_VERSION,
+            request_id,
             request_success: false,
             message: format!("{}", e),
+            audit: None,
+            rules: None,
+            interface: None,
         },
-    };
+    }
+}
 
-    let response_data = serde_json::to_vec(&resp)?;
-    stream.write_all(&response_data)?;
+/// Whether a request changes the firewall, rather than only looking at it
+fn changes_firewall(req_type: ClientReqType) -> bool {
+    !matches!(
+        req_type,
+        ClientReqType::STATUS | ClientReqType::AUDIT | ClientReqType::LIST
+    )
+}
 
-    Ok(())
+/// Once parsed to a ClientMessage, handle the request, returning the reply for the client. Changes
+/// to the firewall are recorded in the audit log.
+async fn handle_server_request_fallible(
+    message: ClientMessage,
+    peer: &Peer,
+) -> anyhow::Result<Reply> {
+    match message.req_type {
+        ClientReqType::STATUS => return handle_status_request().await.map(Reply::from),
+        ClientReqType::AUDIT => return handle_audit(message.audit_query),
+        ClientReqType::LIST => return handle_list().await,
+        _ => {}
+    }
+
+    let _management = MANAGEMENT_LOCK.lock().await;
+
+    let request_id = message.request_id;
+    let action = format!("{:?}", message.req_type);
+
+    let before = snapshot().await;
+    let result = handle_change(message, peer).await;
+    let after = snapshot().await;
+
+    audit::record(Some(peer), request_id, &action, &before, &after, &result);
+
+    result.map(Reply::from)
 }
 
-/// Once parsed to a ClientMessage, handle the request
-async fn handle_server_request_fallible(message: ClientMessage) -> anyhow::Result<ServerMessage> {
+/// Handle a request that changes the firewall. The caller holds the management lock.
+async fn handle_change(message: ClientMessage, peer: &Peer) -> anyhow::Result<String> {
     match message.req_type {
-        ClientReqType::STATUS => handle_status_request().await,
         ClientReqType::RULES => {
             handle_load(
                 message.rules.ok_or(anyhow::anyhow!(
@@ -98,9 +354,14 @@ async fn handle_server_request_fallible(message: ClientMessage) -> anyhow::Resul
                 message.interface.ok_or(anyhow::anyhow!(
                     "request to change rules didn't include the interface"
                 ))?,
+                message.confirm_within,
+                message.force,
+                message.persistent.unwrap_or(true),
+                peer.pid,
             )
             .await
         }
+        ClientReqType::CONFIRM => handle_confirm().await,
         ClientReqType::ENABLE => {
             handle_enable(message.interface.ok_or(anyhow::anyhow!(
                 "enable message didn't include the interface"
@@ -108,67 +369,495 @@ async fn handle_server_request_fallible(message: ClientMessage) -> anyhow::Resul
             .await
         }
         ClientReqType::DISABLE => handle_disable().await,
+        ClientReqType::ADD => {
+            handle_add(
+                message
+                    .rule
+                    .ok_or(anyhow::anyhow!("request to add a rule didn't include it"))?,
+                message.persistent.unwrap_or(true),
+            )
+            .await
+        }
+        ClientReqType::UPDATE => {
+            handle_update(
+                message.rule_id.ok_or(anyhow::anyhow!(
+                    "request to update a rule didn't include its ID"
+                ))?,
+                message.rule.ok_or(anyhow::anyhow!(
+                    "request to update a rule didn't include the new rule"
+                ))?,
+                message.force,
+                message.persistent.unwrap_or(true),
+                peer.pid,
+            )
+            .await
+        }
+        ClientReqType::DELETE => {
+            handle_delete(
+                message.rule_id.ok_or(anyhow::anyhow!(
+                    "request to delete a rule didn't include its ID"
+                ))?,
+                message.force,
+                message.persistent.unwrap_or(true),
+                peer.pid,
+            )
+            .await
+        }
+        req_type => anyhow::bail!("{:?} requests don't change the firewall", req_type),
+    }
+}
+
+/// Apply a ruleset read from the configuration file of the rules, like a client's load without a
+/// confirmation. Rules that would drop the established management sessions are refused, and the
+/// reload is recorded in the audit log.
+pub async fn handle_reload(
+    rules: Vec<Rule>,
+    interface: String,
+    persistent: bool,
+) -> anyhow::Result<String> {
+    let _management = MANAGEMENT_LOCK.lock().await;
+
+    if OVERALL_STATE.read().await.pending_confirm.is_some() {
+        anyhow::bail!(
+            "the last load is waiting for confirmation, confirm it or wait for the rollback before reloading"
+        );
     }
+
+    let before = snapshot().await;
+    let result = handle_load(rules, interface, None, false, persistent, None).await;
+    let after = snapshot().await;
+
+    audit::record(None, 0, "RELOAD", &before, &after, &result);
+
+    result
 }
 
 /// Handle a status request from the client
-async fn handle_status_request() -> anyhow::Result<ServerMessage> {
+async fn handle_status_request() -> anyhow::Result<String> {
     let overall_status = OVERALL_STATE.read().await;
 
-    Ok(ServerMessage {
-        request_success: true,
-        message: overall_status.fmt().await,
+    Ok(overall_status.fmt().await)
+}
+
+/// Handle a request for the applied rules
+async fn handle_list() -> anyhow::Result<Reply> {
+    let overall_state = OVERALL_STATE.read().await;
+
+    let Some(state) = &overall_state.state else {
+        return Ok(Reply {
+            message: "Ghostwire is disabled, no rules are applied".to_string(),
+            rules: Some(vec![]),
+            ..Default::default()
+        });
+    };
+
+    let rules = state.rules.read().await.clone();
+
+    Ok(Reply {
+        message: format!("{} rules applied on {}", rules.len(), state.interface),
+        rules: Some(rules),
+        interface: Some(state.interface.clone()),
+        ..Default::default()
     })
 }
 
-/// Handle the modification of rules. The client will send the full list of rules, to which we will
-/// replace the map.
-async fn handle_load(rules: Vec<Rule>, interface: String) -> anyhow::Result<ServerMessage> {
-    // Find if the firewall is enabled.
-    let mut enabled = false;
+/// Handle a request for a page of the audit log
+fn handle_audit(query: Option<AuditQuery>) -> anyhow::Result<Reply> {
+    let query = query.unwrap_or(AuditQuery {
+        skip: 0,
+        limit: DEFAULT_AUDIT_PAGE,
+    });
+
+    let (records, total) = read_page(query)?;
+
+    let message = match records.len() {
+        0 => format!("No audit records to show, out of {}", total),
+        len => format!(
+            "Showing audit records {} to {} of {}, newest first",
+            query.skip + 1,
+            query.skip + len,
+            total
+        ),
+    };
 
-    {
-        enabled = OVERALL_STATE.read().await.enabled;
+    Ok(Reply {
+        message,
+        audit: Some(records),
+        ..Default::default()
+    })
+}
+
+/// Handle the modification of rules. The client will send the full list of rules, to which we will
+/// replace the map. If the client asks to confirm the rules, the previous ruleset is restored unless
+/// a CONFIRM request arrives in time. Unless forced, rules that would drop the established
+/// management sessions are refused. Persistent rules are saved to be applied again on startup once
+/// they're accepted.
+async fn handle_load(
+    mut rules: Vec<Rule>,
+    interface: String,
+    confirm_within: Option<u64>,
+    force: bool,
+    persistent: bool,
+    client_pid: Option<i32>,
+) -> anyhow::Result<String> {
+    if confirm_within == Some(0) {
+        anyhow::bail!("the confirmation timeout must be at least a second");
     }
 
-    if !enabled {
-        handle_enable(interface).await?;
+    let state = OVERALL_STATE.read().await.state.clone();
+    let applied = match &state {
+        Some(state) => state.rules.read().await.clone(),
+        None => vec![],
+    };
+
+    assign_ids(&mut rules, &applied)?;
+
+    if !force {
+        check_lockout(state.as_deref(), &rules, client_pid).await?;
     }
 
-    let overall_status = OVERALL_STATE.read().await;
+    // Snapshot the ruleset to restore if this load isn't confirmed. If a confirmation is already
+    // pending, keep its snapshot instead, as that's the last ruleset the client confirmed.
+    let previous = {
+        let overall_state = OVERALL_STATE.read().await;
 
-    match &overall_status.state {
-        Some(state) => {
-            // eBPF maps are super limited in what they can do in comparison to a HashMap from the standard
-            // library, so instead of being able to clear the map,
-            // we'll have to sauce it up
-            let mut map = state.rule_map.write().await;
+        match (&overall_state.pending_confirm, &overall_state.state) {
+            (Some(pending), _) => pending.previous.clone(),
+            (None, Some(state)) => {
+                Some((state.rules.read().await.clone(), state.interface.clone()))
+            }
+            (None, None) => None,
+        }
+    };
 
-            let map_len = map.iter().collect::<Vec<_>>().len();
+    apply_ruleset(rules.clone(), interface.clone()).await?;
 
-            // we use index as key
-            for i in 0..map_len {
-                map.remove(&(i as u32))?;
+    let Some(confirm_within) = confirm_within else {
+        OVERALL_STATE.write().await.pending_confirm = None;
+
+        persist(persistent, &rules, &interface)?;
+
+        return Ok("Rules updated".to_string());
+    };
+
+    let id = CONFIRM_ID.fetch_add(1, Ordering::Relaxed);
+    let timeout = Duration::from_secs(confirm_within);
+
+    OVERALL_STATE.write().await.pending_confirm = Some(PendingConfirm {
+        id,
+        previous,
+        persistent,
+        deadline: Instant::now() + timeout,
+    });
+
+    tokio::task::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        rollback(id).await;
+    });
+
+    Ok(format!(
+        "Rules updated, run `gw confirm` within {} seconds to keep them",
+        confirm_within
+    ))
+}
+
+/// Give the rules sent without an ID one. A rule matching the same traffic as an applied rule takes
+/// its ID, so its metrics and ratelimits carry on, and any other rule takes an ID no rule had.
+fn assign_ids(rules: &mut [Rule], applied: &[Rule]) -> anyhow::Result<()> {
+    let mut taken = HashSet::new();
+
+    for rule in rules.iter().filter(|rule| rule.id != 0) {
+        check_id(rule.id)?;
+
+        if !taken.insert(rule.id) {
+            anyhow::bail!("Rule ID {} is used more than once", rule.id);
+        }
+    }
+
+    let mut next = applied
+        .iter()
+        .chain(rules.iter())
+        .map(|rule| rule.id)
+        .chain([highest_rule_id()])
+        .max()
+        .unwrap_or(0);
+
+    // Rules saved before IDs were assigned this way can have an ID of 0, which isn't reused.
+    for rule in rules.iter_mut().filter(|rule| rule.id == 0) {
+        let reused = applied.iter().find(|applied| {
+            applied.id != 0 && applied.same_traffic(rule) && !taken.contains(&applied.id)
+        });
+
+        rule.id = match reused {
+            Some(applied) => applied.id,
+            None => {
+                next = next_id(next)?;
+                next
             }
+        };
+
+        taken.insert(rule.id);
+    }
+
+    Ok(())
+}
+
+/// The ID after one, if it's below the IDs reserved for the always-allow list.
+fn next_id(id: u32) -> anyhow::Result<u32> {
+    id.checked_add(1)
+        .filter(|next| *next < RESERVED_RULE_IDS)
+        .context(format!(
+            "There are no rule IDs left below {}, which are reserved for the always-allow list",
+            RESERVED_RULE_IDS
+        ))
+}
 
-            // insert the new rules
-            for (i, rule) in rules.iter().enumerate() {
-                map.insert(i as u32, convert_rule(*rule), 0)?;
+/// Refuse an ID the client picked if it's reserved for the always-allow list.
+fn check_id(id: u32) -> anyhow::Result<()> {
+    if id >= RESERVED_RULE_IDS {
+        anyhow::bail!(
+            "Rule ID {} is reserved for the always-allow list, pick an ID below {}",
+            id,
+            RESERVED_RULE_IDS
+        );
+    }
+
+    Ok(())
+}
+
+/// Get the state to change single rules of. A ruleset has to be applied, and confirmed if it was
+/// loaded with a confirmation, since a rollback would undo the change.
+async fn rule_state() -> anyhow::Result<Arc<State>> {
+    let overall_state = OVERALL_STATE.read().await;
+
+    if overall_state.pending_confirm.is_some() {
+        anyhow::bail!(
+            "The last load is waiting for confirmation, confirm it or wait for the rollback before changing single rules"
+        );
+    }
+
+    overall_state
+        .state
+        .clone()
+        .context("The firewall is disabled, load a ruleset first")
+}
+
+/// Save the applied rules after a change to a single rule. A change that isn't persistent leaves
+/// the saved ruleset as it was, so it's gone after a restart.
+async fn persist_rules(state: &State, persistent: bool) -> anyhow::Result<()> {
+    match persistent {
+        true => persist(true, &state.rules.read().await, &state.interface),
+        false => Ok(()),
+    }
+}
+
+/// Handle the addition of a single rule after the applied ones. A rule sent without an ID gets the
+/// next free one.
+async fn handle_add(mut rule: Rule, persistent: bool) -> anyhow::Result<String> {
+    let state = rule_state().await?;
+
+    {
+        let applied = state.rules.read().await;
+
+        match rule.id {
+            0 => {
+                rule.id = next_id(
+                    applied
+                        .iter()
+                        .map(|rule| rule.id)
+                        .chain([highest_rule_id()])
+                        .max()
+                        .unwrap_or(0),
+                )?
             }
+            id if applied.iter().any(|applied| applied.id == id) => {
+                anyhow::bail!("Rule {} already exists, update it instead", id)
+            }
+            id => check_id(id)?,
+        }
+    }
+
+    let id = rule.id;
+
+    add_rule(&state, rule).await?;
+    persist_rules(&state, persistent).await?;
+
+    Ok(format!("Rule {} added", id))
+}
 
-            Ok(ServerMessage {
-                request_success: true,
-                message: "Rules updated".to_string(),
+/// Handle the replacement of a single rule, keeping its ID and position. Unless forced, the change
+/// is refused if it'd drop the established management sessions.
+async fn handle_update(
+    id: u32,
+    mut rule: Rule,
+    force: bool,
+    persistent: bool,
+    client_pid: Option<i32>,
+) -> anyhow::Result<String> {
+    let state = rule_state().await?;
+
+    rule.id = id;
+
+    if !force {
+        let rules = state
+            .rules
+            .read()
+            .await
+            .iter()
+            .map(|applied| match applied.id == id {
+                true => rule.clone(),
+                false => applied.clone(),
             })
+            .collect::<Vec<_>>();
+
+        check_lockout(Some(&state), &rules, client_pid).await?;
+    }
+
+    update_rule(&state, rule).await?;
+    persist_rules(&state, persistent).await?;
+
+    Ok(format!("Rule {} updated", id))
+}
+
+/// Handle the removal of a single rule. Unless forced, the removal is refused if it'd drop the
+/// established management sessions.
+async fn handle_delete(
+    id: u32,
+    force: bool,
+    persistent: bool,
+    client_pid: Option<i32>,
+) -> anyhow::Result<String> {
+    let state = rule_state().await?;
+
+    if !force {
+        let rules = state
+            .rules
+            .read()
+            .await
+            .iter()
+            .filter(|applied| applied.id != id)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        check_lockout(Some(&state), &rules, client_pid).await?;
+    }
+
+    delete_rule(&state, id).await?;
+    persist_rules(&state, persistent).await?;
+
+    Ok(format!("Rule {} deleted", id))
+}
+
+/// Handle the confirmation of the rules from the last load, cancelling the rollback.
+async fn handle_confirm() -> anyhow::Result<String> {
+    let mut overall_state = OVERALL_STATE.write().await;
+
+    let Some(pending) = overall_state.pending_confirm.take() else {
+        anyhow::bail!("No rules are waiting for confirmation");
+    };
+
+    if let Some(state) = &overall_state.state {
+        persist(
+            pending.persistent,
+            &state.rules.read().await,
+            &state.interface,
+        )?;
+    }
+
+    Ok("Rules confirmed".to_string())
+}
+
+/// Apply a ruleset, loading the eBPF program on the interface if it isn't already.
+async fn apply_ruleset(rules: Vec<Rule>, interface: String) -> anyhow::Result<()> {
+    let state = OVERALL_STATE.read().await.state.clone();
+
+    match state {
+        Some(state) if state.interface == interface => replace_rules(&state, rules).await,
+        state => {
+            // The program has to be reattached if the interface changed. Drop our reference to
+            // the state first so the old program is detached when it's unloaded.
+            if state.is_some() {
+                drop(state);
+                unload_ebpf().await;
+            }
+
+            let result = load_ebpf(rules, interface).await;
+
+            OVERALL_STATE.write().await.enabled = result.is_ok();
+
+            result
+        }
+    }
+}
+
+/// Restore the ruleset from before an unconfirmed load. Does nothing if the load was confirmed or
+/// superseded by another load since.
+async fn rollback(id: u64) {
+    let _management = MANAGEMENT_LOCK.lock().await;
+
+    let pending = {
+        let mut overall_state = OVERALL_STATE.write().await;
+
+        match &overall_state.pending_confirm {
+            Some(pending) if pending.id == id => overall_state.pending_confirm.take(),
+            _ => None,
+        }
+    };
+
+    let Some(pending) = pending else {
+        return;
+    };
+
+    let before = snapshot().await;
+
+    let result = match pending.previous {
+        Some((rules, interface)) => {
+            let len = rules.len();
+
+            apply_ruleset(rules, interface.clone())
+                .await
+                .map(|_| format!("restored {} rules on interface {}", len, interface))
         }
         None => {
-            anyhow::bail!("Firewall is not enabled");
+            unload_ebpf().await;
+            OVERALL_STATE.write().await.enabled = false;
+
+            Ok("disabled the firewall, as it was disabled before the load".to_string())
         }
-    }
+    };
+
+    audit::record(None, 0, "ROLLBACK", &before, &snapshot().await, &result);
+
+    let success = result.is_ok();
+    let report = match result {
+        Ok(restored) => {
+            tracing::warn!("Rules weren't confirmed in time, {}", restored);
+
+            format!("rules weren't confirmed in time, {}", restored)
+        }
+        Err(e) => {
+            tracing::error!(
+                "Rules weren't confirmed in time, but rolling back failed: {}",
+                e
+            );
+
+            format!(
+                "rules weren't confirmed in time, but rolling back failed: {}",
+                e
+            )
+        }
+    };
+
+    events::publish(Event::Rollback {
+        success,
+        message: report.clone(),
+    });
+
+    OVERALL_STATE.write().await.last_rollback = Some(report);
 }
 
 /// Handle the enabling of the firewall.
-async fn handle_enable(interface: String) -> anyhow::Result<ServerMessage> {
+async fn handle_enable(interface: String) -> anyhow::Result<String> {
     {
         let overall_status = OVERALL_STATE.read().await;
 
@@ -185,14 +874,11 @@ async fn handle_enable(interface: String) -> anyhow::Result<ServerMessage> {
         overall_status.enabled = true;
     }
 
-    Ok(ServerMessage {
-        request_success: true,
-        message: "Firewall enabled".to_string(),
-    })
+    Ok("Firewall enabled".to_string())
 }
 
 /// Handle the disabling of the firewall.
-async fn handle_disable() -> anyhow::Result<ServerMessage> {
+async fn handle_disable() -> anyhow::Result<String> {
     {
         let overall_status = OVERALL_STATE.read().await;
 
@@ -207,24 +893,12 @@ async fn handle_disable() -> anyhow::Result<ServerMessage> {
         let mut overall_status = OVERALL_STATE.write().await;
 
         overall_status.enabled = false;
+        // Disabling is deliberate, so there's nothing left to roll back.
+        overall_status.pending_confirm = None;
     }
 
-    Ok(ServerMessage {
-        request_success: true,
-        message: "Firewall disabled".to_string(),
-    })
-}
+    // Stay disabled across restarts.
+    clear_state()?;
 
-/// Convert a rule from the common format to the eBPF format for insertion into the map.
-fn convert_rule(rule: Rule) -> ghostwire_common::Rule {
-    ghostwire_common::Rule {
-        id: rule.id,
-        source_start_ip: rule.source_start_ip,
-        source_end_ip: rule.source_end_ip,
-        destination_start_ip: rule.destination_start_ip,
-        destination_end_ip: rule.destination_end_ip,
-        protocol_number: rule.protocol_number,
-        port_number: rule.port_number,
-        ratelimiting: rule.ratelimiting,
-    }
+    Ok("Firewall disabled".to_string())
 }
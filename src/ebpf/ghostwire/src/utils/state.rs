@@ -1,11 +1,14 @@
+use super::packet_log::PacketLogReader;
 use aya::{
     maps::{
+        Array,
         HashMap,
         MapData,
     },
-    Bpf,
+    programs::xdp::XdpLink,
 };
 use ghostwire_common::{
+    Connection,
     Rule,
     RuleAnalytics,
 };
@@ -13,8 +16,15 @@ use prometheus::{
     IntCounterVec,
     Registry,
 };
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::{
+    collections::HashMap as StdHashMap,
+    net::Ipv4Addr,
+    sync::Arc,
+};
+use tokio::{
+    sync::RwLock,
+    time::Instant,
+};
 
 /// The overall state of the firewall, to be exposed to the CLI
 pub struct OverallState {
@@ -23,24 +33,71 @@ pub struct OverallState {
     pub state: Option<Arc<State>>,
     /// The Prometheus counters to update from the maps
     pub counters: PromCounters,
+    /// A ruleset that was applied but is waiting for the client to confirm it
+    pub pending_confirm: Option<PendingConfirm>,
+    /// A description of the last automatic rollback, if one happened
+    pub last_rollback: Option<String>,
+}
+
+/// A ruleset waiting to be confirmed by the client before the deadline, after which the previous
+/// ruleset is restored
+pub struct PendingConfirm {
+    /// Identifies this confirmation, so a stale rollback timer doesn't revert a newer ruleset
+    pub id: u64,
+    /// The rules and interface to restore. If None, the firewall was disabled before the load.
+    pub previous: Option<(Vec<ghostwire_types::Rule>, String)>,
+    /// Whether the rules should be saved for the next startup once they're confirmed
+    pub persistent: bool,
+    /// When the rollback will happen
+    pub deadline: Instant,
 }
 
 /// The state of the firewall when active
 pub struct State {
-    /// Reference to the eBPF program. Held to avoid dropping the program.
-    pub _ebpf: RwLock<Bpf>,
+    /// The priority and handle of the egress TC filter, which stays attached without the daemon
+    pub tc_filter: (u16, u32),
+    /// The XDP link, when the kernel couldn't pin it. Held to avoid detaching the program.
+    pub _xdp_link: Option<XdpLink>,
     /// The interface to apply the XDP hook to
     pub interface: String,
+    /// The applied rules, in the format the client sent them
+    pub rules: RwLock<Vec<ghostwire_types::Rule>>,
     /// The applied rules
-    pub rule_map: RwLock<HashMap<MapData, u32, Rule>>,
+    pub rule_maps: RwLock<RuleMaps>,
     /// The rule metrics
-    pub rule_analytic_map: HashMap<MapData, u32, RuleAnalytics>,
+    pub rule_analytic_map: RwLock<HashMap<MapData, u32, RuleAnalytics>>,
     /// The ratelimit metrics
     pub rule_ratelimit_map: RwLock<HashMap<MapData, u64, u64>>,
+    /// The sources the XDP program reported going over a ratelimit since the ratelimits were last
+    /// reset, oldest first
+    pub blocked_sources: RwLock<Vec<BlockedSource>>,
+    /// The connections the host made, with when they last sent traffic
+    pub holepunched: RwLock<HashMap<MapData, Connection, u64>>,
     /// The aggregate XDP metrics
     pub xdp_analytic_map: HashMap<MapData, u32, u128>,
     /// The aggregate traffic control metrics
     pub tc_analytic_map: HashMap<MapData, i32, u128>,
+    /// The task writing the packet log. Held to keep it running while the firewall is loaded.
+    pub _packet_log: PacketLogReader,
+}
+
+/// A source blocked for going over the ratelimit of a rule, until the ratelimits reset
+#[derive(Debug, Clone)]
+pub struct BlockedSource {
+    /// The address of the source
+    pub source_ip: Ipv4Addr,
+    /// The ID of the rule whose ratelimit it went over
+    pub rule_id: u32,
+    /// When it was blocked, as a Unix timestamp in seconds
+    pub blocked_at: u64,
+}
+
+/// The two banks of the rule map, and the switch saying which one the XDP program reads
+pub struct RuleMaps {
+    /// RULES_A and RULES_B
+    pub banks: [HashMap<MapData, u32, Rule>; 2],
+    /// The index of the bank in use, in key 0
+    pub active: Array<MapData, u32>,
 }
 
 /// The state of the Prometheus counters
@@ -57,6 +114,18 @@ pub struct PromCounters {
     pub tc_action: IntCounterVec,
 }
 
+impl State {
+    /// The names of the applied rules that have one, by rule ID.
+    pub async fn rule_names(&self) -> StdHashMap<u32, String> {
+        self.rules
+            .read()
+            .await
+            .iter()
+            .filter_map(|rule| Some((rule.id, rule.name.clone()?)))
+            .collect()
+    }
+}
+
 impl OverallState {
     /// Implement format for OverallState that shows the overall status of the application. Not a trait
     /// because we access the rule map async and blocking reads are a bad practice.
@@ -72,14 +141,24 @@ impl OverallState {
         // summarize the rules
         if let Some(state) = self.state.as_ref() {
             str.push_str(&format!(" on interface {}", state.interface));
-            let rule_map = state.rule_map.read().await;
-            // eBPF maps don't have a length method
+            // The rule map also holds the always-allow list, so count the client's rules.
+            str.push_str(&format!(" with {} rules", state.rules.read().await.len()));
+        }
+
+        if let Some(pending) = self.pending_confirm.as_ref() {
             str.push_str(&format!(
-                " with {} rules",
-                rule_map.iter().collect::<Vec<_>>().len()
+                ", awaiting confirmation (rolling back in {} seconds)",
+                pending
+                    .deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs()
             ));
         }
 
+        if let Some(rollback) = self.last_rollback.as_ref() {
+            str.push_str(&format!(". Last rollback: {}", rollback));
+        }
+
         str
     }
 }
@@ -0,0 +1,98 @@
+use crate::OVERALL_STATE;
+use std::{
+    env,
+    os::{
+        fd::{
+            FromRawFd,
+            OwnedFd,
+        },
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{
+                SocketAddr,
+                UnixDatagram,
+            },
+        },
+    },
+    process,
+    time::Duration,
+};
+
+/// The first file descriptor systemd passes with socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Tell systemd about the state of the daemon, like `READY=1`. Does nothing unless systemd
+/// started the daemon with a notify socket, as with `Type=notify`.
+pub fn notify(state: &str) {
+    if let Err(e) = notify_fallible(state) {
+        tracing::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Send a message to the notify socket, if there is one.
+fn notify_fallible(state: &str) -> std::io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+
+    // A socket starting with @ is in the abstract namespace.
+    let address = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+
+    socket.send_to_addr(state.as_bytes(), &address)?;
+
+    Ok(())
+}
+
+/// How often to tell systemd the daemon is alive, half the watchdog timeout, if systemd set a
+/// watchdog for this process with `WatchdogSec=`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let timeout: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros((timeout / 2).max(1)))
+}
+
+/// Tell systemd the daemon is alive. The state is locked first, so a daemon stuck holding it is
+/// restarted by the watchdog.
+pub async fn pet_watchdog() {
+    let _overall_state = OVERALL_STATE.read().await;
+
+    notify("WATCHDOG=1");
+}
+
+/// Take the socket systemd passed with socket activation, if it started the daemon that way. Only
+/// the first socket is used, as the daemon only listens on one.
+pub fn activated_socket() -> Option<OwnedFd> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let count: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+
+    // The variables are inherited by hooks, which aren't the process they're meant for.
+    if pid != process::id() || count < 1 {
+        return None;
+    }
+
+    if count > 1 {
+        tracing::warn!(
+            "systemd passed {} sockets, only the first one is used",
+            count
+        );
+    }
+
+    // Keep the socket from leaking into the commands of hooks.
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+
+    Some(unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) })
+}
@@ -0,0 +1,132 @@
+use crate::Rule;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::collections::BTreeMap;
+
+// Bodies of the HTTP API of the daemon
+
+/// The status of the firewall
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiStatus {
+    /// Whether the firewall is enabled
+    pub enabled: bool,
+    /// The interface the firewall is attached to, if it's enabled
+    pub interface: Option<String>,
+    /// The number of applied rules
+    pub rule_count: usize,
+    /// The seconds left to confirm the last load before it's rolled back, if it's awaiting
+    /// confirmation
+    pub rollback_in: Option<u64>,
+    /// A description of the last automatic rollback, if one happened
+    pub last_rollback: Option<String>,
+}
+
+/// The applied ruleset
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiRules {
+    /// The interface the rules are applied to, if the firewall is enabled
+    pub interface: Option<String>,
+    /// The applied rules, empty if the firewall is disabled
+    pub rules: Vec<Rule>,
+}
+
+/// A ruleset to replace the applied one with
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiRulesUpdate {
+    /// The interface to apply the rules to
+    pub interface: String,
+    /// The rules to apply
+    pub rules: Vec<Rule>,
+    /// The seconds the client has to confirm the rules before they're rolled back, at least 1
+    #[serde(default)]
+    pub confirm_within: Option<u64>,
+    /// Whether to apply the rules even if they'd drop the established management sessions
+    #[serde(default)]
+    pub force: bool,
+    /// Whether the rules are applied again when the daemon restarts. Defaults to true.
+    #[serde(default)]
+    pub persistent: Option<bool>,
+}
+
+/// A rule to add, or the new version of an applied rule
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiRuleChange {
+    /// The rule. When adding it, an ID of 0 has the server assign one. When updating it, its ID is
+    /// taken from the path.
+    pub rule: Rule,
+    /// Whether to make the change even if it'd drop the established management sessions
+    #[serde(default)]
+    pub force: bool,
+    /// Whether the change is saved for when the daemon restarts. Defaults to true.
+    #[serde(default)]
+    pub persistent: Option<bool>,
+}
+
+/// The traffic counters of the firewall
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiStats {
+    /// The counters of each rule, by rule ID
+    pub rules: BTreeMap<u32, ApiRuleStats>,
+    /// The number of times each XDP action was taken, by action name
+    pub xdp_actions: BTreeMap<String, u64>,
+    /// The number of times each TC action was taken, by action name
+    pub tc_actions: BTreeMap<String, u64>,
+}
+
+/// The traffic counters of a rule
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiRuleStats {
+    /// The name of the rule, if it has one
+    pub name: Option<String>,
+    /// The number of times the rule was evaluated
+    pub evaluated: u64,
+    /// The number of times the rule allowed traffic
+    pub passed: u64,
+}
+
+/// The sources blocked for going over the ratelimit of a rule. They're unblocked when the
+/// ratelimits reset at the end of the minute, or when they're removed from the blocklist.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiBlocklist {
+    /// The blocked sources, oldest first
+    pub sources: Vec<ApiBlockedSource>,
+}
+
+/// A source blocked for going over the ratelimit of a rule
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiBlockedSource {
+    /// The IPv4 address of the source
+    pub source_ip: String,
+    /// The ID of the rule whose ratelimit the source went over
+    pub rule_id: u32,
+    /// The name of the rule, if it has one
+    pub rule_name: Option<String>,
+    /// When the source was blocked, as a Unix timestamp in seconds
+    pub blocked_at: u64,
+}
+
+/// The outcome of a request that changed the firewall
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiMessage {
+    /// What the server did
+    pub message: String,
+}
+
+/// Why a request failed
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiError {
+    /// The error
+    pub error: String,
+}
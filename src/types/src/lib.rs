@@ -5,20 +5,87 @@ use serde::{
     Serialize,
 };
 
+pub mod api;
+#[cfg(feature = "config")]
+pub mod schema;
+#[cfg(feature = "config")]
+pub mod yaml;
+
 // Types for firewall rules, messages
 
+/// The version of the socket protocol. Bump this whenever a change to the messages would break a
+/// client or server built before it.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The rule IDs from here up are reserved for the always-allow list, whether or not a line uses
+/// them, so a line added later never takes the ID of one of the client's rules.
+pub const RESERVED_RULE_IDS: u32 = u32::MAX - 0xFFFF;
+
+/// The largest message either side will read. Messages are newline-delimited JSON, so this bounds
+/// how long a line can be.
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
 /// A message between server and client
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ClientMessage {
+    /// The protocol version the client speaks. Clients from before versioning send none, which is
+    /// read as 0.
+    #[serde(default)]
+    pub version: u32,
+    /// Identifies the request, echoed back in the response
+    #[serde(default)]
+    pub request_id: u64,
     pub req_type: ClientReqType,
     /// Optional rules to send to the server on a RULES request
     pub rules: Option<Vec<Rule>>,
     /// Optional interface to send to the server on a RULES request
     pub interface: Option<String>,
+    /// Optional number of seconds the client has to send a CONFIRM after a RULES request before
+    /// the server rolls back to the previous ruleset. It must be at least 1.
+    #[serde(default)]
+    pub confirm_within: Option<u64>,
+    /// Whether to apply the rules of a RULES request even if they'd drop the established management
+    /// sessions
+    #[serde(default)]
+    pub force: bool,
+    /// Whether the server should apply the rules of a RULES request again when it restarts. If
+    /// omitted, the rules are persistent.
+    #[serde(default)]
+    pub persistent: Option<bool>,
+    /// Which audit records to send back on an AUDIT request. If omitted, the newest records are
+    /// sent.
+    #[serde(default)]
+    pub audit_query: Option<AuditQuery>,
+    /// The rule to add on an ADD request, or the new version of the rule on an UPDATE request. On an
+    /// ADD request, an ID of 0 has the server assign one.
+    #[serde(default)]
+    pub rule: Option<Rule>,
+    /// The ID of the rule to change on an UPDATE or DELETE request
+    #[serde(default)]
+    pub rule_id: Option<u32>,
+}
+
+impl ClientMessage {
+    /// Create a message of the request type with no optional fields set.
+    pub fn new(req_type: ClientReqType) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            request_id: 0,
+            req_type,
+            rules: None,
+            interface: None,
+            confirm_within: None,
+            force: false,
+            persistent: None,
+            audit_query: None,
+            rule: None,
+            rule_id: None,
+        }
+    }
 }
 
 /// What the client is requesting from the server
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum ClientReqType {
     /// Client is asking for the current status of the firewall
     STATUS,
@@ -28,23 +95,92 @@ pub enum ClientReqType {
     ENABLE,
     /// Client is asking to disable the firewall
     DISABLE,
+    /// Client is confirming the rules sent in a previous RULES request, cancelling the rollback
+    CONFIRM,
+    /// Client is asking for the audit log of the changes made to the firewall
+    AUDIT,
+    /// Client is adding a rule after the applied ones
+    ADD,
+    /// Client is replacing an applied rule, keeping its ID and position
+    UPDATE,
+    /// Client is removing an applied rule
+    DELETE,
+    /// Client is asking for the applied rules
+    LIST,
+}
+
+/// A page of the audit log, counted from the newest record
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct AuditQuery {
+    /// The number of newest records to skip
+    pub skip: usize,
+    /// The maximum number of records to send
+    pub limit: usize,
 }
 
 /// A response from the server
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServerMessage {
+    /// The protocol version the server speaks. Servers from before versioning send none, which is
+    /// read as 0.
+    #[serde(default)]
+    pub version: u32,
+    /// The ID of the request this responds to
+    #[serde(default)]
+    pub request_id: u64,
     pub request_success: bool,
     pub message: String,
+    /// The audit records requested by an AUDIT request, newest first
+    #[serde(default)]
+    pub audit: Option<Vec<AuditRecord>>,
+    /// The applied rules, on a LIST request
+    #[serde(default)]
+    pub rules: Option<Vec<Rule>>,
+    /// The interface the rules are applied to, on a LIST request while the firewall is enabled
+    #[serde(default)]
+    pub interface: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// A change made to the firewall, as recorded in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    /// When the change was made, in seconds since the UNIX epoch
+    pub timestamp: u64,
+    /// The user ID of the client, if it connected to the socket. If neither this nor the address
+    /// is set, the server made the change on its own, like a rollback.
+    pub uid: Option<u32>,
+    /// The address of the client, if it used the HTTP API
+    #[serde(default)]
+    pub address: Option<String>,
+    /// The process ID of the client, if known
+    pub pid: Option<i32>,
+    /// The ID of the request that made the change, or 0 if the server made it on its own
+    pub request_id: u64,
+    /// What was done, like RULES or DISABLE, or ROLLBACK when the server rolled back on its own
+    pub action: String,
+    /// A summary of how the ruleset changed
+    pub changes: String,
+    /// Whether the request succeeded
+    pub success: bool,
+    /// The message the request was answered with, or the error if it failed
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 /// A firewall rule in C format, where fields are expected to be in big endian, or network byte order
 /// You may have noticed this also exists in the ghostwire_types crate. This is because that's the specific type
 /// that is used in the eBPF program.
 pub struct Rule {
     /// The ID of this rule with what the API identifies it as. This will also be the key of the
-    /// ratelimiting map if ratelimiting is enabled for this rule.
+    /// ratelimiting map if ratelimiting is enabled for this rule. IDs start at 1, a rule sent with
+    /// an ID of 0 is given one by the server, reusing the ID of the applied rule matching the same
+    /// traffic if there is one.
     pub id: u32,
+    /// A name for the rule, used as a label of its Prometheus metrics. Not passed to the eBPF
+    /// program.
+    #[serde(default)]
+    pub name: Option<String>,
     /// The start source IP address in big endian
     pub source_start_ip: u32,
     /// The end source IP address in big endian
@@ -55,14 +191,83 @@ pub struct Rule {
     /// The end destination IP address in big endian. If this rule applies everywhere, all bytes
     /// will show 0
     pub destination_end_ip: u32,
-    /// Protocol number (currently limited to either 1, 6, 17 for ICMP, TCP, and UDP respectively.
-    /// if this rule applies to all protocols, this will be zero)
+    /// The IP protocol number, like 6 for TCP or 47 for GRE. If this rule applies to all protocols,
+    /// this will be zero
     pub protocol_number: u8,
-    /// The port if TCP or UDP (if not, 0)
+    /// Which headers of tunneled traffic the rule matches on
+    #[serde(default)]
+    pub match_on: MatchOn,
+    /// The destination port if the protocol has ports, like TCP, UDP, SCTP and DCCP (if not, 0)
     pub port_number: u16,
     /// If the rule is a ratelimiting one, represent the amount of traffic allowed per IP over 10
     /// seconds. If there's no ratelimiting rule, this is 0.
     pub ratelimiting: u32,
+    /// Whether the packets matching this rule are written to the daemon's packet log
+    #[serde(default)]
+    pub log: bool,
+}
+
+impl Rule {
+    /// Whether two rules match the same traffic, regardless of their ID, name and ratelimit.
+    pub fn same_traffic(&self, other: &Rule) -> bool {
+        self.source_start_ip == other.source_start_ip
+            && self.source_end_ip == other.source_end_ip
+            && self.destination_start_ip == other.destination_start_ip
+            && self.destination_end_ip == other.destination_end_ip
+            && self.protocol_number == other.protocol_number
+            && self.port_number == other.port_number
+            && self.match_on == other.match_on
+    }
+}
+
+/// Which IPv4 header of tunneled traffic a rule matches on. GRE, IPIP and VXLAN tunnels are
+/// looked into.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum MatchOn {
+    /// The outermost header, as for traffic that isn't tunneled
+    #[default]
+    Outer,
+    /// The header inside the tunnel. The rule never matches traffic that isn't tunneled
+    Inner,
+}
+
+/// The IP protocols rules can name, and their protocol numbers. Other protocols are written as their
+/// number, and 0 stands for every protocol.
+pub const PROTOCOLS: [(&str, u8); 9] = [
+    ("ALL", 0),
+    ("ICMP", 1),
+    ("TCP", 6),
+    ("UDP", 17),
+    ("DCCP", 33),
+    ("GRE", 47),
+    ("ESP", 50),
+    ("AH", 51),
+    ("SCTP", 132),
+];
+
+/// Parse a protocol name from PROTOCOLS in any case, or a protocol number from 0 to 255.
+pub fn parse_protocol(protocol: &str) -> Option<u8> {
+    PROTOCOLS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(protocol))
+        .map(|(_, number)| *number)
+        .or_else(|| protocol.parse().ok())
+}
+
+/// Name a protocol number, or write it as a number if it has no name.
+pub fn protocol_name(protocol_number: u8) -> String {
+    PROTOCOLS
+        .iter()
+        .find(|(_, number)| *number == protocol_number)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| protocol_number.to_string())
+}
+
+/// Whether the packets of a protocol have ports a rule can match on: TCP, UDP, SCTP and DCCP.
+pub fn has_ports(protocol_number: u8) -> bool {
+    matches!(protocol_number, 6 | 17 | 33 | 132)
 }
 
 /// A network protocol. This is used in the Rule struct to determine what protocol the rule applies to.
@@ -0,0 +1,200 @@
+use jsonschema::{
+    error::ValidationErrorKind,
+    ValidationError,
+    Validator,
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+};
+
+/// The JSON Schema of configuration files, published as config.schema.json for editors
+pub const SCHEMA: &str = include_str!("../config.schema.json");
+
+/// Check a configuration file against the schema, reporting every error with its file, line and
+/// column, and its path like `rules[2].port`, which includes the index of the rule.
+pub fn validate(
+    file: &str,
+    parsed: &serde_yaml::Value,
+    positions: &HashMap<String, (usize, usize)>,
+) -> anyhow::Result<()> {
+    let instance = serde_json::to_value(parsed)
+        .map_err(|e| anyhow::anyhow!("{}: keys must be strings: {}", file, e))?;
+
+    let mut errors: Vec<((usize, usize), String)> = validator()
+        .iter_errors(&instance)
+        .map(|error| {
+            let mut path = yaml_path(error.instance_path.as_str());
+
+            // Point at the unexpected key rather than the mapping holding it.
+            if let ValidationErrorKind::AdditionalProperties { unexpected } = &error.kind {
+                if let Some(key) = unexpected.first() {
+                    path = match path.is_empty() {
+                        true => key.clone(),
+                        false => format!("{}.{}", path, key),
+                    };
+                }
+            }
+
+            let position = positions.get(&path).copied().unwrap_or((0, 0));
+            let location = match position {
+                (0, 0) => file.to_string(),
+                (line, column) => format!("{}:{}:{}", file, line, column),
+            };
+            let subject = match path.is_empty() {
+                true => String::new(),
+                false => format!("{}: ", path),
+            };
+
+            (
+                position,
+                format!("{}: {}{}", location, subject, message(&error, &path)),
+            )
+        })
+        .collect();
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    errors.sort();
+    errors.dedup();
+
+    anyhow::bail!(
+        "{} doesn't match the configuration schema:\n{}",
+        file,
+        errors
+            .into_iter()
+            .map(|(_, error)| error)
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// The schema, parsed the first time it's needed.
+fn schema() -> &'static Value {
+    static SCHEMA_VALUE: OnceLock<Value> = OnceLock::new();
+
+    SCHEMA_VALUE.get_or_init(|| serde_json::from_str(SCHEMA).expect("the schema is valid JSON"))
+}
+
+/// The validator of the schema, compiled the first time it's needed.
+fn validator() -> &'static Validator {
+    static VALIDATOR: OnceLock<Validator> = OnceLock::new();
+
+    VALIDATOR.get_or_init(|| {
+        jsonschema::validator_for(schema()).expect("the schema is a valid JSON Schema")
+    })
+}
+
+/// Describe a validation error. The errors of the fields of a rule name what the field takes, as
+/// the errors of the schema's alternatives only say none of them matched.
+fn message(error: &ValidationError, path: &str) -> String {
+    let value = error.instance.to_string();
+
+    match &error.kind {
+        // The path already names the key.
+        ValidationErrorKind::AdditionalProperties { .. } => match path.starts_with("rules[") {
+            true => "not a field of a rule".to_string(),
+            false => "not a setting".to_string(),
+        },
+        ValidationErrorKind::Required { property } => {
+            format!("the rule has no {}", property.as_str().unwrap_or_default())
+        }
+        _ => match rule_field(path).and_then(field_description) {
+            Some(description) => format!("{} isn't {}", value, description),
+            None => error.to_string(),
+        },
+    }
+}
+
+/// The field of a rule a path is in, like `port` for `rules[2].port[1]`.
+fn rule_field(path: &str) -> Option<&str> {
+    let (_, field) = path.strip_prefix("rules[")?.split_once("].")?;
+
+    Some(field.split('[').next().unwrap_or(field))
+}
+
+/// What a field of a rule takes, from its description in the schema.
+fn field_description(field: &str) -> Option<&'static str> {
+    schema()
+        .pointer(&format!("/$defs/rule/properties/{}/description", field))?
+        .as_str()
+}
+
+/// Convert a JSON pointer like `/rules/2/port` to the path of a YAML node, like `rules[2].port`.
+fn yaml_path(pointer: &str) -> String {
+    let mut path = String::new();
+
+    for segment in pointer.split('/').skip(1) {
+        let segment = segment.replace("~1", "/").replace("~0", "~");
+
+        if segment.parse::<usize>().is_ok() {
+            path.push_str(&format!("[{}]", segment));
+        } else if path.is_empty() {
+            path.push_str(&segment);
+        } else {
+            path.push_str(&format!(".{}", segment));
+        }
+    }
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yaml::node_positions;
+
+    /// The errors of validating a configuration file, one per line
+    fn errors(yaml: &str) -> Vec<String> {
+        let parsed = serde_yaml::from_str(yaml).unwrap();
+
+        match validate("rules.yml", &parsed, &node_positions(yaml)) {
+            Ok(()) => vec![],
+            Err(e) => e.to_string().lines().skip(1).map(str::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn valid_files_pass() {
+        assert!(errors("interface: eth0\nrules:\n  - protocol: tcp\n    port: 22\n").is_empty());
+    }
+
+    #[test]
+    fn unknown_settings_are_located_at_their_key() {
+        assert_eq!(
+            errors("interface: eth0\ninterfaces: eth1\n"),
+            ["rules.yml:2:1: interfaces: not a setting"]
+        );
+    }
+
+    #[test]
+    fn unknown_rule_fields_are_located_at_their_key() {
+        assert_eq!(
+            errors("rules:\n  - protocol: tcp\n    prot: 22\n"),
+            ["rules.yml:3:5: rules[0].prot: not a field of a rule"]
+        );
+    }
+
+    #[test]
+    fn invalid_values_name_the_rule_index() {
+        let errors = errors(
+            "rules:\n  - protocol: tcp\n  - protocol: udp\n    port: 70000\n    log: yes please\n",
+        );
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("rules.yml:4:5: rules[1].port: 70000 isn't "));
+        assert!(errors[1].starts_with("rules.yml:5:5: rules[1].log: \"yes please\" isn't "));
+    }
+
+    #[test]
+    fn missing_fields_are_located_at_the_rule() {
+        let errors = errors("interface: eth0\nrules:\n  - port: 22\n");
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("rules.yml:3:"), "{}", errors[0]);
+        assert!(errors[0].ends_with("rules[0]: the rule has no protocol"));
+    }
+}
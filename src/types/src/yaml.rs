@@ -0,0 +1,1126 @@
+use crate::{
+    parse_protocol,
+    schema,
+    MatchOn,
+    Rule,
+    RESERVED_RULE_IDS,
+};
+use anyhow::Context;
+/// This file is dedicated to the YAML chief Dobri.
+use serde::{
+    Deserialize,
+    Deserializer,
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    fmt::Display,
+    fs,
+    net::Ipv4Addr,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+use yaml_rust2::{
+    parser::{
+        Event,
+        MarkedEventReceiver,
+        Parser,
+    },
+    scanner::Marker,
+};
+
+/// A parsed configuration file.
+pub struct Config {
+    /// The firewall rules
+    pub rules: Vec<Rule>,
+    /// The interface to run the XDP on
+    pub interface: String,
+    /// Whether the rules should be applied again when the server restarts
+    pub persistent: bool,
+}
+
+/// A rule as written in a configuration file, and where it was written.
+pub struct SourcedRule {
+    /// The rule
+    pub rule: YamlRule,
+    /// The file the rule came from
+    pub file: String,
+    /// The line of the rule in its file, if known
+    pub line: Option<usize>,
+    /// The index of the rule in the rules of its file
+    pub index: usize,
+    /// The line and column of each field of the rule
+    pub fields: HashMap<String, (usize, usize)>,
+}
+
+impl SourcedRule {
+    /// Where the rule was written, as `file:line`.
+    pub fn location(&self) -> String {
+        location(&self.file, self.line)
+    }
+
+    /// Where a field of the rule was written, as `file:line:column: rules[index].field`.
+    pub fn field_location(&self, field: &str) -> String {
+        let location = match self.fields.get(field) {
+            Some((line, column)) => format!("{}:{}:{}", self.file, line, column),
+            None => self.location(),
+        };
+
+        format!("{}: rules[{}].{}", location, self.index, field)
+    }
+}
+
+/// A field of a rule with a value that can't be converted
+#[derive(Debug)]
+pub struct FieldError {
+    /// The field as written in the configuration file, like `port`
+    pub field: &'static str,
+    /// What's wrong with the value
+    pub message: String,
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for FieldError {}
+
+/// Format a line of a file as `file:line`, or just the file if the line isn't known.
+fn location(file: &str, line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{}:{}", file, line),
+        None => file.to_string(),
+    }
+}
+
+/// A rule as written in a configuration file, before its variables are expanded.
+struct RawRule {
+    /// The rule
+    value: serde_yaml::Value,
+    /// The file the rule came from
+    file: String,
+    /// The line of the rule in its file, if known
+    line: Option<usize>,
+    /// The index of the rule in the rules of its file
+    index: usize,
+    /// The line and column of each field of the rule
+    fields: HashMap<String, (usize, usize)>,
+}
+
+/// The configuration files a configuration is made of, merged but not yet converted to firewall
+/// rules. Each file's rules come before the rules of the files it includes.
+#[derive(Default)]
+pub struct ConfigFiles {
+    /// The files read, in the order their rules were merged
+    pub files: Vec<String>,
+    /// The rules of every file, with their variables expanded
+    pub rules: Vec<SourcedRule>,
+    /// The rules of every file as written
+    raw_rules: Vec<RawRule>,
+    /// The variables of every file, and where each was defined
+    variables: HashMap<String, (serde_yaml::Value, String)>,
+    /// The interface, and where it was set
+    interface: Option<(String, String)>,
+    /// Whether the rules are persistent, and where it was set
+    persistent: Option<(bool, String)>,
+    /// The canonical paths of the files read, to catch a file included twice
+    read: HashSet<PathBuf>,
+}
+
+impl ConfigFiles {
+    /// Read a configuration file and the files it includes.
+    pub fn read(path: &str) -> anyhow::Result<Self> {
+        let mut files = Self::default();
+
+        files.add_file(Path::new(path))?;
+        files.expand()?;
+
+        Ok(files)
+    }
+
+    /// Read the configuration files of a directory in lexical order, like a conf.d, and the files
+    /// they include.
+    pub fn read_dir(dir: &str) -> anyhow::Result<Self> {
+        let mut files = Self::default();
+
+        files.add_dir(Path::new(dir))?;
+
+        if files.files.is_empty() {
+            anyhow::bail!("No .yml or .yaml files in {}", dir);
+        }
+
+        files.expand()?;
+
+        Ok(files)
+    }
+
+    /// Convert the rules to firewall rules and check the settings the files agree on.
+    pub fn config(&self) -> anyhow::Result<Config> {
+        let rules = self
+            .rules
+            .iter()
+            .map(|sourced| {
+                convert_to_rule(sourced.rule.clone()).map_err(|e| {
+                    match e.downcast::<FieldError>() {
+                        Ok(e) => {
+                            anyhow::anyhow!("{}: {}", sourced.field_location(e.field), e.message)
+                        }
+                        Err(e) => e.context(format!(
+                            "Invalid rule at {}: rules[{}]",
+                            sourced.location(),
+                            sourced.index
+                        )),
+                    }
+                })
+            })
+            .collect::<anyhow::Result<Vec<Rule>>>()?;
+
+        // Rules without an ID are given one by the server, the rest have to be unique.
+        let mut ids = HashMap::new();
+
+        for (rule, sourced) in rules.iter().zip(&self.rules) {
+            if rule.id == 0 {
+                continue;
+            }
+
+            if let Some(other) = ids.insert(rule.id, sourced.location()) {
+                anyhow::bail!(
+                    "Rule ID {} is used at {} and {}",
+                    rule.id,
+                    other,
+                    sourced.location()
+                );
+            }
+        }
+
+        Ok(Config {
+            rules,
+            interface: self
+                .interface
+                .as_ref()
+                .map(|(interface, _)| interface.clone())
+                .ok_or(anyhow::anyhow!("interface not provided"))?,
+            persistent: self
+                .persistent
+                .as_ref()
+                .is_none_or(|(persistent, _)| *persistent),
+        })
+    }
+
+    /// Read the .yml and .yaml files of a directory in lexical order.
+    fn add_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let mut paths = fs::read_dir(dir)
+            .with_context(|| format!("Couldn't read {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        paths.retain(|path| {
+            path.is_file()
+                && matches!(
+                    path.extension().and_then(|extension| extension.to_str()),
+                    Some("yml" | "yaml")
+                )
+        });
+        paths.sort();
+
+        for path in paths {
+            self.add_file(&path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a configuration file, then the files or directories it includes, relative to it.
+    fn add_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let file = path.display().to_string();
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Couldn't read {}", file))?;
+
+        if !self.read.insert(canonical) {
+            anyhow::bail!("{} is included more than once", file);
+        }
+
+        let yaml = fs::read_to_string(path).with_context(|| format!("Couldn't read {}", file))?;
+        let parsed: serde_yaml::Value =
+            serde_yaml::from_str(&yaml).with_context(|| format!("Invalid YAML in {}", file))?;
+        let positions = node_positions(&yaml);
+
+        schema::validate(&file, &parsed, &positions)?;
+
+        let locate = |path: &str| match positions.get(path) {
+            Some((line, column)) => format!("{}:{}:{}", file, line, column),
+            None => file.clone(),
+        };
+
+        if let Some(interface) = setting(&parsed, "interface", &file, |value| value.as_str())? {
+            merge_setting(
+                &mut self.interface,
+                interface.to_string(),
+                &locate("interface"),
+                "interface",
+            )?;
+        }
+
+        if let Some(persistent) = setting(&parsed, "persistent", &file, |value| value.as_bool())? {
+            merge_setting(
+                &mut self.persistent,
+                persistent,
+                &locate("persistent"),
+                "persistent",
+            )?;
+        }
+
+        match &parsed["variables"] {
+            serde_yaml::Value::Null => {}
+            serde_yaml::Value::Mapping(variables) => {
+                for (name, value) in variables {
+                    let name = name
+                        .as_str()
+                        .with_context(|| format!("Variable names in {} must be strings", file))?;
+                    let location = locate(&format!("variables.{}", name));
+
+                    match self.variables.get(name) {
+                        Some((other, other_location)) if other != value => anyhow::bail!(
+                            "{}: {} is already defined differently at {}",
+                            location,
+                            name,
+                            other_location
+                        ),
+                        Some(_) => {}
+                        None => {
+                            self.variables
+                                .insert(name.to_string(), (value.clone(), location));
+                        }
+                    }
+                }
+            }
+            _ => anyhow::bail!("variables in {} must map names to values", file),
+        }
+
+        let rules: Vec<serde_yaml::Value> = match &parsed["rules"] {
+            serde_yaml::Value::Null => vec![],
+            rules => serde_yaml::from_value(rules.clone())
+                .with_context(|| format!("rules in {} must be a list", file))?,
+        };
+
+        self.files.push(file.clone());
+
+        for (index, value) in rules.into_iter().enumerate() {
+            let path = format!("rules[{}]", index);
+            let fields = positions
+                .iter()
+                .filter_map(|(field, position)| {
+                    let field = field.strip_prefix(&path)?.strip_prefix('.')?;
+
+                    Some((field.to_string(), *position))
+                })
+                .collect();
+
+            self.raw_rules.push(RawRule {
+                value,
+                file: file.clone(),
+                line: positions.get(&path).map(|(line, _)| *line),
+                index,
+                fields,
+            });
+        }
+
+        let includes: Vec<String> = match &parsed["include"] {
+            serde_yaml::Value::Null => vec![],
+            serde_yaml::Value::String(include) => vec![include.clone()],
+            includes => serde_yaml::from_value(includes.clone()).with_context(|| {
+                format!("include in {} must be a path or a list of paths", file)
+            })?,
+        };
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        for include in includes {
+            let path = base.join(include);
+
+            match path.is_dir() {
+                true => self.add_dir(&path)?,
+                false => self.add_file(&path)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Expand the variables of the rules read. A field set to a list, or to a variable holding
+    /// one, makes a rule for each of its items.
+    fn expand(&mut self) -> anyhow::Result<()> {
+        for raw in std::mem::take(&mut self.raw_rules) {
+            let location = location(&raw.file, raw.line);
+
+            let serde_yaml::Value::Mapping(fields) = &raw.value else {
+                anyhow::bail!("{}: a rule must map its fields to values", location);
+            };
+
+            let mut expanded = vec![serde_yaml::Mapping::new()];
+
+            for (field, value) in fields {
+                let field_location = match field.as_str().and_then(|field| raw.fields.get(field)) {
+                    Some((line, column)) => format!("{}:{}:{}", raw.file, line, column),
+                    None => location.clone(),
+                };
+
+                let values = self
+                    .resolve(value, &mut vec![])
+                    .and_then(|values| {
+                        values
+                            .iter()
+                            .try_for_each(|value| check_field(field, value))
+                            .map(|_| values)
+                    })
+                    .with_context(|| {
+                        format!(
+                            "{}: rules[{}].{}",
+                            field_location,
+                            raw.index,
+                            field.as_str().unwrap_or_default()
+                        )
+                    })?;
+
+                expanded = expanded
+                    .into_iter()
+                    .flat_map(|rule| {
+                        values.iter().map(move |value| {
+                            let mut rule = rule.clone();
+
+                            rule.insert(field.clone(), value.clone());
+                            rule
+                        })
+                    })
+                    .collect();
+            }
+
+            if expanded.len() > 1 && fields.contains_key("id") {
+                anyhow::bail!(
+                    "{}: the rule expands to {} rules, which can't share its id",
+                    location,
+                    expanded.len()
+                );
+            }
+
+            for fields in expanded {
+                self.rules.push(SourcedRule {
+                    rule: serde_yaml::from_value(serde_yaml::Value::Mapping(fields)).with_context(
+                        || format!("Invalid rule at {}: rules[{}]", location, raw.index),
+                    )?,
+                    file: raw.file.clone(),
+                    line: raw.line,
+                    index: raw.index,
+                    fields: raw.fields.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Substitute the variables of a value, written as `$name`. A list expands to all of its
+    /// items, and a variable can refer to other variables.
+    fn resolve(
+        &self,
+        value: &serde_yaml::Value,
+        expanding: &mut Vec<String>,
+    ) -> anyhow::Result<Vec<serde_yaml::Value>> {
+        match value {
+            serde_yaml::Value::String(reference) if reference.starts_with('$') => {
+                let name = &reference[1..];
+
+                if expanding.iter().any(|expanding| expanding == name) {
+                    anyhow::bail!("{} refers to itself", reference);
+                }
+
+                let (variable, location) = self
+                    .variables
+                    .get(name)
+                    .with_context(|| format!("{} isn't defined", reference))?;
+
+                expanding.push(name.to_string());
+
+                let values = self
+                    .resolve(variable, expanding)
+                    .with_context(|| format!("in {} defined at {}", reference, location))?;
+
+                expanding.pop();
+
+                match values.is_empty() {
+                    true => anyhow::bail!("{} is an empty list", reference),
+                    false => Ok(values),
+                }
+            }
+            serde_yaml::Value::Sequence(items) => Ok(items
+                .iter()
+                .map(|item| self.resolve(item, expanding))
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .concat()),
+            value => Ok(vec![value.clone()]),
+        }
+    }
+}
+
+/// Check a field of a rule has a value of the type the field takes.
+fn check_field(field: &serde_yaml::Value, value: &serde_yaml::Value) -> anyhow::Result<()> {
+    let valid = match field.as_str() {
+        Some("port") => value.as_u64().is_some_and(|port| port <= u16::MAX as u64),
+        Some("protocol") => {
+            value.is_string()
+                || value
+                    .as_u64()
+                    .is_some_and(|number| number <= u8::MAX as u64)
+        }
+        Some("log") => value.is_bool(),
+        Some("id" | "ratelimit") => value
+            .as_u64()
+            .is_some_and(|number| number <= u32::MAX as u64),
+        Some("source_ip_range" | "destination_ip_range" | "name" | "comment" | "match_on") => {
+            value.is_string()
+        }
+        _ => true,
+    };
+
+    if !valid {
+        anyhow::bail!(
+            "{} isn't a valid {}",
+            serde_yaml::to_string(value)?.trim_end(),
+            serde_yaml::to_string(field)?.trim_end()
+        );
+    }
+
+    Ok(())
+}
+
+/// Get a setting of a configuration file, if it's set.
+fn setting<'a, T>(
+    parsed: &'a serde_yaml::Value,
+    name: &str,
+    file: &str,
+    convert: impl Fn(&'a serde_yaml::Value) -> Option<T>,
+) -> anyhow::Result<Option<T>> {
+    match &parsed[name] {
+        serde_yaml::Value::Null => Ok(None),
+        value => match convert(value) {
+            Some(value) => Ok(Some(value)),
+            None => anyhow::bail!("{} in {} has the wrong type", name, file),
+        },
+    }
+}
+
+/// Take a setting from a file, unless another file set it to something else. Each setting is kept
+/// with where it was set, as `file:line:column`.
+fn merge_setting<T: PartialEq + Display>(
+    setting: &mut Option<(T, String)>,
+    value: T,
+    location: &str,
+    name: &str,
+) -> anyhow::Result<()> {
+    match setting {
+        Some((other_value, other_location)) if *other_value != value => anyhow::bail!(
+            "{}: {} is set to {}, but {} sets it to {}",
+            location,
+            name,
+            value,
+            other_location,
+            other_value
+        ),
+        Some(_) => Ok(()),
+        None => {
+            *setting = Some((value, location.to_string()));
+
+            Ok(())
+        }
+    }
+}
+
+/// Convert a YAML rule into a firewall rule. A rule without an ID gets 0, for the server to assign
+/// one. Invalid values are reported as a FieldError naming the field.
+pub fn convert_to_rule(yaml_rule: YamlRule) -> anyhow::Result<Rule> {
+    let field_error = |field: &'static str, message: String| FieldError { field, message };
+
+    if yaml_rule.id == Some(0) {
+        return Err(field_error("id", "rule IDs start at 1".to_string()).into());
+    }
+
+    if yaml_rule.id.is_some_and(|id| id >= RESERVED_RULE_IDS) {
+        return Err(field_error(
+            "id",
+            format!(
+                "rule IDs from {} up are reserved for the always-allow list",
+                RESERVED_RULE_IDS
+            ),
+        )
+        .into());
+    }
+
+    let (source_start_ip, source_end_ip) = parse_ip_range(&yaml_rule.source_ip_range)
+        .map_err(|e| field_error("source_ip_range", format!("{:#}", e)))?;
+    let (destination_start_ip, destination_end_ip) =
+        parse_ip_range(&yaml_rule.destination_ip_range)
+            .map_err(|e| field_error("destination_ip_range", format!("{:#}", e)))?;
+
+    let protocol_number = parse_protocol(&yaml_rule.protocol).ok_or_else(|| {
+        field_error(
+            "protocol",
+            format!(
+                "{} isn't a protocol, expected a name like TCP, UDP, SCTP or ALL, or a number from 0 to 255",
+                yaml_rule.protocol
+            ),
+        )
+    })?;
+
+    let match_on = match yaml_rule
+        .match_on
+        .as_deref()
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        None | Some("outer") => MatchOn::Outer,
+        Some("inner") => MatchOn::Inner,
+        Some(match_on) => {
+            return Err(
+                field_error("match_on", format!("{} isn't outer or inner", match_on)).into(),
+            )
+        }
+    };
+
+    Ok(Rule {
+        id: yaml_rule.id.unwrap_or(0),
+        name: yaml_rule.name,
+        source_start_ip,
+        source_end_ip,
+        destination_start_ip,
+        destination_end_ip,
+        protocol_number,
+        match_on,
+        port_number: u16::to_be(yaml_rule.port),
+        ratelimiting: yaml_rule.ratelimit,
+        log: yaml_rule.log,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+/// A rule in the YAML format.
+pub struct YamlRule {
+    /// The ID of the rule, which keeps its metrics and ratelimits when other rules are added or
+    /// removed. If omitted, the server reuses the ID of the applied rule matching the same traffic,
+    /// or picks a new one.
+    #[serde(default)]
+    pub id: Option<u32>,
+    /// A name for the rule, shown in its metrics
+    #[serde(default, alias = "comment")]
+    pub name: Option<String>,
+    /// Defaults to every source
+    #[serde(default = "any_ip_range")]
+    pub source_ip_range: String,
+    /// Defaults to every destination
+    #[serde(default = "any_ip_range")]
+    pub destination_ip_range: String,
+    /// A protocol name like TCP or ALL, or a protocol number
+    #[serde(deserialize_with = "string_or_number")]
+    pub protocol: String,
+    /// Defaults to 0, any port
+    #[serde(default)]
+    pub port: u16,
+    /// Defaults to 0, no ratelimit
+    #[serde(default)]
+    pub ratelimit: u32,
+    /// outer or inner, which headers of tunneled traffic to match on. Defaults to outer
+    #[serde(default)]
+    pub match_on: Option<String>,
+    /// Whether the packets matching the rule are written to the server's packet log. Defaults to
+    /// false
+    #[serde(default)]
+    pub log: bool,
+}
+
+/// Read a string, or a number as a string, like a protocol written as its number.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(u64),
+    }
+
+    Ok(match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(string) => string,
+        StringOrNumber::Number(number) => number.to_string(),
+    })
+}
+
+/// The IP range of every address, the default of the ranges of a rule
+fn any_ip_range() -> String {
+    "0.0.0.0/0".to_string()
+}
+
+/// Parse an IP range in CIDR notation to two big endian numbers: the start and end of the range.
+fn parse_ip_range(ip_range: &str) -> anyhow::Result<(u32, u32)> {
+    if ip_range == "0.0.0.0/0" {
+        return Ok((0, u32::MAX));
+    }
+
+    // Break up the subnet from the IP.
+    let parts: Vec<&str> = ip_range.split('/').collect();
+    // Parse the IPv4 part.
+    let ip: Ipv4Addr = parts[0]
+        .parse()
+        .with_context(|| format!("{} isn't an IPv4 address", parts[0]))?;
+    // The user didn't provide a prefix length. Assume it's a single ip (/32).
+    let prefix_length: u8 = if parts.len() > 1 {
+        parts[1]
+            .parse()
+            .with_context(|| format!("{} isn't a prefix length", parts[1]))?
+    } else {
+        32
+    };
+    if prefix_length > 32 {
+        anyhow::bail!("the prefix length of {} is over 32", ip_range);
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix_length as u32).unwrap_or(0);
+
+    let start_ip = u32::from(ip) & mask;
+    let end_ip = start_ip | !mask;
+
+    Ok((start_ip.to_be(), end_ip.to_be()))
+}
+
+/// Format a big endian IP range back to CIDR notation, or to `start-end` if it isn't a subnet.
+pub fn format_ip_range(start_ip: u32, end_ip: u32) -> String {
+    let start_ip = u32::from_be(start_ip);
+    let end_ip = u32::from_be(end_ip);
+    let size = end_ip.wrapping_sub(start_ip).wrapping_add(1);
+
+    // A subnet spans a power of two addresses and starts on a multiple of it.
+    if size == 0 {
+        "0.0.0.0/0".to_string()
+    } else if size.is_power_of_two() && start_ip.is_multiple_of(size) {
+        format!(
+            "{}/{}",
+            Ipv4Addr::from(start_ip),
+            32 - size.trailing_zeros()
+        )
+    } else {
+        format!("{}-{}", Ipv4Addr::from(start_ip), Ipv4Addr::from(end_ip))
+    }
+}
+
+/// Find where each node of a YAML document is, as a 1-based line and column, by its path like
+/// `rules[2].port`. Keys of a mapping are located at the key, so null values have a position too.
+pub fn node_positions(yaml: &str) -> HashMap<String, (usize, usize)> {
+    let mut locator = Locator {
+        stack: vec![],
+        positions: HashMap::new(),
+    };
+
+    // The document was already parsed by serde_yaml, so errors here only cut the positions short.
+    let _ = Parser::new(yaml.chars()).load(&mut locator, false);
+
+    locator.positions
+}
+
+/// A container being walked by the Locator
+enum Frame {
+    /// A mapping at the path, waiting for a key if None, or for the value of the key
+    Mapping { path: String, key: Option<String> },
+    /// A sequence at the path, with the index of its next item
+    Sequence { path: String, index: usize },
+}
+
+/// Records the position of each node while the YAML is parsed
+struct Locator {
+    /// The containers enclosing the next node
+    stack: Vec<Frame>,
+    /// The positions found so far
+    positions: HashMap<String, (usize, usize)>,
+}
+
+impl Locator {
+    /// Work out the path of a node that starts at the marker, recording its position. Returns None
+    /// for mapping keys, which aren't nodes with a path of their own.
+    fn enter(&mut self, event: &Event, mark: Marker) -> Option<String> {
+        let join = |path: &str, key: &str| match path.is_empty() {
+            true => key.to_string(),
+            false => format!("{}.{}", path, key),
+        };
+
+        let path = match self.stack.last_mut() {
+            None => String::new(),
+            Some(Frame::Mapping { path, key }) => match key.take() {
+                Some(key) => join(path, &key),
+                None => {
+                    if let Event::Scalar(name, ..) = event {
+                        let path = join(path, name);
+
+                        self.positions.insert(path, (mark.line(), mark.col() + 1));
+                        *key = Some(name.clone());
+                    }
+
+                    return None;
+                }
+            },
+            Some(Frame::Sequence { path, index }) => {
+                let path = format!("{}[{}]", path, index);
+
+                *index += 1;
+                self.positions
+                    .insert(path.clone(), (mark.line(), mark.col() + 1));
+
+                path
+            }
+        };
+
+        Some(path)
+    }
+}
+
+impl MarkedEventReceiver for Locator {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(..) | Event::Alias(_) => {
+                self.enter(&event, mark);
+            }
+            Event::MappingStart(..) => {
+                let path = self.enter(&event, mark).unwrap_or_default();
+
+                self.stack.push(Frame::Mapping { path, key: None });
+            }
+            Event::SequenceStart(..) => {
+                let path = self.enter(&event, mark).unwrap_or_default();
+
+                self.stack.push(Frame::Sequence { path, index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the files of a test configuration to a new directory, returning it.
+    fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ghostwire-yaml-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        for (path, contents) in files {
+            let path = dir.join(path);
+
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        dir
+    }
+
+    fn read(dir: &Path, file: &str) -> anyhow::Result<ConfigFiles> {
+        ConfigFiles::read(dir.join(file).to_str().unwrap())
+    }
+
+    /// The ports of the rules read, in order
+    fn ports(files: &ConfigFiles) -> Vec<u16> {
+        files
+            .rules
+            .iter()
+            .map(|sourced| sourced.rule.port)
+            .collect()
+    }
+
+    #[test]
+    fn includes_come_after_the_including_file() {
+        let dir = fixture(
+            "includes",
+            &[
+                (
+                    "main.yml",
+                    "interface: eth0\ninclude: [a.yml, b.yml]\nrules:\n  - protocol: tcp\n    port: 1\n",
+                ),
+                ("a.yml", "rules:\n  - protocol: tcp\n    port: 2\n"),
+                ("b.yml", "rules:\n  - protocol: tcp\n    port: 3\n"),
+            ],
+        );
+
+        let files = read(&dir, "main.yml").unwrap();
+
+        assert_eq!(ports(&files), [1, 2, 3]);
+        assert_eq!(files.files.len(), 3);
+        assert!(files.rules[1].file.ends_with("a.yml"));
+        assert_eq!(files.config().unwrap().interface, "eth0");
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let dir = fixture(
+            "relative",
+            &[
+                ("main.yml", "interface: eth0\ninclude: sub/a.yml\n"),
+                (
+                    "sub/a.yml",
+                    "include: b.yml\nrules:\n  - protocol: tcp\n    port: 2\n",
+                ),
+                ("sub/b.yml", "rules:\n  - protocol: tcp\n    port: 3\n"),
+            ],
+        );
+
+        assert_eq!(ports(&read(&dir, "main.yml").unwrap()), [2, 3]);
+    }
+
+    #[test]
+    fn directory_includes_read_yaml_files_in_order() {
+        let dir = fixture(
+            "directory",
+            &[
+                ("main.yml", "interface: eth0\ninclude: conf.d\n"),
+                (
+                    "conf.d/20-b.yaml",
+                    "rules:\n  - protocol: tcp\n    port: 2\n",
+                ),
+                (
+                    "conf.d/10-a.yml",
+                    "rules:\n  - protocol: tcp\n    port: 1\n",
+                ),
+                ("conf.d/notes.txt", "not: yaml: at all"),
+            ],
+        );
+
+        assert_eq!(ports(&read(&dir, "main.yml").unwrap()), [1, 2]);
+
+        let files = ConfigFiles::read_dir(dir.join("conf.d").to_str().unwrap()).unwrap();
+        assert_eq!(ports(&files), [1, 2]);
+    }
+
+    #[test]
+    fn empty_directories_are_refused() {
+        let dir = fixture("empty", &[("conf.d/notes.txt", "")]);
+
+        let error = ConfigFiles::read_dir(dir.join("conf.d").to_str().unwrap())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("No .yml or .yaml files"));
+    }
+
+    #[test]
+    fn files_included_twice_are_refused() {
+        let dir = fixture(
+            "twice",
+            &[
+                ("main.yml", "interface: eth0\ninclude: [a.yml, ./a.yml]\n"),
+                ("a.yml", "rules: []\n"),
+            ],
+        );
+
+        let error = read(&dir, "main.yml").err().unwrap();
+        assert!(error.to_string().contains("is included more than once"));
+    }
+
+    #[test]
+    fn include_cycles_are_refused() {
+        let dir = fixture(
+            "cycle",
+            &[
+                ("main.yml", "interface: eth0\ninclude: a.yml\n"),
+                ("a.yml", "include: main.yml\n"),
+            ],
+        );
+
+        let error = read(&dir, "main.yml").err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("main.yml is included more than once"));
+    }
+
+    #[test]
+    fn missing_includes_are_reported() {
+        let dir = fixture(
+            "missing",
+            &[("main.yml", "interface: eth0\ninclude: gone.yml\n")],
+        );
+
+        let error = read(&dir, "main.yml").err().unwrap();
+        assert!(format!("{:#}", error).contains("gone.yml"));
+    }
+
+    #[test]
+    fn node_positions_are_one_based() {
+        let positions =
+            node_positions("interface: eth0\nrules:\n  - protocol: tcp\n    port: 22\n");
+
+        assert_eq!(positions["interface"], (1, 1));
+        assert_eq!(positions["rules"], (2, 1));
+        // Only the line of a rule is used, the parser marks a block mapping after its first key.
+        assert_eq!(positions["rules[0]"].0, 3);
+        assert_eq!(positions["rules[0].port"], (4, 5));
+    }
+
+    #[test]
+    fn variables_are_substituted() {
+        let dir = fixture(
+            "variables",
+            &[(
+                "main.yml",
+                "interface: eth0\nvariables:\n  ssh: 22\n  office: 192.0.2.0/24\n  admin: $office\nrules:\n  - protocol: tcp\n    port: $ssh\n    source_ip_range: $admin\n",
+            )],
+        );
+
+        let files = read(&dir, "main.yml").unwrap();
+
+        assert_eq!(ports(&files), [22]);
+        assert_eq!(files.rules[0].rule.source_ip_range, "192.0.2.0/24");
+    }
+
+    #[test]
+    fn variables_are_shared_between_files() {
+        let dir = fixture(
+            "shared_variables",
+            &[
+                (
+                    "main.yml",
+                    "interface: eth0\ninclude: a.yml\nvariables:\n  ssh: 22\n",
+                ),
+                ("a.yml", "rules:\n  - protocol: tcp\n    port: $ssh\n"),
+            ],
+        );
+
+        assert_eq!(ports(&read(&dir, "main.yml").unwrap()), [22]);
+    }
+
+    #[test]
+    fn undefined_variables_are_reported_where_used() {
+        let dir = fixture(
+            "undefined",
+            &[(
+                "main.yml",
+                "interface: eth0\nrules:\n  - protocol: tcp\n    port: $ssh\n",
+            )],
+        );
+
+        let error = format!("{:#}", read(&dir, "main.yml").err().unwrap());
+
+        assert!(error.contains("main.yml:4:5: rules[0].port"), "{}", error);
+        assert!(error.contains("$ssh isn't defined"), "{}", error);
+    }
+
+    #[test]
+    fn variables_referring_to_themselves_are_refused() {
+        let dir = fixture(
+            "self_reference",
+            &[(
+                "main.yml",
+                "interface: eth0\nvariables:\n  a: $b\n  b: [1, $a]\nrules:\n  - protocol: tcp\n    port: $a\n",
+            )],
+        );
+
+        let error = format!("{:#}", read(&dir, "main.yml").err().unwrap());
+
+        assert!(error.contains("refers to itself"), "{}", error);
+    }
+
+    #[test]
+    fn variables_defined_differently_are_refused() {
+        let dir = fixture(
+            "redefined",
+            &[
+                (
+                    "main.yml",
+                    "interface: eth0\ninclude: a.yml\nvariables:\n  ssh: 22\n",
+                ),
+                ("a.yml", "variables:\n  ssh: 2222\n"),
+            ],
+        );
+
+        let error = format!("{:#}", read(&dir, "main.yml").err().unwrap());
+
+        assert!(
+            error.contains("a.yml:2:3: ssh is already defined differently"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn lists_expand_to_a_rule_for_each_item() {
+        let dir = fixture(
+            "lists",
+            &[(
+                "main.yml",
+                "interface: eth0\nvariables:\n  web: [80, 443]\n  sources: [10.0.0.0/8, 192.0.2.0/24]\nrules:\n  - protocol: tcp\n    port: [$web, 8080]\n    source_ip_range: $sources\n",
+            )],
+        );
+
+        let files = read(&dir, "main.yml").unwrap();
+
+        assert_eq!(ports(&files), [80, 80, 443, 443, 8080, 8080]);
+        assert!(files.rules.iter().all(|sourced| sourced.index == 0));
+        assert_eq!(
+            files
+                .rules
+                .iter()
+                .map(|sourced| sourced.rule.source_ip_range.as_str())
+                .collect::<Vec<_>>(),
+            ["10.0.0.0/8", "192.0.2.0/24"].repeat(3)
+        );
+    }
+
+    #[test]
+    fn expanded_rules_cannot_share_an_id() {
+        let dir = fixture(
+            "expanded_id",
+            &[(
+                "main.yml",
+                "interface: eth0\nrules:\n  - id: 1\n    protocol: tcp\n    port: [80, 443]\n",
+            )],
+        );
+
+        let error = format!("{:#}", read(&dir, "main.yml").err().unwrap());
+
+        assert!(error.contains("expands to 2 rules"), "{}", error);
+    }
+
+    #[test]
+    fn empty_list_variables_are_refused() {
+        let dir = fixture(
+            "empty_list",
+            &[(
+                "main.yml",
+                "interface: eth0\nvariables:\n  none: []\nrules:\n  - protocol: tcp\n    port: $none\n",
+            )],
+        );
+
+        let error = format!("{:#}", read(&dir, "main.yml").err().unwrap());
+
+        assert!(error.contains("$none is an empty list"), "{}", error);
+    }
+
+    #[test]
+    fn conflicting_settings_name_both_locations() {
+        let dir = fixture(
+            "conflict",
+            &[
+                ("main.yml", "include: a.yml\ninterface: eth0\n"),
+                ("a.yml", "rules: []\n\ninterface: eth1\n"),
+            ],
+        );
+
+        let error = read(&dir, "main.yml").err().unwrap().to_string();
+
+        assert!(
+            error.contains("a.yml:3:1: interface is set to eth1, but "),
+            "{}",
+            error
+        );
+        assert!(error.ends_with("main.yml:2:1 sets it to eth0"), "{}", error);
+    }
+}